@@ -1,7 +1,21 @@
+use crate::handlers::admin::{
+    __path_delete_asset, __path_list_all_jobs, __path_list_assets, __path_list_uploads,
+    __path_pin_asset, __path_storage_usage, __path_unpin_asset,
+};
+use crate::handlers::bundle::__path_download_bundle;
+use crate::handlers::checksum::{__path_get_checksum, __path_get_sums};
+use crate::handlers::contents::__path_download_archive_entry;
 use crate::handlers::delete::__path_request_delete_asset;
-use crate::handlers::download::__path_download_file;
+use crate::handlers::download::{__path_download_entry, __path_download_file};
+use crate::handlers::jobs::__path_list_jobs;
+use crate::handlers::modify::__path_modify_asset;
 use crate::handlers::qrcode::__path_get_qrcode_from_id;
+use crate::handlers::tus::{
+    __path_tus_create, __path_tus_delete, __path_tus_head, __path_tus_options, __path_tus_patch,
+};
+use crate::handlers::unlock::__path_unlock_asset;
 use crate::upload::__path_accept_form;
+use crate::upload::__path_accept_raw;
 use crate::upload::__path_progress_token_to_id;
 use utoipa::OpenApi;
 
@@ -9,10 +23,31 @@ use utoipa::OpenApi;
 #[openapi(
     paths(
         accept_form,
+        accept_raw,
         download_file,
+        download_entry,
+        download_bundle,
+        download_archive_entry,
+        get_checksum,
+        get_sums,
+        list_jobs,
         progress_token_to_id,
         request_delete_asset,
-        get_qrcode_from_id
+        modify_asset,
+        unlock_asset,
+        tus_options,
+        tus_create,
+        tus_head,
+        tus_patch,
+        tus_delete,
+        get_qrcode_from_id,
+        list_assets,
+        list_uploads,
+        list_all_jobs,
+        delete_asset,
+        pin_asset,
+        unpin_asset,
+        storage_usage
     ),
     info(title = "Tapfer API", version = "1.0")
 )]
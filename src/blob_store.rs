@@ -0,0 +1,195 @@
+use crate::configuration::config;
+use crate::handlers::checksum;
+use crate::index;
+use crate::jobs;
+use crate::jobs::{JobContext, JobKind};
+use crate::storage::{BlockingReader, StorageReader, storage};
+use crate::structs::digest::DigestAlgorithm;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::FileMeta;
+use crate::structs::tapfer_id::TapferId;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::io;
+use std::sync::LazyLock;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Number of assets referencing each blob, by SHA-256.
+/// Held while blobs are created or removed, so a blob never vanishes under an asset adopting it
+static BLOB_REFS: LazyLock<Mutex<HashMap<String, u32>>> = LazyLock::new(Mutex::default);
+
+/// Blob keys of interned payloads by their former key, for downloads that resolved it before the move.
+/// Kept until the asset is removed, as such downloads may open it at any point
+static MOVED: LazyLock<DashMap<String, String>> = LazyLock::new(DashMap::new);
+
+pub fn blob_key(hash: &str) -> String {
+    format!("blobs/{hash}")
+}
+
+/// Counts the references of every stored asset and removes blobs no asset references anymore
+pub async fn init() -> TapferResult<()> {
+    let mut refs = BLOB_REFS.lock().await;
+    let mut unreadable = false;
+    for id in storage().list_assets().await? {
+        match FileMeta::read_from_id(id).await {
+            Ok(meta) => {
+                if let Some(hash) = meta.blob() {
+                    *refs.entry(hash.to_owned()).or_default() += 1;
+                }
+            }
+            // Skipped by the index, its blob may still be needed
+            Err(_) => unreadable = true,
+        }
+    }
+    if unreadable {
+        warn!("Keeping unreferenced blobs, as the metadata of some assets cannot be read");
+        return Ok(());
+    }
+    for hash in storage().list("blobs").await? {
+        if !refs.contains_key(&hash) {
+            info!("Removing unreferenced blob {hash}");
+            storage().remove(&blob_key(&hash)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Validates a client supplied SHA-256, as lowercase hex
+pub fn parse_hash(hash: &str) -> TapferResult<String> {
+    let hash = hash.to_ascii_lowercase();
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(TapferError::InvalidDigest(format!(
+            "{hash:?} is not a hex encoded SHA-256"
+        )));
+    }
+    Ok(hash)
+}
+
+/// Queues moving the payload of a completed single-file asset into the blob store
+pub fn spawn_intern(id: TapferId) {
+    if config().storage.deduplicate {
+        jobs::enqueue(id, JobKind::Deduplicate);
+    }
+}
+
+pub async fn intern(id: TapferId, job: JobContext) -> TapferResult<()> {
+    let meta = FileMeta::read_from_id(id).await?;
+    if meta.is_multi_file() || meta.blob().is_some() {
+        return Ok(());
+    }
+    // Uploads through a form or raw body stored it while streaming
+    let hash = match checksum::read_checksum(id, DigestAlgorithm::Sha256).await {
+        Some(hash) => hash,
+        None => checksum::compute(id, DigestAlgorithm::Sha256, job).await?,
+    };
+
+    let mut refs = BLOB_REFS.lock().await;
+    let _guard = FileMeta::lock(id).await;
+    // The asset may have been deleted or renamed while it was hashed
+    let Ok(mut meta) = FileMeta::read_from_id(id).await else {
+        return Ok(());
+    };
+    if meta.blob().is_some() {
+        return Ok(());
+    }
+    let key = meta.payload_key(id);
+    let blob = blob_key(&hash);
+    // The blob only exists while referenced, and references are only taken under the lock
+    let stored = refs.contains_key(&hash);
+    MOVED.insert(key.clone(), blob.clone());
+    if !stored && let Err(e) = storage().rename(&key, &blob).await {
+        MOVED.remove(&key);
+        return Err(e.into());
+    }
+    meta.set_blob(hash.clone());
+    if let Err(e) = meta.write_to_id(id).await {
+        // Keep the payload where the unchanged metadata expects it
+        if !stored {
+            storage().rename(&blob, &key).await?;
+        }
+        MOVED.remove(&key);
+        return Err(e);
+    }
+    *refs.entry(hash).or_default() += 1;
+    if stored {
+        storage().remove(&key).await?;
+    }
+    info!("Deduplicated {id}");
+    Ok(())
+}
+
+/// Opens `key` at `offset`, following a payload that was moved into the blob store after `key` was resolved
+pub async fn reader(key: &str, offset: u64) -> io::Result<StorageReader> {
+    match storage().reader(key, offset).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match moved(key) {
+            Some(blob) => storage().reader(&blob, offset).await,
+            None => Err(e),
+        },
+        res => res,
+    }
+}
+
+/// Like [`reader`], for use within `spawn_blocking`
+pub fn blocking_reader(key: &str) -> io::Result<BlockingReader> {
+    match storage().blocking_reader(key) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match moved(key) {
+            Some(blob) => storage().blocking_reader(&blob),
+            None => Err(e),
+        },
+        res => res,
+    }
+}
+
+fn moved(key: &str) -> Option<String> {
+    MOVED.get(key).map(|blob| blob.clone())
+}
+
+/// References the blob `hash` for a new asset, returning its size.
+/// `None` when there is no such blob, or its size differs from the announced one
+pub async fn adopt(hash: &str, expected_size: Option<u64>) -> TapferResult<Option<u64>> {
+    let mut refs = BLOB_REFS.lock().await;
+    let Some(count) = refs.get_mut(hash) else {
+        return Ok(None);
+    };
+    let size = storage().len(&blob_key(hash)).await?;
+    if expected_size.is_some_and(|expected| expected != size) {
+        return Ok(None);
+    }
+    *count += 1;
+    Ok(Some(size))
+}
+
+/// Removes the directory of an asset and drops its reference to a blob
+pub async fn remove_asset(id: TapferId) -> TapferResult<()> {
+    let mut refs = BLOB_REFS.lock().await;
+    let meta = FileMeta::read_from_id(id).await.ok();
+    index::transaction(storage().remove_asset(id), move |index| {
+        index::remove(index, id)
+    })
+    .await?;
+    let dir = format!("{id}/");
+    MOVED.retain(|key, _| !key.starts_with(&dir));
+    if let Some(hash) = meta.as_ref().and_then(FileMeta::blob) {
+        drop_ref(&mut refs, hash).await?;
+    }
+    Ok(())
+}
+
+/// Drops a reference taken by [`adopt`] for an asset that was never stored
+pub async fn release(hash: &str) -> TapferResult<()> {
+    drop_ref(&mut *BLOB_REFS.lock().await, hash).await
+}
+
+async fn drop_ref(refs: &mut HashMap<String, u32>, hash: &str) -> TapferResult<()> {
+    let Some(count) = refs.get_mut(hash) else {
+        return Ok(());
+    };
+    *count -= 1;
+    if *count == 0 {
+        refs.remove(hash);
+        storage().remove(&blob_key(hash)).await?;
+        info!("Removed blob {hash} as its last asset is gone");
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: tapfer [COMMAND] [--config <PATH>]
+
+Commands:
+  serve          Run the server (default)
+  check-config   Validate the configuration and exit
+
+Options:
+  -c, --config <PATH>   Configuration file, defaults to ./tapfer.toml
+  -h, --help            Print this help";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Parses the arguments following the binary name
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut command = None;
+        let mut config = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| format!("{arg} requires a path"))?;
+                    config = Some(PathBuf::from(path));
+                }
+                "-h" | "--help" => command = Some(Command::Help),
+                "serve" | "check-config" if command.is_some() => {
+                    return Err(format!("unexpected second command {arg}"));
+                }
+                "serve" => command = Some(Command::Serve),
+                "check-config" => command = Some(Command::CheckConfig),
+                other => {
+                    if let Some(path) = other.strip_prefix("--config=") {
+                        config = Some(PathBuf::from(path));
+                    } else {
+                        return Err(format!("unknown argument {other}"));
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            command: command.unwrap_or(Command::Serve),
+            config,
+        })
+    }
+}
@@ -1,4 +1,21 @@
+use crate::retention_control::GlobalRetentionPolicy;
+use crate::structs::byte_size::ByteSize;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::expiration;
+use crate::structs::expiration::Expiration;
+use crate::structs::human_duration;
+use crate::structs::management_token::MANAGEMENT_TOKEN_HEADER;
+use crate::updown::resumable;
+use http::{HeaderName, HeaderValue, Method, Uri, header};
 use qrcode_generator::QrCodeEcc;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::{env, fs, io};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
 
 // Quick helper for easier to read sizes
 #[macro_export]
@@ -20,17 +37,1041 @@ macro_rules! size {
     };
 }
 
-pub const MAX_UPLOAD_SIZE: usize = size!(100 G);
+/// Used when no `--config` is passed. A missing default file is not an error.
+pub const DEFAULT_CONFIG_PATH: &str = "tapfer.toml";
 
-pub const DOWNLOAD_CHUNKSIZE: usize = size!(1 M);
+/// Prefix of environment variables overriding configuration keys.
+/// Nested keys are separated by a double underscore, e.g. `TAPFER_LIMITS__MAX_UPLOAD_SIZE=10G`
+pub const ENV_PREFIX: &str = "TAPFER_";
 
-pub const UPLOAD_BUFSIZE: usize = size!(100 M);
+static CONFIGURATION: OnceLock<Configuration> = OnceLock::new();
 
-pub const QR_CODE_SIZE: usize = 200; // pixels
-pub const QR_CODE_ECC: QrCodeEcc = QrCodeEcc::Medium;
+/// The configuration loaded at startup
+pub fn config() -> &'static Configuration {
+    CONFIGURATION
+        .get()
+        .expect("configuration is loaded before anything reads it")
+}
+
+/// Installs the configuration for the rest of the process lifetime
+pub fn init(configuration: Configuration) {
+    if CONFIGURATION.set(configuration).is_err() {
+        panic!("configuration was initialized twice");
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Configuration {
+    pub server: Server,
+    pub cors: Cors,
+    pub limits: Limits,
+    pub qr_code: QrCode,
+    pub embed: Embed,
+    pub retention: GlobalRetentionPolicy,
+    pub expiration: ExpirationSettings,
+    pub passwords: Passwords,
+    pub tus: Tus,
+    pub archive_browsing: ArchiveBrowsing,
+    pub storage: Storage,
+    pub jobs: Jobs,
+    pub admin: Admin,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    /// Every address is served by the same application
+    pub listen: Vec<ListenAddress>,
+    /// Permissions of Unix domain sockets, e.g. `"660"` or `0o660`
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub unix_socket_mode: u32,
+    /// Canonical URL this instance is reachable at, e.g. `https://tapfer.example.com`
+    pub public_url: Option<BaseUrl>,
+    /// Separate URL downloads and uploads are served from, e.g. `https://cdn.tapfer.example.com`.
+    /// Defaults to `public_url`
+    pub download_url: Option<BaseUrl>,
+    /// Derive links from the Host header when `public_url` is unset.
+    /// Clients control that header, so this should only be enabled behind a proxy that overwrites it
+    pub trust_host_header: bool,
+    /// Proxies whose `X-Forwarded-For` names the client, as addresses, CIDR ranges or `unix` for Unix sockets
+    pub trusted_proxies: Vec<TrustedProxy>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddress::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000)))],
+            unix_socket_mode: 0o660,
+            public_url: None,
+            download_url: None,
+            trust_host_header: false,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+/// Either `host:port` for TCP or `unix:/path/to/socket` for a Unix domain socket
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: listen address requires a path".to_owned());
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|_| format!("invalid listen address {s:?}, expected e.g. `0.0.0.0:3000`, `[::]:3000` or `unix:/run/tapfer.sock`"))
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => addr.fmt(f),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ListenAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Either an address, a CIDR range such as `10.0.0.0/8`, or `unix` for anything connecting through a Unix socket
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TrustedProxy {
+    Network { addr: IpAddr, prefix: u8 },
+    Unix,
+}
+
+impl TrustedProxy {
+    /// `peer` is `None` for connections over Unix sockets
+    pub fn contains(&self, peer: Option<IpAddr>) -> bool {
+        let bits = |prefix: u8, a: u128, b: u128, width: u8| {
+            let shift = u32::from(width - prefix);
+            a.checked_shr(shift).unwrap_or(0) == b.checked_shr(shift).unwrap_or(0)
+        };
+        match (*self, peer) {
+            (Self::Unix, None) => true,
+            (Self::Network { addr, prefix }, Some(peer)) => match (addr, peer.to_canonical()) {
+                (IpAddr::V4(a), IpAddr::V4(p)) => {
+                    bits(prefix, u32::from(a).into(), u32::from(p).into(), 32)
+                }
+                (IpAddr::V6(a), IpAddr::V6(p)) => bits(prefix, a.into(), p.into(), 128),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(Self::Unix);
+        }
+        let invalid = || {
+            format!("invalid proxy {s:?}, expected e.g. `127.0.0.1`, `10.0.0.0/8`, `::1` or `unix`")
+        };
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= width)
+                .ok_or_else(invalid)?,
+            None => width,
+        };
+        Ok(Self::Network { addr, prefix })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for TrustedProxy {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An absolute `http(s)://host[:port]` URL without path, stored without trailing slash
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BaseUrl(String);
+
+impl BaseUrl {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for BaseUrl {
+    type Err = String;
 
-pub const EMBED_DESCRIPTION: &str = "fast self-hosted file transfer";
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: Uri = s.parse().map_err(|e| format!("invalid URL {s:?}: {e}"))?;
+        let scheme = uri.scheme_str().unwrap_or_default();
+        if scheme != "http" && scheme != "https" {
+            return Err(format!("URL {s:?} must start with http:// or https://"));
+        }
+        let Some(authority) = uri.authority() else {
+            return Err(format!("URL {s:?} has no host"));
+        };
+        if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+            return Err(format!("URL {s:?} must not contain a path or query"));
+        }
+        Ok(Self(format!("{scheme}://{authority}")))
+    }
+}
+
+impl Display for BaseUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BaseUrl {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn deserialize_mode<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Int(u32),
+        Str(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Int(i) => Ok(i),
+        Raw::Str(s) => u32::from_str_radix(s.trim_start_matches("0o"), 8)
+            .map_err(|_| serde::de::Error::custom(format!("invalid octal mode {s:?}"))),
+    }
+}
+
+/// Lists accept `"*"` as their only element to allow anything
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    /// Allow the origins of `server.public_url` and `server.download_url` in addition to `allowed_origins`
+    pub derive_from_public_url: bool,
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            derive_from_public_url: true,
+            allowed_origins: vec![],
+            allowed_methods: vec!["*".to_owned()],
+            allowed_headers: vec![],
+        }
+    }
+}
+
+fn is_wildcard(list: &[String]) -> bool {
+    matches!(list, [only] if only == "*")
+}
+
+impl Configuration {
+    /// Builds the layer from the validated configuration
+    pub fn cors_layer(&self) -> CorsLayer {
+        let cors = &self.cors;
+        let origins: AllowOrigin = if is_wildcard(&cors.allowed_origins) {
+            Any.into()
+        } else {
+            self.cors_origins()
+                .expect("CORS origins are validated at startup")
+                .into()
+        };
+        let methods: AllowMethods = if is_wildcard(&cors.allowed_methods) {
+            Any.into()
+        } else {
+            cors.allowed_methods
+                .iter()
+                .map(|m| Method::from_str(&m.to_ascii_uppercase()))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("CORS methods are validated at startup")
+                .into()
+        };
+        let headers: AllowHeaders = if is_wildcard(&cors.allowed_headers) {
+            Any.into()
+        } else {
+            cors.allowed_headers
+                .iter()
+                .map(|h| HeaderName::from_str(h))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("CORS headers are validated at startup")
+                .into()
+        };
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            // The frontend uploads to the download URL and needs to read the token from the response,
+            // tus clients additionally need the upload location and offsets
+            .expose_headers(
+                [MANAGEMENT_TOKEN_HEADER, header::LOCATION.as_str()]
+                    .into_iter()
+                    .chain(resumable::EXPOSED_HEADERS)
+                    .map(HeaderName::from_static)
+                    .collect::<Vec<_>>(),
+            )
+    }
+
+    fn cors_origins(&self) -> Result<Vec<HeaderValue>, String> {
+        let derived = self
+            .cors
+            .derive_from_public_url
+            .then_some([&self.server.public_url, &self.server.download_url])
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(BaseUrl::to_string);
+        let mut origins = vec![];
+        for origin in self.cors.allowed_origins.iter().cloned().chain(derived) {
+            let origin: BaseUrl = origin.parse()?;
+            let value = HeaderValue::from_str(origin.as_str())
+                .map_err(|e| format!("invalid CORS origin {origin}: {e}"))?;
+            if !origins.contains(&value) {
+                origins.push(value);
+            }
+        }
+        Ok(origins)
+    }
+}
 
-pub const EMBED_TITLE: &str = "Tapfer";
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_upload_size: ByteSize,
+    pub download_chunksize: ByteSize,
+    pub upload_bufsize: ByteSize,
+    /// Read buffer used when checksumming assets after their upload
+    pub checksum_bufsize: ByteSize,
+    /// Files a single multi-file upload may contain
+    pub max_files: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_upload_size: ByteSize(size!(100 G)),
+            download_chunksize: ByteSize(size!(1 M)),
+            upload_bufsize: ByteSize(size!(100 M)),
+            checksum_bufsize: ByteSize(size!(100 M)),
+            max_files: 10_000,
+        }
+    }
+}
+
+/// Bounds and choices for the lifetime uploaders may request
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpirationSettings {
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub minimum: time::Duration,
+    /// Must not exceed `retention.maximum_age`, which deletes assets regardless of their expiration
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub maximum: time::Duration,
+    /// Options offered on the homepage, the first one is preselected
+    pub presets: Vec<ExpirationPreset>,
+}
+
+impl Default for ExpirationSettings {
+    fn default() -> Self {
+        Self {
+            minimum: time::Duration::minutes(5),
+            maximum: time::Duration::hours(24),
+            presets: vec![
+                ExpirationPreset {
+                    label: "one download".to_owned(),
+                    value: Some("single_download".to_owned()),
+                    max_downloads: None,
+                },
+                ExpirationPreset {
+                    label: "24 hours".to_owned(),
+                    value: Some("24h".to_owned()),
+                    max_downloads: None,
+                },
+            ],
+        }
+    }
+}
+
+/// Access to password protected assets
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Passwords {
+    /// How long a correct password unlocks the asset in the browser
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub unlock_duration: time::Duration,
+    /// Wrong passwords tolerated per asset before further attempts are refused
+    pub max_failed_attempts: u32,
+    /// Wait after too many wrong passwords. Every wrong password afterwards starts it anew
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub lockout: time::Duration,
+}
+
+impl Default for Passwords {
+    fn default() -> Self {
+        Self {
+            unlock_duration: time::Duration::hours(1),
+            max_failed_attempts: 5,
+            lockout: time::Duration::minutes(5),
+        }
+    }
+}
+
+/// Resumable uploads via the tus protocol
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tus {
+    /// How long an interrupted upload waits for its client to resume before it is deleted
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub grace_period: time::Duration,
+}
+
+impl Default for Tus {
+    fn default() -> Self {
+        Self {
+            grace_period: time::Duration::hours(1),
+        }
+    }
+}
 
-pub const FAVICON: &str = "/static/favicon.ico";
+/// Listing and extracting the contents of uploaded archives
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveBrowsing {
+    /// Entries listed per archive, the rest can only be had by downloading the archive
+    pub max_entries: usize,
+    /// Largest entry that is extracted
+    pub max_entry_size: ByteSize,
+    /// Decompressed bytes read per byte of the archive at most, refusing zip bombs
+    pub max_compression_ratio: u64,
+}
+
+impl Default for ArchiveBrowsing {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_entry_size: ByteSize(size!(10 G)),
+            max_compression_ratio: 100,
+        }
+    }
+}
+
+/// Where and how payloads are kept
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub backend: StorageBackend,
+    /// Directory of the filesystem backend
+    pub root: PathBuf,
+    /// SQLite index of all assets, `index.sqlite` below `root` by default. Kept in memory for the memory backend
+    pub index: Option<PathBuf>,
+    /// Bucket of the S3 backend
+    pub s3: S3,
+    /// Moves completed single-file assets into a store keyed by their SHA-256, so identical uploads share one copy
+    pub deduplicate: bool,
+    /// Lets raw uploads name the SHA-256 of their content and skip sending it when it is stored already.
+    /// Anyone knowing the hash of a stored file can then obtain it
+    pub hash_first_uploads: bool,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Filesystem,
+            root: PathBuf::from("data"),
+            index: None,
+            s3: S3::default(),
+            deduplicate: true,
+            hash_first_uploads: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Filesystem,
+    /// Lost on restart, for tests and ephemeral instances
+    Memory,
+    /// Any S3 compatible object store, such as MinIO
+    S3,
+}
+
+/// Connection to an S3 compatible bucket
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3 {
+    /// Such as `http://localhost:9000` for MinIO or `https://s3.eu-central-1.amazonaws.com`
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Prepended to every object key, so several instances can share a bucket
+    pub prefix: String,
+    /// Addresses the bucket as `{endpoint}/{bucket}` instead of as a subdomain, which MinIO expects
+    pub path_style: bool,
+    /// Uploads are stored in parts of this size, which downloaders can read before the upload completes.
+    /// Smaller parts reach downloaders sooner, an upload may consist of at most 10000
+    pub part_size: ByteSize,
+}
+
+impl Default for S3 {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            region: "us-east-1".to_owned(),
+            bucket: String::new(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            prefix: String::new(),
+            path_style: true,
+            part_size: ByteSize(size!(16 M)),
+        }
+    }
+}
+
+/// Background processing of complete assets, such as computing checksums
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Jobs {
+    /// Jobs running at once, each reading with a buffer of `limits.checksum_bufsize`
+    pub concurrency: usize,
+    /// Runs of a failing job before it is dropped
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    #[serde(deserialize_with = "human_duration::deserialize")]
+    pub retry_delay: time::Duration,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            concurrency: 2,
+            max_attempts: 5,
+            retry_delay: time::Duration::seconds(10),
+        }
+    }
+}
+
+/// The API below `/admin` for inspecting and managing every asset of the instance
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+    /// Expected as `Authorization: Bearer <token>`. The API is disabled while empty
+    pub token: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpirationPreset {
+    pub label: String,
+    /// Anything the `expiration` upload parameter accepts
+    #[serde(default)]
+    pub value: Option<String>,
+    /// Sent as the `max_downloads` upload parameter
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+}
+
+impl ExpirationPreset {
+    /// Empty when the preset only limits downloads
+    pub fn expiration(&self) -> &str {
+        self.value.as_deref().unwrap_or_default()
+    }
+
+    /// Empty when the preset does not limit downloads
+    pub fn max_downloads(&self) -> String {
+        self.max_downloads
+            .map(|n| n.to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QrCode {
+    /// Edge length in pixels
+    pub size: usize,
+    pub ecc: QrEcc,
+}
+
+impl Default for QrCode {
+    fn default() -> Self {
+        Self {
+            size: 200,
+            ecc: QrEcc::Medium,
+        }
+    }
+}
+
+/// Serde-friendly mirror of [`QrCodeEcc`]
+#[derive(Debug, Copy, Clone, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QrEcc {
+    Low,
+    Medium,
+    Quartile,
+    High,
+}
+
+impl From<QrEcc> for QrCodeEcc {
+    fn from(value: QrEcc) -> Self {
+        match value {
+            QrEcc::Low => QrCodeEcc::Low,
+            QrEcc::Medium => QrCodeEcc::Medium,
+            QrEcc::Quartile => QrCodeEcc::Quartile,
+            QrEcc::High => QrCodeEcc::High,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Embed {
+    pub title: String,
+    pub description: String,
+    pub favicon: String,
+}
+
+impl Default for Embed {
+    fn default() -> Self {
+        Self {
+            title: "Tapfer".to_owned(),
+            description: "fast self-hosted file transfer".to_owned(),
+            favicon: "/static/favicon.ico".to_owned(),
+        }
+    }
+}
+
+impl Configuration {
+    /// Reads the file at `path` (if present), applies environment overrides and validates the result
+    pub fn load(path: Option<&Path>) -> TapferResult<Self> {
+        let (path, required) = match path {
+            Some(p) => (p.to_path_buf(), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut table = match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str::<toml::Table>(&s).map_err(|e| {
+                TapferError::InvalidConfiguration(vec![format!("{}: {e}", path.display())])
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => toml::Table::new(),
+            Err(e) => {
+                return Err(TapferError::InvalidConfiguration(vec![format!(
+                    "{}: {e}",
+                    path.display()
+                )]));
+            }
+        };
+        apply_env_overrides(&mut table, env::vars())?;
+
+        let configuration: Configuration = table
+            .try_into()
+            .map_err(|e: toml::de::Error| TapferError::InvalidConfiguration(vec![e.to_string()]))?;
+        let errors = configuration.validate();
+        if !errors.is_empty() {
+            return Err(TapferError::InvalidConfiguration(errors));
+        }
+        Ok(configuration)
+    }
+
+    /// Returns every problem found, so that `check-config` can report all of them at once
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let mut check = |ok: bool, msg: &str| {
+            if !ok {
+                errors.push(msg.to_owned());
+            }
+        };
+
+        check(
+            !self.server.listen.is_empty(),
+            "server.listen must contain at least one address",
+        );
+        check(
+            self.server.unix_socket_mode <= 0o777,
+            "server.unix_socket_mode must be a permission mode between 000 and 777",
+        );
+        let mut seen = self.server.listen.clone();
+        seen.sort_by_key(ToString::to_string);
+        seen.dedup();
+        check(
+            seen.len() == self.server.listen.len(),
+            "server.listen contains duplicate addresses",
+        );
+
+        check(
+            self.limits.max_upload_size.bytes() > 0,
+            "limits.max_upload_size must be greater than zero",
+        );
+        check(
+            self.limits.max_files > 0,
+            "limits.max_files must be greater than zero",
+        );
+        check(
+            self.limits.download_chunksize.bytes() > 0,
+            "limits.download_chunksize must be greater than zero",
+        );
+        check(
+            self.limits.upload_bufsize.bytes() > 0,
+            "limits.upload_bufsize must be greater than zero",
+        );
+        check(
+            self.limits.checksum_bufsize.bytes() > 0,
+            "limits.checksum_bufsize must be greater than zero",
+        );
+        check(
+            (21..=4096).contains(&self.qr_code.size),
+            "qr_code.size must be between 21 and 4096 pixels",
+        );
+        check(
+            !self.embed.favicon.is_empty(),
+            "embed.favicon must not be empty",
+        );
+        check(
+            self.retention.maximum_age.is_positive(),
+            "retention.maximum_age must be positive",
+        );
+        check(
+            self.retention.recheck_interval.is_positive(),
+            "retention.recheck_interval must be positive",
+        );
+        check(
+            !self.expiration.minimum.is_negative(),
+            "expiration.minimum must not be negative",
+        );
+        check(
+            self.expiration.minimum <= self.expiration.maximum,
+            "expiration.minimum must not exceed expiration.maximum",
+        );
+        check(
+            self.expiration.maximum <= self.retention.maximum_age,
+            "expiration.maximum must not exceed retention.maximum_age",
+        );
+        check(
+            !self.expiration.presets.is_empty(),
+            "expiration.presets must contain at least one preset",
+        );
+        check(
+            self.passwords.unlock_duration.is_positive(),
+            "passwords.unlock_duration must be positive",
+        );
+        check(
+            self.passwords.max_failed_attempts > 0,
+            "passwords.max_failed_attempts must be at least 1",
+        );
+        check(
+            self.passwords.lockout.is_positive(),
+            "passwords.lockout must be positive",
+        );
+        check(
+            self.tus.grace_period.is_positive(),
+            "tus.grace_period must be positive",
+        );
+        check(
+            !self.storage.root.as_os_str().is_empty(),
+            "storage.root must not be empty",
+        );
+        if self.storage.backend == StorageBackend::S3 {
+            let s3 = &self.storage.s3;
+            check(
+                s3.endpoint.starts_with("http://") || s3.endpoint.starts_with("https://"),
+                "storage.s3.endpoint must be an http:// or https:// URL",
+            );
+            check(!s3.bucket.is_empty(), "storage.s3.bucket must not be empty");
+            check(
+                !s3.access_key_id.is_empty() && !s3.secret_access_key.is_empty(),
+                "storage.s3.access_key_id and storage.s3.secret_access_key must be set",
+            );
+            check(
+                (size!(5 M)..=size!(5 G)).contains(&s3.part_size.bytes()),
+                "storage.s3.part_size must be between 5M and 5G",
+            );
+            check(
+                s3.part_size.bytes().saturating_mul(10000) >= self.limits.max_upload_size.bytes(),
+                "storage.s3.part_size must allow limits.max_upload_size in 10000 parts",
+            );
+        }
+        check(
+            self.jobs.concurrency > 0,
+            "jobs.concurrency must be at least 1",
+        );
+        check(
+            self.jobs.max_attempts > 0,
+            "jobs.max_attempts must be at least 1",
+        );
+        check(
+            self.jobs.retry_delay.is_positive(),
+            "jobs.retry_delay must be positive",
+        );
+        check(
+            self.admin.token.is_empty() || self.admin.token.len() >= 16,
+            "admin.token must be at least 16 characters long",
+        );
+
+        for (key, list) in [
+            ("allowed_origins", &self.cors.allowed_origins),
+            ("allowed_methods", &self.cors.allowed_methods),
+            ("allowed_headers", &self.cors.allowed_headers),
+        ] {
+            if list.len() > 1 && list.iter().any(|e| e == "*") {
+                errors.push(format!(
+                    "cors.{key}: \"*\" allows anything and must be the only element"
+                ));
+            }
+        }
+        if !is_wildcard(&self.cors.allowed_origins)
+            && let Err(e) = self.cors_origins()
+        {
+            errors.push(format!("cors.allowed_origins: {e}"));
+        }
+        if !is_wildcard(&self.cors.allowed_methods) {
+            for method in &self.cors.allowed_methods {
+                if Method::from_str(&method.to_ascii_uppercase()).is_err() {
+                    errors.push(format!("cors.allowed_methods: invalid method {method:?}"));
+                }
+            }
+        }
+        if !is_wildcard(&self.cors.allowed_headers) {
+            for header in &self.cors.allowed_headers {
+                if HeaderName::from_str(header).is_err() {
+                    errors.push(format!("cors.allowed_headers: invalid header {header:?}"));
+                }
+            }
+        }
+        for preset in &self.expiration.presets {
+            if preset.label.is_empty() {
+                errors.push("expiration.presets: every preset needs a label".to_owned());
+            }
+            let expiration = preset
+                .value
+                .as_deref()
+                .map(Expiration::from_str)
+                .transpose();
+            let res = expiration.and_then(|e| {
+                if e.is_none() && preset.max_downloads.is_none() {
+                    return Err("needs a value, max_downloads or both".to_owned());
+                }
+                // Only validates the combination, the clamped lifetime is discarded
+                expiration::removal_policy(
+                    e,
+                    preset.max_downloads,
+                    time::UtcDateTime::now(),
+                    &self.expiration,
+                )
+            });
+            if let Err(e) = res {
+                errors.push(format!("expiration.presets: {:?} {e}", preset.label));
+            }
+        }
+        errors
+    }
+}
+
+/// Merges every `TAPFER_*` variable into the parsed file.
+/// Values are parsed as TOML when possible, so `true`, `3000` and `["a", "b"]` keep their types,
+/// unless the key only accepts a string, as `TAPFER_EMBED__TITLE=123` does.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> TapferResult<()> {
+    let mut errors = vec![];
+    let mut overrides = vec![];
+    for (key, value) in vars {
+        let Some(path) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path: Vec<String> = path.split("__").map(str::to_ascii_lowercase).collect();
+        if path.iter().any(String::is_empty) {
+            errors.push(format!("{key}: malformed configuration variable"));
+            continue;
+        }
+        let parsed = toml::from_str::<toml::Table>(&format!("v = {value}"))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or_else(|| toml::Value::String(value.clone()));
+        overrides.push((key, path, value, parsed));
+    }
+
+    // Usually every value keeps its type, which a single attempt on the overrides alone confirms.
+    // Only otherwise each key is probed on its own, to find those that only accept strings
+    let mut typed = toml::Table::new();
+    for (_, path, _, parsed) in &overrides {
+        // Conflicting paths are reported when merging into the file below
+        let _ = insert_at(&mut typed, path, parsed.clone());
+    }
+    let all_typed = typed.try_into::<Configuration>().is_ok();
+
+    for (key, path, raw, parsed) in overrides {
+        let value = if !all_typed
+            && !parsed.is_str()
+            && !accepts(&path, &parsed)
+            && accepts(&path, &toml::Value::String(raw.clone()))
+        {
+            toml::Value::String(raw)
+        } else {
+            parsed
+        };
+        if let Err(e) = insert_at(table, &path, value) {
+            errors.push(format!("{key}: {e}"));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(TapferError::InvalidConfiguration(errors))
+    }
+}
+
+/// Whether the key at `path` accepts `value`, judged by a configuration setting nothing else
+fn accepts(path: &[String], value: &toml::Value) -> bool {
+    let mut table = toml::Table::new();
+    insert_at(&mut table, path, value.clone()).is_ok() && table.try_into::<Configuration>().is_ok()
+}
+
+fn insert_at(table: &mut toml::Table, path: &[String], value: toml::Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("split always yields a segment");
+    let mut current = table;
+    for segment in parents {
+        let entry = current
+            .entry(segment.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        match entry {
+            toml::Value::Table(t) => current = t,
+            _ => return Err(format!("{segment} is not a section")),
+        }
+    }
+    current.insert(last.clone(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_env(vars: &[(&str, &str)]) -> Result<Configuration, Vec<String>> {
+        let mut table = toml::Table::new();
+        let vars = vars.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned()));
+        match apply_env_overrides(&mut table, vars) {
+            Err(TapferError::InvalidConfiguration(errors)) => return Err(errors),
+            res => res.unwrap(),
+        }
+        table
+            .try_into()
+            .map_err(|e: toml::de::Error| vec![e.to_string()])
+    }
+
+    #[test]
+    fn env_values_keep_their_type() {
+        let c = with_env(&[
+            ("TAPFER_LIMITS__MAX_UPLOAD_SIZE", "10G"),
+            ("TAPFER_LIMITS__DOWNLOAD_CHUNKSIZE", "4096"),
+            ("TAPFER_SERVER__TRUST_HOST_HEADER", "true"),
+            (
+                "TAPFER_SERVER__LISTEN",
+                r#"["127.0.0.1:80", "unix:/tmp/t.sock"]"#,
+            ),
+            ("TAPFER_EMBED__TITLE", "transfer"),
+            ("UNRELATED", "1"),
+        ])
+        .unwrap();
+        assert_eq!(c.limits.max_upload_size, ByteSize(size!(10 G)));
+        assert_eq!(c.limits.download_chunksize, ByteSize(4096));
+        assert!(c.server.trust_host_header);
+        assert_eq!(c.server.listen.len(), 2);
+        assert_eq!(c.embed.title, "transfer");
+    }
+
+    #[test]
+    fn numeric_env_values_stay_strings_for_string_keys() {
+        let c = with_env(&[
+            ("TAPFER_EMBED__TITLE", "123"),
+            ("TAPFER_EMBED__DESCRIPTION", "true"),
+            // A typed key in the same batch must not keep the others from being probed
+            ("TAPFER_SERVER__UNIX_SOCKET_MODE", "0o600"),
+        ])
+        .unwrap();
+        assert_eq!(c.embed.title, "123");
+        assert_eq!(c.embed.description, "true");
+        assert_eq!(c.server.unix_socket_mode, 0o600);
+    }
+
+    #[test]
+    fn env_keys_nest_on_double_underscores() {
+        let c = with_env(&[
+            ("TAPFER_STORAGE__S3__BUCKET", "2024"),
+            ("TAPFER_STORAGE__S3__PATH_STYLE", "false"),
+            ("TAPFER_STORAGE__S3__PART_SIZE", "16M"),
+        ])
+        .unwrap();
+        assert_eq!(c.storage.s3.bucket, "2024");
+        assert!(!c.storage.s3.path_style);
+        assert_eq!(c.storage.s3.part_size, ByteSize(size!(16 M)));
+    }
+
+    #[test]
+    fn rejects_malformed_env_keys() {
+        let errors = with_env(&[("TAPFER_LIMITS____MAX_UPLOAD_SIZE", "1G")]).unwrap_err();
+        assert!(errors[0].contains("malformed"), "{errors:?}");
+        let errors = with_env(&[("TAPFER_EMBED", "x"), ("TAPFER_EMBED__TITLE", "y")]).unwrap_err();
+        assert!(errors[0].contains("not a section"), "{errors:?}");
+    }
+
+    #[test]
+    fn matches_trusted_proxies() {
+        let proxy = |s: &str| TrustedProxy::from_str(s).unwrap();
+        let ip = |s: &str| Some(IpAddr::from_str(s).unwrap());
+        assert!(proxy("127.0.0.1").contains(ip("127.0.0.1")));
+        assert!(!proxy("127.0.0.1").contains(ip("127.0.0.2")));
+        assert!(proxy("10.0.0.0/8").contains(ip("10.20.30.40")));
+        assert!(!proxy("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(proxy("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(proxy("fd00::/8").contains(ip("fd12::1")));
+        assert!(!proxy("::1").contains(ip("127.0.0.1")));
+        // Dual-stack sockets report IPv4 peers as mapped IPv6 addresses
+        assert!(proxy("127.0.0.1").contains(ip("::ffff:127.0.0.1")));
+        assert!(proxy("unix").contains(None));
+        assert!(!proxy("0.0.0.0/0").contains(None));
+        assert!(TrustedProxy::from_str("10.0.0.0/33").is_err());
+        assert!(TrustedProxy::from_str("localhost").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_units() {
+        assert!(with_env(&[("TAPFER_LIMITS__MAX_UPLOAD_SIZE", "10X")]).is_err());
+        assert!(with_env(&[("TAPFER_EXPIRATION__MAXIMUM", "3 fortnights")]).is_err());
+        assert!(with_env(&[("TAPFER_SERVER__UNIX_SOCKET_MODE", "0o9")]).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::UPLOAD_POOL;
+use crate::handlers::delete::delete_or_abort;
+use crate::handlers::download::UpDownFsm;
+use crate::handlers::get_any_meta;
+use crate::index;
+use crate::index::{AssetFilter, AssetState, AssetUsage, IndexedAsset};
+use crate::jobs;
+use crate::jobs::AssetJobSummary;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::FileMeta;
+use crate::structs::human_duration::HumanDuration;
+use crate::structs::management_token::authorize_admin;
+use crate::updown::resumable::RESUMABLE_UPLOADS;
+use crate::updown::upload_pool::UploadFsm;
+use axum::Json;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use time::UtcDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::info;
+
+/// Assets listed per request unless `limit` says otherwise
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssetQuery {
+    state: Option<AssetState>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    older_than: Option<HumanDuration>,
+    newer_than: Option<HumanDuration>,
+    pinned: Option<bool>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    InProgress,
+    /// A resumable upload waiting for its client to continue
+    Stalled,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct UploadSummary {
+    id: String,
+    name: String,
+    /// Announced size, absent when the client did not send one
+    size: Option<u64>,
+    /// Bytes received so far
+    received: u64,
+    state: UploadState,
+    /// Whether the upload goes through tus and survives interruptions
+    resumable: bool,
+    /// Files received so far, more than one for multi-file uploads
+    files: usize,
+    /// RFC 3339
+    started: String,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct StorageUsage {
+    assets: AssetUsage,
+    /// Uploads in progress
+    uploads: u64,
+    /// Bytes received by uploads in progress
+    uploading_bytes: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/assets",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+        ("state" = Option<AssetState>, Query, description = "`uploading` or `complete`"),
+        ("min_size" = Option<u64>, Query, description = "Smallest size in bytes"),
+        ("max_size" = Option<u64>, Query, description = "Largest size in bytes"),
+        ("older_than" = Option<String>, Query, description = "Only assets created longer ago than this duration, such as `7d`"),
+        ("newer_than" = Option<String>, Query, description = "Only assets created within this duration, such as `1h`"),
+        ("pinned" = Option<bool>, Query, description = "Only pinned or only unpinned assets"),
+        ("limit" = Option<u32>, Query, description = "Assets per page, 100 by default and at most 1000"),
+        ("offset" = Option<u32>, Query, description = "Assets to skip, for paging"),
+    ),
+    responses(
+        (status = 200, description = "Matching assets, newest first", body = Vec<IndexedAsset>),
+        (status = 400, description = "Invalid filter"),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "The admin API is disabled"),
+    ),
+)]
+pub async fn list_assets(
+    headers: HeaderMap,
+    Query(query): Query<AssetQuery>,
+) -> TapferResult<Json<Vec<IndexedAsset>>> {
+    authorize_admin(&headers)?;
+    let now = UtcDateTime::now();
+    let filter = AssetFilter {
+        state: query.state,
+        min_size: query.min_size,
+        max_size: query.max_size,
+        created_before: query.older_than.map(|d| now - d.duration()),
+        created_after: query.newer_than.map(|d| now - d.duration()),
+        pinned: query.pinned,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+        offset: query.offset.unwrap_or_default(),
+    };
+    Ok(Json(index::list(filter).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/uploads",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 200, description = "Uploads in progress with the bytes received so far. `/uploads/{id}/ws` streams their progress as it happens", body = Vec<UploadSummary>),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "The admin API is disabled"),
+    ),
+)]
+pub async fn list_uploads(headers: HeaderMap) -> TapferResult<Json<Vec<UploadSummary>>> {
+    authorize_admin(&headers)?;
+    Ok(Json(upload_summaries().await?))
+}
+
+async fn upload_summaries() -> TapferResult<Vec<UploadSummary>> {
+    // Not held across awaits, which would block uploads registering in the same shard
+    let handles: Vec<_> = UPLOAD_POOL
+        .uploads
+        .iter()
+        .map(|h| h.value().clone())
+        .collect();
+    let mut uploads = vec![];
+    for handle in handles {
+        let fsm = *handle.read_fsm().await;
+        let (received, state) = match fsm {
+            UploadFsm::InProgress { progress } => (progress, UploadState::InProgress),
+            UploadFsm::Stalled { progress } => (progress, UploadState::Stalled),
+            // About to leave the pool
+            UploadFsm::Failed | UploadFsm::Completed => continue,
+        };
+        let meta = handle.file_meta();
+        uploads.push((
+            meta.created(),
+            UploadSummary {
+                id: handle.id().to_string(),
+                name: meta.name().to_owned(),
+                size: meta.known_size(),
+                received,
+                state,
+                resumable: RESUMABLE_UPLOADS.contains_key(&handle.id()),
+                files: handle.entries(received).len().max(1),
+                started: meta.created().format(&Rfc3339)?,
+            },
+        ));
+    }
+    // Formatted times only compare correctly while their fractional seconds have the same length
+    uploads.sort_by_key(|(started, _)| *started);
+    Ok(uploads.into_iter().map(|(_, u)| u).collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 200, description = "Queued and running post-processing jobs of all assets with their attempts and progress, oldest first", body = Vec<AssetJobSummary>),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "The admin API is disabled"),
+    ),
+)]
+pub async fn list_all_jobs(headers: HeaderMap) -> TapferResult<Json<Vec<AssetJobSummary>>> {
+    authorize_admin(&headers)?;
+    Ok(Json(jobs::all_jobs()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/assets/{id}",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 204, description = "Asset deleted, or its upload aborted"),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "Asset does not exist, or the admin API is disabled"),
+    ),
+)]
+pub async fn delete_asset(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> TapferResult<StatusCode> {
+    authorize_admin(&headers)?;
+    let ((id, _), _) = get_any_meta(&path).await?;
+    info!("Admin request to delete {id}");
+    delete_or_abort(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/assets/{id}/pin",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 204, description = "Asset pinned. Retention no longer removes it for its age or expiration, reaching its download limit still does"),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "Asset does not exist, or the admin API is disabled"),
+        (status = 409, description = "Asset is still uploading"),
+    ),
+)]
+pub async fn pin_asset(Path(path): Path<String>, headers: HeaderMap) -> TapferResult<StatusCode> {
+    set_pinned(&path, &headers, true).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/assets/{id}/pin",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 204, description = "Asset unpinned, retention applies to it again"),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "Asset does not exist, or the admin API is disabled"),
+        (status = 409, description = "Asset is still uploading"),
+    ),
+)]
+pub async fn unpin_asset(Path(path): Path<String>, headers: HeaderMap) -> TapferResult<StatusCode> {
+    set_pinned(&path, &headers, false).await
+}
+
+async fn set_pinned(path: &str, headers: &HeaderMap, pinned: bool) -> TapferResult<StatusCode> {
+    authorize_admin(headers)?;
+    let ((id, _), fsm) = get_any_meta(path).await?;
+    if matches!(fsm, UpDownFsm::UpdownInProgress { .. }) {
+        return Err(TapferError::AssetInProgress);
+    }
+    FileMeta::update(id, |meta| meta.set_pinned(pinned)).await?;
+    info!(
+        "{} {id} as requested",
+        if pinned { "Pinned" } else { "Unpinned" }
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/storage",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <admin token>` as configured in `admin.token`"),
+    ),
+    responses(
+        (status = 200, description = "What stored assets and uploads in progress occupy", body = StorageUsage),
+        (status = 401, description = "Admin token missing"),
+        (status = 403, description = "Admin token does not match"),
+        (status = 404, description = "The admin API is disabled"),
+    ),
+)]
+pub async fn storage_usage(headers: HeaderMap) -> TapferResult<Json<StorageUsage>> {
+    authorize_admin(&headers)?;
+    let uploads = upload_summaries().await?;
+    Ok(Json(StorageUsage {
+        assets: index::usage().await?,
+        uploads: uploads.len() as u64,
+        uploading_bytes: uploads.iter().map(|u| u.received).sum(),
+    }))
+}
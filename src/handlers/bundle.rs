@@ -0,0 +1,171 @@
+use crate::blob_store;
+use crate::configuration::config;
+use crate::handlers;
+use crate::handlers::download::{UpDownFsm, archive_stream};
+use crate::structs::archive::{ArchiveBuilder, ArchiveFile, ArchiveFormat, ArchivePart};
+use crate::structs::asset_password::AssetCredentials;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::{FileMeta, PathSet};
+use crate::structs::tapfer_id::TapferId;
+use axum::body::Body;
+use axum::extract::Query;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use futures_util::stream;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use tokio::task;
+
+/// Every bundled asset is read and authorized before the first byte is sent
+const MAX_BUNDLE_ASSETS: usize = 100;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BundleParameters {
+    ids: String,
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+#[utoipa::path(
+    method(get, head),
+    path = "/bundle",
+    params(
+        ("ids" = String, Query, description = "Comma separated IDs of the assets to bundle"),
+        ("format" = Option<String>, Query, description = "`zip` (default) or `tar`"),
+    ),
+    responses(
+        (status = 200, description = "Uncompressed archive of all files of the assets. Files of different assets with clashing paths are put in a directory named by their asset ID", headers
+            (
+                ("content-length" = u64, description = "Size of the archive"),
+            )
+        ),
+        (status = 400, description = "Invalid or too many asset IDs"),
+        (status = 401, description = "An asset is password protected. Send its unlock cookie, or the `tapfer-password` header when all protected assets share the password"),
+        (status = 404, description = "An asset does not exist"),
+        (status = 409, description = "An asset is still uploading"),
+    ),
+)]
+pub async fn download_bundle(
+    Query(params): Query<BundleParameters>,
+    credentials: AssetCredentials,
+    method: Method,
+) -> TapferResult<Response> {
+    let mut ids = vec![];
+    for raw in params
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let id = TapferId::from_str(raw)
+            .map_err(|_| TapferError::InvalidBundle(format!("{raw:?} is not an asset ID")))?;
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    if ids.is_empty() {
+        return Err(TapferError::InvalidBundle("no asset IDs given".to_owned()));
+    }
+    if ids.len() > MAX_BUNDLE_ASSETS {
+        return Err(TapferError::InvalidBundle(format!(
+            "at most {MAX_BUNDLE_ASSETS} assets can be bundled"
+        )));
+    }
+
+    let mut assets = Vec::with_capacity(ids.len());
+    for id in ids {
+        let ((id, mut meta), fsm) = handlers::get_any_meta(&id.to_string()).await?;
+        credentials.authorize(id, &meta).await?;
+        if matches!(fsm, UpDownFsm::UpdownInProgress { .. }) {
+            return Err(TapferError::BundleInProgress(id));
+        }
+        // Only the body needs the CRC-32, so HEAD requests never wait for it
+        if matches!(params.format, ArchiveFormat::Zip)
+            && method != Method::HEAD
+            && !meta.is_multi_file()
+            && meta.crc32().is_none()
+        {
+            meta = record_crc32(id, &meta).await?;
+        }
+        assets.push((id, meta));
+    }
+
+    // Without clashes, the archive looks like the assets downloaded and extracted next to each other
+    // Paths within an asset never collide, so any collision is between assets
+    let mut paths = PathSet::default();
+    let clashing = !assets
+        .iter()
+        .flat_map(|(_, meta)| meta.files())
+        .all(|f| paths.insert(&f.path));
+
+    let mut builder = ArchiveBuilder::new(params.format);
+    let mut streams = Vec::with_capacity(assets.len() + 1);
+    for (id, meta) in assets {
+        for file in meta.files() {
+            builder.add_file(ArchiveFile {
+                name: if clashing {
+                    format!("{id}/{}", file.path)
+                } else {
+                    file.path.clone()
+                },
+                key: meta.storage_key(id, &file.path),
+                size: file.size,
+                crc32: file.crc32,
+                modified: meta.created(),
+            });
+        }
+        // Each asset is counted as downloaded on its own, once all its files were sent
+        streams.push(archive_stream(id, meta, builder.take_parts()));
+    }
+    let archive = builder.finish();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        handlers::attachment(&format!("tapfer-bundle.{}", params.format.extension())),
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(params.format.content_type()),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(archive.len()));
+    if method == Method::HEAD {
+        return Ok((StatusCode::OK, headers, Body::empty()).into_response());
+    }
+
+    // The trailer holds no files
+    let trailer = archive
+        .into_parts()
+        .into_iter()
+        .filter_map(|part| match part {
+            ArchivePart::Bytes(bytes) => Some(Ok(bytes)),
+            ArchivePart::File { .. } => None,
+        });
+    let body = stream::iter(streams).flatten().chain(stream::iter(trailer));
+    Ok((StatusCode::OK, headers, Body::from_stream(body)).into_response())
+}
+
+/// Assets whose CRC-32 was neither recorded while uploading nor by their checksum job yet are read once to compute it
+async fn record_crc32(id: TapferId, meta: &FileMeta) -> TapferResult<FileMeta> {
+    let key = meta.payload_key(id);
+    let crc32 = task::spawn_blocking(move || {
+        let mut file = BufReader::with_capacity(
+            config().limits.checksum_bufsize.as_usize(),
+            blob_store::blocking_reader(&key)?,
+        );
+        let mut hasher = crc32fast::Hasher::new();
+        loop {
+            let buf = file.fill_buf()?;
+            if buf.is_empty() {
+                return Ok::<_, TapferError>(hasher.finalize());
+            }
+            hasher.update(buf);
+            let n = buf.len();
+            file.consume(n);
+        }
+    })
+    .await
+    .map_err(|e| TapferError::StdIo(e.into()))??;
+    FileMeta::update(id, |meta| meta.set_crc32(crc32)).await
+}
@@ -1,104 +1,262 @@
+use crate::blob_store;
+use crate::configuration::config;
+use crate::handlers::download::UpDownFsm;
 use crate::handlers::get_any_meta;
-use crate::size;
-use crate::structs::error::{TapferErrorExt, TapferResult};
+use crate::index;
+use crate::jobs;
+use crate::jobs::{JobContext, JobKind};
+use crate::storage;
+use crate::storage::storage;
+use crate::structs::archive;
+use crate::structs::archive::ArchivePart;
+use crate::structs::asset_password::AssetCredentials;
+use crate::structs::digest;
+use crate::structs::digest::DigestAlgorithm;
+use crate::structs::error::{TapferError, TapferErrorExt, TapferResult};
 use crate::structs::file_meta::FileMeta;
 use crate::structs::tapfer_id::TapferId;
 use crate::websocket::{WsEvent, broadcast_event};
 use axum::extract::Path;
-use axum::response::{IntoResponse, Response};
-use axum_extra::extract::Host;
-use dashmap::DashSet;
-use http::StatusCode;
-use scopeguard::defer;
-use sha2::Digest;
-use std::fs::File;
-use std::io::BufReader;
-use std::ops::Not;
-use std::sync::LazyLock;
-use std::thread::sleep;
-use std::time::Duration;
-use std::{fs, io, thread};
-use tracing::{error, info};
+use axum::response::{Html, Response};
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+use tokio::task;
+use tracing::info;
 
 #[utoipa::path(
 	get,
-	path = "/uploads/{id}/checksum.sha512",
+	path = "/uploads/{id}/checksum.{algorithm}",
+	params(
+        ("algorithm" = String, Path, description = "`sha256`, `sha512`, `blake3` or `md5`"),
+	),
+	responses(
+        (status = 200, description = "Returns the hex checksum"),
+        (status = 202, description = "Checksum computation is in progress, retry later"),
+        (status = 401, description = "Asset is password protected"),
+        (status = 404, description = "Asset or algorithm does not exist"),
+	),
+)]
+pub async fn get_checksum(
+    Path((path, algorithm)): Path<(String, String)>,
+    credentials: AssetCredentials,
+) -> TapferResult<Response> {
+    let algorithm = DigestAlgorithm::from_str(&algorithm).map_err(|_| no_such_checksum())?;
+    let ((id, meta), fsm) = get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    match checksum_of_complete(id, &fsm, algorithm).await? {
+        Some(chksum) => Ok(Response::builder().body(chksum.into())?),
+        None => in_progress(),
+    }
+}
+
+#[utoipa::path(
+	get,
+	path = "/uploads/{id}/{sums}",
+	params(
+        ("sums" = String, Path, description = "`SHA256SUMS`, `SHA512SUMS`, `B3SUMS` or `MD5SUMS`"),
+	),
 	responses(
-        (status = 200, description = "Returns checksum"),
-        (status = 404, description = "Asset does not exist"),
+        (status = 200, description = "Checksum and name of the downloaded file, as checked by `sha256sum -c` and alike"),
+        (status = 202, description = "Checksum computation is in progress, retry later"),
+        (status = 401, description = "Asset is password protected"),
+        (status = 404, description = "Asset or algorithm does not exist"),
 	),
 )]
-pub async fn get_sha512sum(
-    Path(path): Path<String>,
-    Host(_host): Host,
-) -> TapferResult<impl IntoResponse> {
-    let ((id, _), _) = get_any_meta(&path).await?;
-    if let Some(chksum) = get_sha512_for_asset(id)? {
-        Ok(Response::builder().body(chksum)?)
+pub async fn get_sums(
+    Path((path, sums)): Path<(String, String)>,
+    credentials: AssetCredentials,
+) -> TapferResult<Response> {
+    let algorithm = DigestAlgorithm::from_sums_file(&sums).ok_or_else(no_such_checksum)?;
+    let ((id, meta), fsm) = get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    let Some(chksum) = checksum_of_complete(id, &fsm, algorithm).await? else {
+        return in_progress();
+    };
+    // Named like the download, so the check finds the file next to it
+    let name = if meta.is_multi_file() {
+        format!("{}.zip", meta.name())
     } else {
-        Ok(Response::builder()
-            .status(StatusCode::PROCESSING)
-            .body("Checksum computation is in progress".to_owned())?)
+        meta.name().to_owned()
+    };
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(format!("{chksum}  {name}\n").into())?)
+}
+
+/// Uploads in progress have no checksum yet, nor is one computed for them
+async fn checksum_of_complete(
+    id: TapferId,
+    fsm: &UpDownFsm,
+    algorithm: DigestAlgorithm,
+) -> TapferResult<Option<String>> {
+    match fsm {
+        UpDownFsm::Completed => get_checksum_for_asset(id, algorithm).await,
+        UpDownFsm::UpdownInProgress { .. } => Ok(None),
+    }
+}
+
+/// Informational statuses such as `102 Processing` cannot end a response
+fn in_progress() -> TapferResult<Response> {
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(header::RETRY_AFTER, "1")
+        .body("Checksum computation is in progress".into())?)
+}
+
+fn no_such_checksum() -> TapferError {
+    TapferError::Custom {
+        status_code: StatusCode::NOT_FOUND,
+        body: Html("No such checksum".to_owned()),
     }
 }
 
-pub fn get_sha512_for_asset(id: TapferId) -> TapferResult<Option<String>> {
-    let precomputed = fs::read_to_string(format!("data/{id}/checksum.sha512"));
+/// The stored checksum, or `None` while it is computed. Starts the computation when it is missing
+pub async fn get_checksum_for_asset(
+    id: TapferId,
+    algorithm: DigestAlgorithm,
+) -> TapferResult<Option<String>> {
+    let precomputed = storage().read(&checksum_key(id, algorithm)).await;
     if matches!(
         precomputed.as_ref().map_err(|e| e.kind()),
         Err(io::ErrorKind::NotFound)
     ) {
-        spawn_sha512_checksum(id);
+        spawn_checksum(id, algorithm);
     }
-    Ok(precomputed.ok())
+    Ok(precomputed
+        .ok()
+        .map(|chksum| String::from_utf8_lossy(&chksum).into_owned()))
 }
 
-static ACTIVE_CHECKSUMS: LazyLock<DashSet<TapferId>> = LazyLock::new(|| DashSet::new());
-
-pub fn spawn_sha512_checksum(id: TapferId) {
-    let core = move || {
-        let meta = FileMeta::read_from_id_blocking(id)?;
-        let mut asset = BufReader::with_capacity(
-            size!(100 M),
-            File::open(format!("data/{id}/{}", meta.name()))?,
-        );
-        let mut h = sha2::Sha512::new();
-        let _ = io::copy(&mut asset, &mut h)?;
-        let chksum = base16ct::lower::encode_string(&h.finalize());
-        fs::write(format!("data/{id}/checksum.sha512"), &chksum)?;
-        Ok(chksum)
+/// `Repr-Digest` value for a complete asset, in an algorithm the request asks for with `Want-Repr-Digest`,
+/// SHA-512 otherwise. `None` while no such checksum is stored
+pub async fn repr_digest(
+    id: TapferId,
+    request_headers: &HeaderMap,
+) -> TapferResult<Option<HeaderValue>> {
+    let wanted = match request_headers
+        .get(digest::WANT_REPR_DIGEST)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(field) => digest::wanted(field),
+        None => vec![DigestAlgorithm::Sha512],
     };
-    let already_inserted = ACTIVE_CHECKSUMS.insert(id).not();
-    if already_inserted {
-        return;
+    let mut member = None;
+    for algorithm in &wanted {
+        if let Some(chksum) = read_checksum(id, *algorithm).await {
+            member = digest::field_member(*algorithm, chksum.trim());
+            break;
+        }
+    }
+    // Later requests get the preferred algorithm once it is computed
+    if let Some(preferred) = wanted.first() {
+        get_checksum_for_asset(id, *preferred).await?;
+    }
+    Ok(member.map(HeaderValue::try_from).transpose()?)
+}
+
+fn checksum_key(id: TapferId, algorithm: DigestAlgorithm) -> String {
+    storage::asset_key(id, &algorithm.file_name())
+}
+
+/// The stored checksum, without computing a missing one
+pub async fn read_checksum(id: TapferId, algorithm: DigestAlgorithm) -> Option<String> {
+    let chksum = storage().read(&checksum_key(id, algorithm)).await.ok()?;
+    Some(String::from_utf8_lossy(&chksum).into_owned())
+}
+
+/// Persists a checksum computed elsewhere, such as while the asset was uploaded
+pub async fn write_checksum(
+    id: TapferId,
+    algorithm: DigestAlgorithm,
+    chksum: &str,
+) -> TapferResult<()> {
+    storage()
+        .write(&checksum_key(id, algorithm), chksum.as_bytes().to_vec())
+        .await?;
+    if algorithm == DigestAlgorithm::Sha256 {
+        index::set_checksum(id, chksum).await?;
     }
-    // Ignore handle
-    let _ = thread::Builder::new()
-        .name(format!("hasher_{id}"))
-        .spawn(move || {
-            // Defer also runs on panic - so the map isn't poisoned when the hashing thread panics
-            defer!(if ACTIVE_CHECKSUMS.remove(&id).is_none() {
-                error!("Checksum of {id} not found in ACTIVE_CHECKSUMS");
-            });
-            let res: TapferResult<String> = core();
-            match res {
-                Ok(chksum) => {
-                    info!("Computed sha512 for {id}");
-                    broadcast_event(
-                        id,
-                        WsEvent::Sha512Ready {
-                            chksum: chksum.clone(),
-                        },
-                    )
-                    .log_error("Failed to broadcast event");
-                    // Broadcast a 2nd time to avoid racy loads
-                    sleep(Duration::from_secs(1));
-                    broadcast_event(id, WsEvent::Sha512Ready { chksum })
-                        .log_error("Failed to broadcast event");
+    Ok(())
+}
+
+/// Tells open asset pages about a SHA-512 persisted by [`write_checksum`]
+pub fn announce_sha512(id: TapferId, chksum: String) {
+    broadcast_event(id, WsEvent::Sha512Ready { chksum }).log_error("Failed to broadcast event");
+}
+
+/// Queues the computation of a checksum that is missing
+pub fn spawn_checksum(id: TapferId, algorithm: DigestAlgorithm) {
+    jobs::enqueue(id, JobKind::Checksum { algorithm });
+}
+
+/// Reads the asset again for its checksum, for archives of several files and assets whose checksum
+/// was not computed while uploading. A missing CRC-32 is recorded along, so bundles need not read the asset for it
+pub async fn compute(
+    id: TapferId,
+    algorithm: DigestAlgorithm,
+    job: JobContext,
+) -> TapferResult<String> {
+    let meta = FileMeta::read_from_id(id).await?;
+    let mut crc = (!meta.is_multi_file() && meta.crc32().is_none()).then(crc32fast::Hasher::new);
+    let hashing = job.clone();
+    let (chksum, crc32) = task::spawn_blocking(move || {
+        let job = hashing;
+        // Multi-file assets are downloaded as archive, so that is what gets checksummed
+        let (total, parts) = if meta.is_multi_file() {
+            let archive = archive::zip_asset(id, &meta);
+            (archive.len(), archive.into_parts())
+        } else {
+            let key = meta.payload_key(id);
+            let len = meta.size();
+            (len, vec![ArchivePart::File { key, len }])
+        };
+        let mut h = algorithm.hasher();
+        let mut buf = vec![0; config().limits.checksum_bufsize.as_usize()];
+        let mut processed = 0;
+        for part in parts {
+            match part {
+                ArchivePart::Bytes(bytes) => {
+                    h.update(&bytes);
+                    processed += bytes.len() as u64;
                 }
-                Err(e) => {
-                    error!("Failed to checksum {id} because of: {e}");
+                ArchivePart::File { key, .. } => {
+                    let mut file = blob_store::blocking_reader(&key)?;
+                    loop {
+                        job.check_cancelled()?;
+                        let n = file.read(&mut buf)?;
+                        if n == 0 {
+                            break;
+                        }
+                        h.update(&buf[..n]);
+                        if let Some(crc) = &mut crc {
+                            crc.update(&buf[..n]);
+                        }
+                        processed += n as u64;
+                        job.progress(processed, total);
+                    }
                 }
             }
-        });
+        }
+        Ok::<_, TapferError>((h.finalize(), crc.map(crc32fast::Hasher::finalize)))
+    })
+    .await
+    .map_err(|e| TapferError::StdIo(e.into()))??;
+    {
+        // Deletions cancel under the lock, writing after one would recreate the asset's directory
+        let _guard = FileMeta::lock(id).await;
+        job.check_cancelled()?;
+        write_checksum(id, algorithm, &chksum).await?;
+        if let Some(crc32) = crc32 {
+            let mut meta = FileMeta::read_from_id(id).await?;
+            meta.set_crc32(crc32);
+            meta.write_to_id(id).await?;
+        }
+    }
+    info!("Computed {} for {id}", algorithm.name());
+    if algorithm == DigestAlgorithm::Sha512 {
+        announce_sha512(id, chksum.clone());
+    }
+    Ok(chksum)
 }
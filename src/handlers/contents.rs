@@ -0,0 +1,280 @@
+use crate::configuration::config;
+use crate::handlers;
+use crate::handlers::download::{DATE_FORMAT, UpDownFsm};
+use crate::public_url::PublicUrls;
+use crate::structs::archive_contents;
+use crate::structs::archive_contents::{ArchiveKind, ArchiveListing};
+use crate::structs::asset_password::{AssetCredentials, unlock_token};
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::FileMeta;
+use crate::structs::tapfer_id::TapferId;
+use askama::Template;
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{Html, IntoResponse, Response};
+use dashmap::DashMap;
+use futures_util::stream;
+use human_bytes::human_bytes;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::{OnceCell, mpsc};
+use tokio::task;
+use tokio_util::bytes::Bytes;
+use tracing::warn;
+
+/// Listings kept at most, the least recently used one makes room for the next
+const CACHED_LISTINGS: usize = 256;
+
+/// Listings by asset, the contents of a completed asset never change. Failures are not cached so
+/// that transient errors can be retried
+static LISTINGS: LazyLock<DashMap<TapferId, CachedListing>> = LazyLock::new(DashMap::new);
+/// Orders uses of cached listings for eviction
+static LISTING_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+struct CachedListing {
+    /// Shared so that concurrent requests wait for one listing instead of each decompressing
+    listing: Arc<OnceCell<Arc<ArchiveListing>>>,
+    last_used: AtomicU64,
+}
+
+/// Drops the cached listing of a deleted asset
+pub fn forget_listing(id: TapferId) {
+    LISTINGS.remove(&id);
+}
+
+/// Archives whose files can be listed and extracted. Extracting does not count as a download,
+/// so assets with a download limit are only available as a whole
+pub fn browsable(meta: &FileMeta) -> Option<ArchiveKind> {
+    if meta.removal_policy().download_limit().is_some() {
+        return None;
+    }
+    ArchiveKind::detect(meta)
+}
+
+/// The contents of a completed archive asset, or why they cannot be listed. `None` for other assets
+/// and those with a download limit
+pub async fn archive_listing(
+    id: TapferId,
+    meta: &FileMeta,
+) -> Option<Result<Arc<ArchiveListing>, String>> {
+    let kind = browsable(meta)?;
+    let cell = cached_listing(id);
+    let key = meta.payload_key(id);
+    let size = meta.size();
+    let listing = cell
+        .get_or_try_init(|| async move {
+            task::spawn_blocking(move || archive_contents::list(&key, kind, size))
+                .await
+                .map_err(|e| TapferError::StdIo(e.into()))
+                .flatten()
+                .map(Arc::new)
+        })
+        .await
+        .cloned()
+        .map_err(|e| {
+            warn!("Cannot list the contents of {id}: {e}");
+            e.to_string()
+        });
+    if listing.is_err() {
+        LISTINGS.remove_if(&id, |_, cached| {
+            Arc::ptr_eq(&cached.listing, &cell) && !cell.initialized()
+        });
+    }
+    Some(listing)
+}
+
+/// The cell holding the listing of `id`, inserted empty when there is none yet
+fn cached_listing(id: TapferId) -> Arc<OnceCell<Arc<ArchiveListing>>> {
+    let now = LISTING_CLOCK.fetch_add(1, Ordering::Relaxed);
+    let listing = {
+        let cached = LISTINGS.entry(id).or_insert_with(|| CachedListing {
+            listing: Arc::new(OnceCell::new()),
+            last_used: AtomicU64::new(now),
+        });
+        cached.last_used.store(now, Ordering::Relaxed);
+        cached.listing.clone()
+    };
+    // The entry guard is dropped, iterating while holding it would deadlock on its shard
+    if LISTINGS.len() > CACHED_LISTINGS {
+        let oldest = LISTINGS
+            .iter()
+            .min_by_key(|cached| cached.last_used.load(Ordering::Relaxed))
+            .map(|cached| *cached.key());
+        if let Some(oldest) = oldest {
+            LISTINGS.remove(&oldest);
+        }
+    }
+    listing
+}
+
+#[utoipa::path(
+    method(get, head),
+    path = "/uploads/{id}/entry/{path}",
+    params(
+        ("path" = String, Path, description = "Path of a file within an uploaded zip, tar or tar.gz archive, as listed on the asset page"),
+    ),
+    responses(
+        (status = 200, description = "Returns the extracted file. Does not count as a download of the asset"),
+        (status = 401, description = "Asset is password protected, send the `tapfer-password` header"),
+        (status = 403, description = "Asset has a download limit, so it can only be downloaded as a whole"),
+        (status = 404, description = "Asset does not exist, is no archive or has no such file"),
+        (status = 409, description = "Asset is still uploading"),
+        (status = 422, description = "The archive cannot be read, or the file exceeds the extraction limits"),
+    ),
+)]
+pub async fn download_archive_entry(
+    Path((path, entry_path)): Path<(String, String)>,
+    credentials: AssetCredentials,
+    method: Method,
+) -> TapferResult<Response> {
+    let ((id, meta), fsm) = handlers::get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    if matches!(fsm, UpDownFsm::UpdownInProgress { .. }) {
+        return Err(TapferError::Custom {
+            status_code: StatusCode::CONFLICT,
+            body: Html("The contents can be browsed once the upload has completed".to_owned()),
+        });
+    }
+    let not_found = || TapferError::Custom {
+        status_code: StatusCode::NOT_FOUND,
+        body: Html("The archive has no such file".to_owned()),
+    };
+    let Some(kind) = ArchiveKind::detect(&meta) else {
+        return Err(not_found());
+    };
+    if browsable(&meta).is_none() {
+        return Err(TapferError::Custom {
+            status_code: StatusCode::FORBIDDEN,
+            body: Html("Assets with a download limit can only be downloaded as a whole".to_owned()),
+        });
+    }
+    let listing = archive_listing(id, &meta)
+        .await
+        .ok_or_else(not_found)?
+        .map_err(TapferError::ArchiveContents)?;
+    let entry = listing.entry(&entry_path).ok_or_else(not_found)?.clone();
+    archive_contents::check_entry_size(&entry, meta.size())?;
+
+    let file_name = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_DISPOSITION, handlers::attachment(file_name));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(entry.size));
+    if method == Method::HEAD {
+        return Ok((StatusCode::OK, headers, Body::empty()).into_response());
+    }
+
+    // Archives are read synchronously, handing chunks over to the response as they are extracted
+    let (tx, rx) = mpsc::channel(4);
+    let archive_key = meta.payload_key(id);
+    let archive_size = meta.size();
+    task::spawn_blocking(move || {
+        let mut out = BufWriter::with_capacity(
+            config().limits.download_chunksize.as_usize(),
+            ChannelWriter(tx.clone()),
+        );
+        let res = archive_contents::extract(&archive_key, kind, archive_size, &entry, &mut out)
+            .and_then(|()| Ok(out.flush()?));
+        if let Err(e) = res {
+            warn!("Failed to extract {:?} from {id}: {e}", entry.path);
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+    let body = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok((StatusCode::OK, headers, Body::from_stream(body)).into_response())
+}
+
+#[derive(Template)]
+#[template(path = "components/archive_contents.html")]
+struct ArchiveContentsTemplate {
+    /// Contents of an uploaded archive, or why they cannot be listed
+    contents: Result<ArchiveContentsView, String>,
+}
+
+struct ArchiveContentsView {
+    entries: Vec<ContentLink>,
+    truncated: bool,
+}
+
+struct ContentLink {
+    path: String,
+    size: String,
+    modified: String,
+    url: String,
+}
+
+/// The archive listing of the download page, fetched separately as listing a large archive
+/// decompresses all of it
+pub async fn show_archive_contents(
+    Path(path): Path<String>,
+    urls: PublicUrls,
+    credentials: AssetCredentials,
+) -> TapferResult<Response> {
+    let ((id, meta), fsm) = handlers::get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    if matches!(fsm, UpDownFsm::UpdownInProgress { .. }) {
+        return Err(TapferError::Custom {
+            status_code: StatusCode::CONFLICT,
+            body: Html("The contents can be browsed once the upload has completed".to_owned()),
+        });
+    }
+    let listing = archive_listing(id, &meta)
+        .await
+        .ok_or_else(|| TapferError::Custom {
+            status_code: StatusCode::NOT_FOUND,
+            body: Html("The asset has no contents to browse".to_owned()),
+        })?;
+    // The download host may differ from this page, so the unlock cookie is not enough
+    let unlock = meta.password_hash().map(|_| unlock_token(id, &meta));
+    let contents = listing.and_then(|listing| {
+        let entries = listing
+            .entries
+            .iter()
+            .map(|entry| {
+                let url = urls.archive_entry(id, &entry.path);
+                Ok(ContentLink {
+                    path: entry.path.clone(),
+                    size: human_bytes(entry.size as f64),
+                    modified: match entry.modified {
+                        Some(modified) => modified.format(&DATE_FORMAT)?,
+                        None => String::new(),
+                    },
+                    url: match &unlock {
+                        Some(token) => format!("{url}?unlock={token}"),
+                        None => url,
+                    },
+                })
+            })
+            .collect::<Result<_, time::error::Format>>()
+            .map_err(|e| e.to_string())?;
+        Ok(ArchiveContentsView {
+            entries,
+            truncated: listing.truncated,
+        })
+    });
+    Ok(Html(ArchiveContentsTemplate { contents }.render()?).into_response())
+}
+
+/// Fails writes once the response is gone, which stops the extraction
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "download aborted"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,46 +1,66 @@
 use crate::UPLOAD_POOL;
 use crate::handlers::get_any_meta;
 use crate::retention_control::delete_asset;
-use crate::structs::error::TapferResult;
+use crate::structs::error::{TapferErrorExt, TapferResult};
+use crate::structs::management_token::authorize_owner;
+use crate::structs::tapfer_id::TapferId;
+use crate::updown::resumable;
+use crate::updown::resumable::RESUMABLE_UPLOADS;
 use crate::updown::upload_pool::UploadFsm;
 use axum::extract::Path;
+use axum::http::HeaderMap;
 use axum::response::{IntoResponse, Redirect};
 use std::time::Duration;
 use tokio::time::sleep;
-use tracing::{error, info};
+use tracing::info;
 
 #[utoipa::path(
-    get,
+    delete,
     path = "/uploads/{id}",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <management token>` as returned on upload"),
+    ),
     responses(
         (status = 303, description = "Asset deleted, redirects to home page"),
+        (status = 401, description = "Management token missing"),
+        (status = 403, description = "Management token does not match"),
         (status = 404, description = "Asset does not exist"),
     ),
 )]
-pub async fn request_delete_asset(Path(path): Path<String>) -> TapferResult<impl IntoResponse> {
-    let ((id, _), _) = get_any_meta(&path).await?;
+pub async fn request_delete_asset(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> TapferResult<impl IntoResponse> {
+    let ((id, meta), _) = get_any_meta(&path).await?;
+    authorize_owner(&headers, meta.management_token_hash())?;
     info!("Request to delete {id}");
+    delete_or_abort(id)
+        .await
+        .log_error(&format!("Failed to delete {id} due to"));
+    Ok(Redirect::to("/"))
+}
 
+/// Deletes a stored asset, or fails its upload along with the downloads waiting for it
+pub async fn delete_or_abort(id: TapferId) -> TapferResult<()> {
+    // A resumable upload between requests has no uploader to clean up after it
+    if RESUMABLE_UPLOADS.contains_key(&id) {
+        resumable::abort(id).await?;
+        info!("Terminated resumable upload {id} as requested");
+        return Ok(());
+    }
+    // Not held across awaits, which would block uploads registering in the same shard
+    let handle = UPLOAD_POOL.uploads.get(&id).map(|h| h.value().clone());
     // Ensure the uploader (if present) fails the upload
-    if let Some(handle) = UPLOAD_POOL.uploads.get(&id) {
-        let v = handle.value();
-        *v.write_fsm().await = UploadFsm::Failed;
-        v.notify_all_downloaders();
+    if let Some(handle) = handle {
+        *handle.write_fsm().await = UploadFsm::Failed;
+        handle.notify_all_downloaders();
         info!("Notified uploader and downloaders that {id} is slated for deletion");
         // Wait for all downloaders to abort and drop their resources gracefully
         sleep(Duration::from_millis(200)).await;
         info!("Aborted upload and downloads for {id} as requested");
     } else {
-        let r = delete_asset(id).await;
-        match r {
-            Ok(()) => {
-                info!("Deleted {id} as requested");
-            }
-            Err(e) => {
-                error!("Failed to delete {id} from filesystem due to {e}");
-            }
-        }
+        delete_asset(id).await?;
+        info!("Deleted {id} as requested");
     }
-
-    Ok(Redirect::to("/"))
+    Ok(())
 }
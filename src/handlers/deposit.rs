@@ -1,10 +1,9 @@
-use crate::configuration::{EMBED_DESCRIPTION, EMBED_TITLE, FAVICON, QR_CODE_ECC, QR_CODE_SIZE};
+use crate::configuration::config;
+use crate::public_url::PublicUrls;
 use crate::structs::error::TapferResult;
-use crate::websocket::wss_method;
 use askama::Template;
 use axum::extract::{Query, WebSocketUpgrade};
 use axum::response::{Html, IntoResponse, Response};
-use axum_extra::extract::Host;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use uuid::Uuid;
@@ -20,25 +19,22 @@ pub struct Deposit {
     ws_url: String,
 }
 
-pub async fn show_form(Host(host): Host) -> TapferResult<impl IntoResponse> {
+pub async fn show_form(urls: PublicUrls) -> TapferResult<impl IntoResponse> {
     let deposit_id = Uuid::new_v4().as_u64_pair().0; // Hacky? Sure. But this avoids another RNG library that we use once
 
     let qr_code = qrcode_generator::to_png_to_vec_from_str(
-        format!("https://{host}?deposit={deposit_id}",),
-        QR_CODE_ECC,
-        QR_CODE_SIZE,
+        format!("{}/?deposit={deposit_id}", urls.base()),
+        config().qr_code.ecc.into(),
+        config().qr_code.size,
     )?;
 
     let template = Deposit {
-        embed_image_url: FAVICON,
-        embed_description: EMBED_DESCRIPTION,
-        embed_title: EMBED_TITLE,
-        qr_size: QR_CODE_SIZE,
+        embed_image_url: &config().embed.favicon,
+        embed_description: &config().embed.description,
+        embed_title: &config().embed.title,
+        qr_size: config().qr_code.size,
         qr_b64: BASE64_STANDARD.encode(&qr_code),
-        ws_url: format!(
-            "{}://{host}/deposit/ws?deposit={deposit_id}",
-            wss_method(&host)
-        ),
+        ws_url: urls.websocket(&format!("/deposit/ws?deposit={deposit_id}")),
     };
 
     Ok(Html(template.render()?))
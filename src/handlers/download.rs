@@ -1,34 +1,53 @@
-use crate::configuration::{DOWNLOAD_CHUNKSIZE, EMBED_DESCRIPTION, QR_CODE_SIZE};
+use crate::blob_store;
+use crate::configuration::config;
 use crate::handlers;
-use crate::handlers::checksum::get_sha512_for_asset;
-use crate::handlers::is_localhost;
+use crate::handlers::checksum;
+use crate::handlers::checksum::get_checksum_for_asset;
+use crate::handlers::contents;
 use crate::handlers::qrcode::base64_qr_from_id;
+use crate::handlers::unlock::locked_response;
+use crate::public_url::PublicUrls;
 use crate::retention_control::delete_asset;
+use crate::storage::StorageReader;
+use crate::structs::archive;
+use crate::structs::archive::ArchivePart;
+use crate::structs::asset_password::{AssetCredentials, unlock_token};
+use crate::structs::byte_range;
+use crate::structs::byte_range::{RangeRequest, RangeSet};
+use crate::structs::digest::DigestAlgorithm;
 use crate::structs::error::{TapferError, TapferResult};
 use crate::structs::file_meta::{FileMeta, RemovalPolicy};
 use crate::structs::tapfer_id::TapferId;
+use crate::structs::validators::Validators;
 use crate::updown::upload_handle::UploadHandle;
 use crate::updown::upload_pool::UploadFsm;
-use crate::websocket::wss_method;
+use crate::websocket;
+use crate::websocket::WsEvent;
 use askama::Template;
 use axum::body::Body;
-use axum::extract::Path;
-use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
-use axum::response::{Html, IntoResponse};
-use axum_extra::extract::Host;
+use axum::extract::{ConnectInfo, FromRequestParts, Path};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{Html, IntoResponse, Response};
+use dashmap::DashMap;
 use futures_util::StreamExt;
+use futures_util::future::ready;
+use futures_util::stream::{self, BoxStream};
 use human_bytes::human_bytes;
+use std::convert::Infallible;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 use time::format_description::BorrowedFormatItem;
 use time::macros::format_description;
-use tokio::fs::File;
+use tokio::io::AsyncReadExt;
 use tokio::select;
 use tokio_util::bytes::Bytes;
-use tokio_util::io::ReaderStream;
-use tracing::{error, info, warn};
+use tracing::{error, info};
+use uuid::Uuid;
 
 #[derive(Template)]
 #[template(path = "download.html")]
@@ -43,36 +62,76 @@ struct DownloadTemplate<'a> {
     embed_description: &'a str,
     embed_title: &'a str,
     delete_url: &'a str,
+    asset_id: String,
     qr_b64: String,
     unix_expiry: i64,
     ws_url: &'a str,
     sha512: &'a str,
     sha512url: String,
+    remaining_downloads: Option<u32>,
+    /// Files of a multi-file asset
+    entries: Vec<EntryLink>,
+    /// Where the page fetches the contents of an uploaded archive from
+    contents_url: Option<String>,
 }
 
+struct EntryLink {
+    path: String,
+    size: String,
+    url: String,
+}
+
+/// Dates shown on asset pages
+pub static DATE_FORMAT: &[BorrowedFormatItem<'_>] =
+    format_description!("[hour]:[minute] [day]-[month]-[year]");
+
 pub async fn download_html(
     Path(path): Path<String>,
-    Host(host): Host,
-) -> TapferResult<impl IntoResponse> {
+    urls: PublicUrls,
+    credentials: AssetCredentials,
+) -> TapferResult<Response> {
     let ((id, meta), progress_handle) = handlers::get_any_meta(&path).await?;
+    if let Err(e) = credentials.authorize(id, &meta).await {
+        return locked_response(id, e);
+    }
+    // The download host may differ from this page, so the unlock cookie is not enough
+    let unlock = meta.password_hash().map(|_| unlock_token(id, &meta));
+    let unlockable = |url: String| match &unlock {
+        Some(token) => format!("{url}?unlock={token}"),
+        None => url,
+    };
+    let download_url = unlockable(urls.asset_download(id));
+    let entries = meta
+        .entries()
+        .iter()
+        .map(|entry| EntryLink {
+            path: entry.path.clone(),
+            size: human_bytes(entry.size as f64),
+            url: unlockable(urls.asset_entry(id, &entry.path)),
+        })
+        .collect();
 
-    static DES: &[BorrowedFormatItem<'_>] =
-        format_description!("[hour]:[minute] [day]-[month]-[year]");
     let expiry = match meta.removal_policy() {
         RemovalPolicy::SingleDownload => " after a single download".to_owned(),
-        RemovalPolicy::Expiry { .. } => meta.expires_on_utc().unwrap().format(&DES)?.clone(),
+        RemovalPolicy::MaxDownloads { n } => format!(" after {n} downloads"),
+        RemovalPolicy::Expiry { .. } | RemovalPolicy::MaxDownloadsOrExpiry { .. } => {
+            meta.expires_on_utc().unwrap().format(&DATE_FORMAT)?.clone()
+        }
     };
 
-    let localhost = is_localhost(&host);
-    let sha512 = get_sha512_for_asset(id)?;
+    // Listing an archive may take a while, so the page fetches it once it is shown
+    let contents_url = match progress_handle {
+        UpDownFsm::Completed if contents::browsable(&meta).is_some() => {
+            Some(unlockable(format!("/uploads/{id}/contents")))
+        }
+        _ => None,
+    };
+
+    let sha512 = get_checksum_for_asset(id, DigestAlgorithm::Sha512).await?;
     let template = DownloadTemplate {
         filename: meta.name(),
         expiry: &expiry,
-        download_url: if !localhost {
-            &format!("https://cdn.{host}/uploads/{id}/download")
-        } else {
-            &format!("http://localhost:3000/uploads/{id}/download")
-        },
+        download_url: &download_url,
         mimetype: meta.content_type(),
         filesize: if meta.known_size().is_some() {
             &human_bytes(meta.size() as f64)
@@ -82,202 +141,720 @@ pub async fn download_html(
             &human_bytes(meta.size() as f64)
         },
         embed_image_url: &format!("/qrcg/{id}"),
-        qr_size: QR_CODE_SIZE,
-        embed_description: EMBED_DESCRIPTION,
+        qr_size: config().qr_code.size,
+        embed_description: &config().embed.description,
         embed_title: meta.name(),
         delete_url: &format!("/uploads/{id}"),
-        qr_b64: base64_qr_from_id(id, &host)?,
+        asset_id: id.to_string(),
+        qr_b64: base64_qr_from_id(id, &urls)?,
         unix_expiry: meta
             .expires_on_utc()
             .map_or(0, time::UtcDateTime::unix_timestamp),
-        ws_url: &format!("{}://{host}/uploads/{id}/ws", wss_method(&host)),
+        ws_url: &unlockable(urls.websocket(&format!("/uploads/{id}/ws"))),
         sha512: sha512.as_deref().unwrap_or("computing..."),
         sha512url: format!("/uploads/{id}/checksum.sha512"),
+        remaining_downloads: meta.remaining_downloads(),
+        entries,
+        contents_url,
     };
 
-    Ok(Html(template.render()?))
+    Ok(Html(template.render()?).into_response())
 }
 
 #[utoipa::path(
-    get,
+    method(get, head),
     path = "/uploads/{id}/download",
+    params(
+        ("Range" = Option<String>, Header, description = "One or more byte ranges such as `bytes=0-1023` or `bytes=-500`. Ignored while an upload of unknown size is in progress, and for archives of multi-file assets"),
+        ("If-None-Match" = Option<String>, Header, description = "Answers with 304 when one of the entity tags matches"),
+        ("If-Modified-Since" = Option<String>, Header, description = "Answers with 304 when the asset was uploaded before this date. Ignored alongside `If-None-Match`"),
+        ("If-Range" = Option<String>, Header, description = "Entity tag or date the `Range` header is conditional on, otherwise the whole asset is sent"),
+    ),
     responses(
-        (status = 200, description = "Returns asset", headers
+        (status = 200, description = "Returns asset, or a ZIP archive of all its files for multi-file assets", headers
             (
                 ("content-disposition" = String, description = "File name"),
                 ("content-type" = String, description = "File mime type"),
                 ("content-length" = Option<u64>, description = "Size of asset"),
+                ("accept-ranges" = Option<String>, description = "`bytes` once the size of the asset is known"),
+                ("etag" = Option<String>, description = "Quoted SHA-512 of the asset, weak while the checksum is computed. Absent during upload"),
+                ("last-modified" = Option<String>, description = "Upload time. Absent during upload"),
+            )
+        ),
+        (status = 304, description = "The asset matches the conditional request headers"),
+        (status = 206, description = "Returns the requested range, or a `multipart/byteranges` body for several ranges", headers
+            (
+                ("content-range" = Option<String>, description = "Position of a single range within the asset"),
             )
         ),
+        (status = 401, description = "Asset is password protected, send the `tapfer-password` header"),
+        (status = 403, description = "Wrong password"),
         (status = 404, description = "Asset does not exist"),
+        (status = 409, description = "Multi-file asset is still uploading, its files can be downloaded individually"),
+        (status = 416, description = "No requested range overlaps the asset"),
+        (status = 429, description = "Too many wrong passwords for this asset"),
     ),
 
 )]
-pub async fn download_file(Path(path): Path<String>) -> TapferResult<impl IntoResponse> {
+pub async fn download_file(
+    Path(path): Path<String>,
+    credentials: AssetCredentials,
+    client: DownloadClient,
+    method: Method,
+    request_headers: HeaderMap,
+) -> TapferResult<Response> {
     let ((id, meta), fsm) = handlers::get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    let upload = match fsm {
+        UpDownFsm::Completed => None,
+        UpDownFsm::UpdownInProgress { handle } => Some(*handle),
+    };
+    if meta.is_multi_file() {
+        if upload.is_some() {
+            return Err(TapferError::ArchiveInProgress);
+        }
+        return serve_archive(id, meta, &method, &request_headers).await;
+    }
+
+    // Only complete assets have stable content to validate against
+    let validators = match upload {
+        Some(_) => None,
+        None => {
+            let sha512 = get_checksum_for_asset(id, DigestAlgorithm::Sha512).await?;
+            Some(
+                Validators::new(sha512.as_deref(), meta.size(), meta.created())
+                    .with_repr_digest(checksum::repr_digest(id, &request_headers).await?),
+            )
+        }
+    };
+    let file = ServedFile {
+        id,
+        path: meta.name().to_owned(),
+        content_type: meta.content_type().to_owned(),
+        total: declared_total(&meta, upload.as_ref()),
+        offset: 0,
+        validators,
+        upload,
+        meta,
+        client,
+    };
+    serve_file(file, &method, request_headers)
+}
+
+#[utoipa::path(
+    method(get, head),
+    path = "/uploads/{id}/files/{path}",
+    params(
+        ("path" = String, Path, description = "Path of a file within the asset, as listed on the asset page"),
+        ("Range" = Option<String>, Header, description = "Same as for the whole asset"),
+        ("If-None-Match" = Option<String>, Header, description = "Same as for the whole asset"),
+        ("If-Modified-Since" = Option<String>, Header, description = "Same as for the whole asset"),
+        ("If-Range" = Option<String>, Header, description = "Same as for the whole asset"),
+    ),
+    responses(
+        (status = 200, description = "Returns the file, while it is uploaded as well"),
+        (status = 206, description = "Returns the requested ranges of the file"),
+        (status = 304, description = "The file matches the conditional request headers"),
+        (status = 401, description = "Asset is password protected, send the `tapfer-password` header"),
+        (status = 404, description = "Asset or file does not exist"),
+        (status = 416, description = "No requested range overlaps the file"),
+    ),
+)]
+pub async fn download_entry(
+    Path((path, entry_path)): Path<(String, String)>,
+    credentials: AssetCredentials,
+    client: DownloadClient,
+    method: Method,
+    request_headers: HeaderMap,
+) -> TapferResult<Response> {
+    let ((id, meta), fsm) = handlers::get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    let (offset, entry) = meta.entry(&entry_path).ok_or_else(|| TapferError::Custom {
+        status_code: StatusCode::NOT_FOUND,
+        body: Html("The asset has no such file".to_owned()),
+    })?;
+    let upload = match fsm {
+        UpDownFsm::Completed => None,
+        UpDownFsm::UpdownInProgress { handle } => Some(*handle),
+    };
+
+    // The file being written has no final size yet, earlier files of the upload do
+    let writing = match &upload {
+        Some(handle) => match handle.read_fsm().await.get_progress() {
+            Some(progress) => handle.entry_progress(&entry.path, progress).is_some(),
+            None => false,
+        },
+        None => false,
+    };
+    let total = if meta.is_multi_file() {
+        (!writing).then_some(entry.size)
+    } else {
+        declared_total(&meta, upload.as_ref())
+    };
+    let validators = upload
+        .is_none()
+        .then(|| Validators::new(None, entry.size, meta.created()));
+    let file = ServedFile {
+        id,
+        path: entry.path,
+        content_type: entry.mimetype,
+        total,
+        offset,
+        validators,
+        upload,
+        meta,
+        client,
+    };
+    serve_file(file, &method, request_headers)
+}
+
+/// Size of a single-file asset as far as it is known.
+/// Uploads without a declared size only know it once they are complete, and a form upload's declared size
+/// only belongs to its first file once it is known that no other file follows
+fn declared_total(meta: &FileMeta, upload: Option<&UploadHandle>) -> Option<u64> {
+    match upload {
+        Some(handle) if handle.is_single_file() => meta.known_size(),
+        Some(_) => None,
+        None => Some(meta.size()),
+    }
+}
+
+/// A file of an asset and what is known about it, to be answered with its contents
+struct ServedFile {
+    id: TapferId,
+    meta: FileMeta,
+    /// Relative to the asset directory
+    path: String,
+    content_type: String,
+    /// `None` while its size is not final
+    total: Option<u64>,
+    /// Position of the file in the concatenation of all files of the asset, where its downloaded bytes are counted
+    offset: u64,
+    validators: Option<Validators>,
+    upload: Option<UploadHandle>,
+    client: DownloadClient,
+}
+
+fn serve_file(
+    file: ServedFile,
+    method: &Method,
+    mut request_headers: HeaderMap,
+) -> TapferResult<Response> {
+    let ServedFile {
+        id,
+        meta,
+        path,
+        content_type,
+        total,
+        offset,
+        validators,
+        upload,
+        client,
+    } = file;
+    let file_name = path.rsplit('/').next().unwrap_or(&path);
 
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_DISPOSITION, handlers::attachment(file_name));
+    if total.is_some() {
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+
+    if let Some(validators) = validators {
+        validators.insert_headers(&mut headers)?;
+        if validators.not_modified(&request_headers) {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+        if !validators.range_applies(&request_headers) {
+            request_headers.remove(header::RANGE);
+        }
+    }
+
+    let range = request_headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok());
+    let ranges = match (total, range) {
+        (Some(total), Some(range)) => byte_range::parse_range(range, total),
+        _ => RangeRequest::Full,
+    };
+
+    let delivered = Arc::new(Mutex::new(RangeSet::default()));
+    let key = meta.storage_key(id, &path);
+    let segment = |start: u64, end: Option<u64>| {
+        FileSegment {
+            key: key.clone(),
+            entry: path.clone(),
+            file: None,
+            offset: start,
+            end,
+            upload: upload.clone(),
+            delivered: delivered.clone(),
+            delivered_base: offset,
+        }
+        .into_stream()
+    };
+    let content_type_value = HeaderValue::from_str(&content_type)?;
+
+    let (status, body) = match ranges {
+        RangeRequest::Full => {
+            headers.insert(header::CONTENT_TYPE, content_type_value);
+            if let Some(total) = total {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(total));
+            }
+            (StatusCode::OK, segment(0, total).boxed())
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            let total = total.expect("ranges are only parsed with a known size");
+            headers.insert(header::CONTENT_TYPE, content_type_value);
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&range.content_range(total))?,
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range.len()));
+            (
+                StatusCode::PARTIAL_CONTENT,
+                segment(range.start, Some(range.end + 1)).boxed(),
+            )
+        }
+        RangeRequest::Partial(ranges) => {
+            let total = total.expect("ranges are only parsed with a known size");
+            let boundary = Uuid::new_v4().simple().to_string();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))?,
+            );
+
+            let mut length = 0;
+            let mut parts = Vec::with_capacity(ranges.len() + 1);
+            for range in ranges {
+                let part_header = format!(
+                    "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    content_type,
+                    range.content_range(total)
+                );
+                length += part_header.len() as u64 + range.len();
+                parts.push(
+                    stream::once(ready(Ok(Bytes::from(part_header))))
+                        .chain(segment(range.start, Some(range.end + 1)))
+                        .boxed(),
+                );
+            }
+            let closing = format!("\r\n--{boundary}--\r\n");
+            length += closing.len() as u64;
+            parts.push(stream::once(ready(Ok(Bytes::from(closing)))).boxed());
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+            (
+                StatusCode::PARTIAL_CONTENT,
+                stream::iter(parts).flatten().boxed(),
+            )
+        }
+        RangeRequest::Unsatisfiable => {
+            let total = total.expect("ranges are only parsed with a known size");
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{total}"))],
+            )
+                .into_response());
+        }
+    };
+
+    // The body is never polled, so nothing is counted as downloaded
+    if method == Method::HEAD {
+        return Ok((status, headers, Body::empty()).into_response());
+    }
+    let wrapped = DownloadStream::new(body, id, meta, upload, delivered, client);
+    Ok((status, headers, Body::from_stream(wrapped)).into_response())
+}
+
+/// Streams all files of a multi-file asset as one ZIP archive. Ranges are not supported
+async fn serve_archive(
+    id: TapferId,
+    meta: FileMeta,
+    method: &Method,
+    request_headers: &HeaderMap,
+) -> TapferResult<Response> {
+    let archive = archive::zip_asset(id, &meta);
     let mut headers = HeaderMap::new();
     headers.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_str(meta.content_type())?,
+        header::CONTENT_DISPOSITION,
+        handlers::attachment(&format!("{}.zip", meta.name())),
     );
     headers.insert(
-        header::CONTENT_DISPOSITION,
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", meta.name()))?,
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
     );
-    // Add size when it is known
-    if let Some(known) = meta.known_size() {
-        headers.insert(
-            header::CONTENT_LENGTH,
-            HeaderValue::from_str(&known.to_string())?,
-        );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(archive.len()));
+    // The checksum of a multi-file asset is the one of its archive
+    let sha512 = get_checksum_for_asset(id, DigestAlgorithm::Sha512).await?;
+    let validators = Validators::new(sha512.as_deref(), archive.len(), meta.created())
+        .with_repr_digest(checksum::repr_digest(id, request_headers).await?);
+    validators.insert_headers(&mut headers)?;
+    if validators.not_modified(request_headers) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
     }
-    // or when there is no ongoing upload
-    else if matches!(fsm, UpDownFsm::Completed) {
-        headers.insert(
-            header::CONTENT_LENGTH,
-            HeaderValue::from_str(&meta.size().to_string())?,
-        );
+    if method == Method::HEAD {
+        return Ok((StatusCode::OK, headers, Body::empty()).into_response());
     }
 
-    let path = format!("data/{id}/{}", meta.name());
-    let file = File::open(&path).await?;
-    let stream = ReaderStream::with_capacity(file, DOWNLOAD_CHUNKSIZE);
+    let body = archive_stream(id, meta, archive.into_parts());
+    Ok((StatusCode::OK, headers, Body::from_stream(body)).into_response())
+}
+
+/// Streams archive parts holding the files of one complete asset in order, counting its download once all of them were delivered
+pub fn archive_stream(
+    id: TapferId,
+    meta: FileMeta,
+    parts: Vec<ArchivePart>,
+) -> BoxStream<'static, io::Result<Bytes>> {
+    // Files are counted at their position in the asset, like downloads of them individually
+    let delivered = Arc::new(Mutex::new(RangeSet::default()));
+    let mut delivered_base = 0;
+    let segments = delivered.clone();
+    let body = stream::iter(parts)
+        .flat_map(move |part| match part {
+            ArchivePart::Bytes(bytes) => stream::once(ready(Ok(bytes))).boxed(),
+            ArchivePart::File { key, len } => {
+                let segment = FileSegment {
+                    key,
+                    entry: String::new(),
+                    file: None,
+                    offset: 0,
+                    end: Some(len),
+                    upload: None,
+                    delivered: segments.clone(),
+                    delivered_base,
+                };
+                delivered_base += len;
+                segment.into_stream().boxed()
+            }
+        })
+        .boxed();
+    // Sent in a single response, which has to deliver everything
+    DownloadStream::new(body, id, meta, None, delivered, DownloadClient::default()).boxed()
+}
+
+/// Bytes handed out of each download-limited asset to each client, across all its requests since it last completed a download
+static DELIVERED_RANGES: LazyLock<DashMap<(TapferId, IpAddr), RangeSet>> =
+    LazyLock::new(DashMap::new);
+
+/// Drops the partial download state of a deleted asset
+pub fn forget_delivered_ranges(id: TapferId) {
+    DELIVERED_RANGES.retain(|(asset, _), _| *asset != id);
+}
+
+/// Address of the downloading client, so that the range requests of one client add up to a download,
+/// while those of different clients do not. Behind `server.trusted_proxies` it is taken from `X-Forwarded-For`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownloadClient(Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for DownloadClient {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Unix sockets have no peer address
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip());
+        let trusted = |ip: Option<IpAddr>| {
+            config()
+                .server
+                .trusted_proxies
+                .iter()
+                .any(|p| p.contains(ip))
+        };
+        if !trusted(peer) {
+            return Ok(Self(peer));
+        }
 
-    let wrapped = DownloadStream::new(stream, id, meta, fsm);
-    Ok((headers, Body::from_stream(wrapped)))
+        // Every proxy appends the address it received the request from,
+        // so the client is the last address not belonging to a trusted proxy
+        let mut client = peer;
+        let forwarded: Vec<&str> = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        for hop in forwarded.into_iter().rev() {
+            let Ok(ip) = hop.trim().parse() else {
+                break;
+            };
+            client = Some(ip);
+            if !trusted(client) {
+                break;
+            }
+        }
+        Ok(Self(client))
+    }
 }
 
-/// A stream wrapper that deletes the file when dropped and rate-limits download during updown
+/// A stream wrapper that counts the download when dropped
 struct DownloadStream {
-    inner: ReaderStream<File>,
+    inner: BoxStream<'static, io::Result<Bytes>>,
     meta: FileMeta,
     id: TapferId,
-    fsm: UpDownFsm,
-    downloaded_bytes: u64,
+    upload: Option<UploadHandle>,
+    /// Filled by the [`FileSegment`]s making up `inner`
+    delivered: Arc<Mutex<RangeSet>>,
+    client: DownloadClient,
+    finished: bool,
 }
 
 /// FSM describing the state of a possibly ongoing upload
 pub enum UpDownFsm {
     Completed,
-    UpdownInProgress { progress: u64, handle: UploadHandle },
-}
-
-impl UpDownFsm {
-    /// Adds extra progress to current one.
-    /// Does nothing when the upload is already complete
-    pub fn add_progress(&mut self, additional: u64) {
-        if let UpDownFsm::UpdownInProgress { progress, .. } = self {
-            *progress += additional;
-        }
-    }
+    UpdownInProgress { handle: Box<UploadHandle> },
 }
 
 impl DownloadStream {
-    fn new(inner: ReaderStream<File>, id: TapferId, meta: FileMeta, fsm: UpDownFsm) -> Self {
+    fn new(
+        inner: BoxStream<'static, io::Result<Bytes>>,
+        id: TapferId,
+        meta: FileMeta,
+        upload: Option<UploadHandle>,
+        delivered: Arc<Mutex<RangeSet>>,
+        client: DownloadClient,
+    ) -> Self {
         Self {
             inner,
             meta,
             id,
-            fsm,
-            downloaded_bytes: 0,
+            upload,
+            delivered,
+            client,
+            finished: false,
         }
     }
 }
 
-/// Responsible for deleting single-download files.
-/// Skips deletion when the download is initiated during upload
+/// Responsible for counting completed downloads of download-limited assets and deleting them once the limit is reached.
+/// A download counts once the bytes delivered by this and earlier (range) requests of the same client cover the whole asset.
+/// Skips counting when the upload did not complete
 impl Drop for DownloadStream {
     fn drop(&mut self) {
+        if self.meta.removal_policy().download_limit().is_none() {
+            return;
+        }
         // Do not delete files in upload when an in-progress download fails early
-        if !matches!(self.fsm, UpDownFsm::Completed) {
+        if let Some(handle) = &self.upload
+            && !handle.read_fsm_blocking().is_complete()
+        {
+            return;
+        }
+        let delivered = std::mem::take(&mut *self.delivered.lock().expect("poisoned range set"));
+        // Empty assets are complete without delivering anything
+        if delivered.is_empty() && !self.finished {
             return;
         }
-        let meta = self.meta.clone();
-        let id = self.id;
 
-        if meta.remove_after_download() {
-            if meta.size() != self.downloaded_bytes {
-                warn!("Not removing {id} as this download filed");
-                return;
+        let (id, client) = (self.id, self.client);
+        tokio::spawn(async move {
+            if let Err(e) = record_delivered_ranges(id, client, delivered).await {
+                error!("Failed to record download of {id} because {e:?}");
             }
-            tokio::spawn(async move {
-                info!("Removing {id} as its download has completed");
-                match delete_asset(id).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        error!("Failed to delete {id} because {e:?}");
-                    }
+        });
+    }
+}
+
+/// Merges the bytes of a finished request into the client's coverage of the asset, counting a download once it is complete.
+/// Requests of unknown clients only count when they delivered everything themselves
+async fn record_delivered_ranges(
+    id: TapferId,
+    client: DownloadClient,
+    delivered: RangeSet,
+) -> TapferResult<()> {
+    let total = FileMeta::read_from_id(id).await?.size();
+    let complete = match client.0 {
+        None => delivered.covers(total),
+        Some(ip) => {
+            let complete = {
+                let mut coverage = DELIVERED_RANGES.entry((id, ip)).or_default();
+                coverage.extend(&delivered);
+                let complete = coverage.covers(total);
+                if complete {
+                    *coverage = RangeSet::default();
                 }
-            });
+                complete
+            };
+            DELIVERED_RANGES.remove_if(&(id, ip), |_, coverage| coverage.is_empty());
+            complete
         }
+    };
+    if complete {
+        record_completed_download(id).await
+    } else {
+        info!("Not counting partial download of {id} yet");
+        Ok(())
     }
 }
 
-/// Main goals here:
-/// Permit unbounded download when the asset is a regular file, transparently polling inner.
-/// Throttle download to the already uploaded (and written) data boundary, when upload is in progress.
-/// Abort download when the uploader failed/cancelled.
+/// Counts a completed download and removes the asset once no downloads remain
+async fn record_completed_download(id: TapferId) -> TapferResult<()> {
+    let meta = FileMeta::update(id, FileMeta::record_download).await?;
+    match meta.remaining_downloads() {
+        Some(0) => {
+            info!("Removing {id} as its download limit has been reached");
+            delete_asset(id).await?;
+        }
+        Some(remaining) => {
+            info!("{id} has {remaining} downloads left");
+            websocket::broadcast_event(id, WsEvent::DownloadRecorded { remaining })?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
 impl futures_core::Stream for DownloadStream {
     type Item = Result<Bytes, io::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // Check if were in progress
-        match &self.fsm {
-            // Conditionally wake here
-            UpDownFsm::UpdownInProgress {
-                handle,
-                progress: download_progress,
-            } => {
-                let upload_fsm = *handle.read_fsm_blocking();
-
-                // Ensure all branches either return or wake
-                match upload_fsm {
-                    // Abort download on upload error
-                    UploadFsm::Failed => {
-                        return Poll::Ready(Some(Err(TapferError::Custom {
-                            status_code: StatusCode::GONE,
-                            body: Html("Upload was aborted".to_owned()),
-                        }
-                        .into())));
-                    }
-                    // Wake once progress is available only
-                    UploadFsm::InProgress {
-                        progress: upload_progress,
-                    } => {
-                        // Delay polling the file when it is incomplete and the current progress is very close to the upload progress
-                        if (upload_progress - DOWNLOAD_CHUNKSIZE as u64 * 2) < *download_progress {
-                            let waker = cx.waker().clone();
-                            let handle = handle.clone();
-                            // Ensure that we do not wait for progress perpetually, time out after a bit to poll the UploadFSM again in case it failed
-                            tokio::spawn(async move {
-                                let timeout = tokio::time::sleep(Duration::from_millis(100));
-                                let progress = handle.wait_for_progress();
-                                select! {
-                                    () = timeout => (),
-                                    () = progress => (),
-                                }
-                                waker.wake();
-                            });
-                            return Poll::Pending;
-                        }
+        let poll_res = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(None) = poll_res {
+            self.finished = true;
+        }
+        poll_res
+    }
+}
+
+/// Reads `start..end` of a file of the asset, or up to its end when `end` is unknown.
+///
+/// Main goals here:
+/// Permit unbounded download when the asset is a regular file.
+/// Throttle download to the already uploaded (and written) data boundary, when upload is in progress.
+/// Abort download when the uploader failed/cancelled.
+struct FileSegment {
+    key: String,
+    /// Path of the file within the asset, to follow its progress while uploading
+    entry: String,
+    file: Option<StorageReader>,
+    offset: u64,
+    end: Option<u64>,
+    upload: Option<UploadHandle>,
+    delivered: Arc<Mutex<RangeSet>>,
+    /// Offset of the file within the asset, added to the delivered ranges
+    delivered_base: u64,
+}
+
+impl FileSegment {
+    fn into_stream(self) -> impl futures_core::Stream<Item = io::Result<Bytes>> + Send + 'static {
+        stream::unfold(Some(self), |segment| async move {
+            let mut segment = segment?;
+            match segment.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(segment))),
+                Ok(None) => None,
+                // End the stream after reporting the error
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let remaining = self
+            .end
+            .map_or(u64::MAX, |end| end.saturating_sub(self.offset));
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(blob_store::reader(&self.key, self.offset).await?),
+        };
+
+        let chunksize = config().limits.download_chunksize.bytes().min(remaining);
+        let mut buf = vec![0; usize::try_from(chunksize).unwrap_or(usize::MAX)];
+        loop {
+            let uploading = match &self.upload {
+                Some(handle) => match *handle.read_fsm().await {
+                    UploadFsm::Failed => return Err(upload_aborted()),
+                    UploadFsm::InProgress { progress } | UploadFsm::Stalled { progress } => {
+                        // Earlier files of a multi-file upload are already complete
+                        handle.entry_progress(&self.entry, progress)
                     }
-                    // Wake once such that we get polled again to wake from below
-                    UploadFsm::Completed => {
-                        self.fsm = UpDownFsm::Completed;
-                        cx.waker().wake_by_ref();
+                    UploadFsm::Completed => None,
+                },
+                None => None,
+            };
+            if uploading.is_none() {
+                self.upload = None;
+            }
+
+            // Reads stop at the acknowledged progress, beyond it the file may hold bytes
+            // of a tus body whose checksum is not verified yet
+            let n = match uploading {
+                Some(progress) if progress <= self.offset => 0,
+                Some(progress) => {
+                    let available = usize::try_from(progress - self.offset).unwrap_or(usize::MAX);
+                    let len = buf.len().min(available);
+                    file.read(&mut buf[..len]).await?
+                }
+                None => file.read(&mut buf).await?,
+            };
+            if n > 0 {
+                buf.truncate(n);
+                let chunk = self.offset..self.offset + n as u64;
+                if self.end == Some(chunk.end) {
+                    // The upload may still be rejected after its last byte, such as for a checksum mismatch.
+                    // Holding back the final chunk until then keeps the download from appearing complete
+                    self.await_verified().await?;
+                }
+                self.offset = chunk.end;
+                self.delivered
+                    .lock()
+                    .expect("poisoned range set")
+                    .insert(self.delivered_base + chunk.start..self.delivered_base + chunk.end);
+                return Ok(Some(Bytes::from(buf)));
+            }
+
+            match (&self.upload, self.end) {
+                (Some(handle), _) => {
+                    // Ensure that we do not wait for progress perpetually, time out after a bit to poll the UploadFSM again in case it failed
+                    let timeout = tokio::time::sleep(Duration::from_millis(100));
+                    select! {
+                        () = timeout => (),
+                        () = handle.wait_for_progress() => (),
                     }
                 }
+                (None, None) => return Ok(None),
+                (None, Some(_)) => return Err(io::ErrorKind::UnexpectedEof.into()),
             }
-            // Wake, always
-            _ => cx.waker().wake_by_ref(),
         }
+    }
 
-        let poll_res = self.inner.poll_next_unpin(cx);
-        if let Poll::Ready(Some(Ok(b))) = &poll_res {
-            self.fsm.add_progress(b.len() as u64);
-            self.downloaded_bytes += b.len() as u64;
+    /// Waits for the upload of a completely read file to be accepted, failing when it is aborted instead
+    async fn await_verified(&mut self) -> io::Result<()> {
+        while let Some(handle) = &self.upload {
+            let verifying = match *handle.read_fsm().await {
+                UploadFsm::Failed => return Err(upload_aborted()),
+                UploadFsm::InProgress { progress } | UploadFsm::Stalled { progress } => {
+                    // Earlier files of a multi-file upload are already complete
+                    handle.entry_progress(&self.entry, progress).is_some()
+                }
+                UploadFsm::Completed => false,
+            };
+            if !verifying {
+                self.upload = None;
+                break;
+            }
+            let timeout = tokio::time::sleep(Duration::from_millis(100));
+            select! {
+                () = timeout => (),
+                () = handle.wait_for_progress() => (),
+            }
         }
-        poll_res
+        Ok(())
+    }
+}
+
+fn upload_aborted() -> io::Error {
+    TapferError::Custom {
+        status_code: StatusCode::GONE,
+        body: Html("Upload was aborted".to_owned()),
     }
+    .into()
 }
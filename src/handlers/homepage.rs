@@ -1,9 +1,9 @@
-use crate::configuration::{EMBED_DESCRIPTION, EMBED_TITLE, FAVICON, QR_CODE_SIZE};
+use crate::configuration::{ExpirationPreset, config};
 use crate::handlers::qrcode::random_base64_qr_from_id;
+use crate::public_url::PublicUrls;
 use crate::structs::error::TapferResult;
 use askama::Template;
 use axum::response::{Html, IntoResponse};
-use axum_extra::extract::Host;
 
 #[derive(Template)]
 #[template(path = "homepage.html")]
@@ -13,15 +13,19 @@ pub struct Homepage {
     embed_title: &'static str,
     qr_size: usize,
     qr_b64: String,
+    upload_url: String,
+    presets: &'static [ExpirationPreset],
 }
 
-pub async fn show_form(Host(host): Host) -> TapferResult<impl IntoResponse> {
+pub async fn show_form(urls: PublicUrls) -> TapferResult<impl IntoResponse> {
     let template = Homepage {
-        embed_image_url: FAVICON,
-        embed_description: EMBED_DESCRIPTION,
-        embed_title: EMBED_TITLE,
-        qr_size: QR_CODE_SIZE,
-        qr_b64: random_base64_qr_from_id(&host)?,
+        embed_image_url: &config().embed.favicon,
+        embed_description: &config().embed.description,
+        embed_title: &config().embed.title,
+        qr_size: config().qr_code.size,
+        qr_b64: random_base64_qr_from_id(&urls)?,
+        upload_url: urls.download().to_owned(),
+        presets: &config().expiration.presets,
     };
 
     Ok(Html(template.render()?))
@@ -0,0 +1,25 @@
+use crate::handlers::get_any_meta;
+use crate::jobs;
+use crate::jobs::JobSummary;
+use crate::structs::asset_password::AssetCredentials;
+use crate::structs::error::TapferResult;
+use axum::Json;
+use axum::extract::Path;
+
+#[utoipa::path(
+	get,
+	path = "/uploads/{id}/jobs",
+	responses(
+        (status = 200, description = "Returns the queued and running post-processing jobs of the asset, with their attempts and progress"),
+        (status = 401, description = "Asset is password protected"),
+        (status = 404, description = "Asset does not exist"),
+	),
+)]
+pub async fn list_jobs(
+    Path(path): Path<String>,
+    credentials: AssetCredentials,
+) -> TapferResult<Json<Vec<JobSummary>>> {
+    let ((id, meta), _) = get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    Ok(Json(jobs::jobs_of(id)))
+}
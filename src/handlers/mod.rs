@@ -1,36 +1,43 @@
 use crate::UPLOAD_POOL;
 use crate::handlers::download::UpDownFsm;
 use crate::handlers::not_found::NotFound;
+use crate::index;
 use crate::structs::error::{TapferError, TapferResult};
 use crate::structs::file_meta::FileMeta;
 use crate::structs::tapfer_id::TapferId;
 use askama::Template;
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::Html;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use std::str::FromStr;
-use tokio::fs;
 
+pub mod admin;
+pub mod bundle;
 pub(crate) mod checksum;
+pub mod contents;
 pub mod delete;
 pub mod deposit;
 pub mod download;
 pub mod homepage;
+pub mod jobs;
+pub mod modify;
 mod not_found;
 pub mod qrcode;
+pub mod tus;
+pub mod unlock;
 pub mod upload;
 
-async fn get_any_meta(path: &String) -> TapferResult<((TapferId, FileMeta), UpDownFsm)> {
+pub(crate) async fn get_any_meta(path: &str) -> TapferResult<((TapferId, FileMeta), UpDownFsm)> {
     let id = TapferId::from_str(path)?;
-    let res = match fs::try_exists(&format!("data/{id}/meta.toml")).await.ok() {
+    let res = match index::get(id).await? {
         // Regular download
-        Some(true) => (
-            FileMeta::read_from_id_path(&path).await?,
-            UpDownFsm::Completed,
-        ),
+        Some(meta) => ((id, meta), UpDownFsm::Completed),
         // In-progress upload or doesnt exist
         _ => {
             let id = TapferId::from_str(path)?;
-            match UPLOAD_POOL.uploads.get(&id) {
+            // Not held across awaits, which would block uploads registering in the same shard
+            let handle = UPLOAD_POOL.uploads.get(&id).map(|h| h.value().clone());
+            match handle {
                 // The upload is not in progress either, so it does not exist
                 None => {
                     return Err(TapferError::Custom {
@@ -39,20 +46,12 @@ async fn get_any_meta(path: &String) -> TapferResult<((TapferId, FileMeta), UpDo
                     });
                 }
                 // The upload is in-progress
-                Some(handle) => {
-                    let fsm = if UPLOAD_POOL.uploads.contains_key(&id) {
-                        UpDownFsm::UpdownInProgress {
-                            progress: 0,
-                            handle: handle.clone(),
-                        }
-                    } else {
-                        UpDownFsm::Completed
-                    };
-                    (
-                        (*handle.key(), FileMeta::from_upload_handle(handle.value())),
-                        fsm,
-                    )
-                }
+                Some(handle) => (
+                    (id, FileMeta::from_upload_handle(&handle).await),
+                    UpDownFsm::UpdownInProgress {
+                        handle: Box::new(handle),
+                    },
+                ),
             }
         }
     };
@@ -62,3 +61,58 @@ async fn get_any_meta(path: &String) -> TapferResult<((TapferId, FileMeta), UpDo
 pub fn is_localhost(host: &str) -> bool {
     host.starts_with("localhost") || host.starts_with("127.0.0.1")
 }
+
+/// `attr-char` of RFC 5987, everything else is percent-encoded
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// `Content-Disposition` offering `file_name` as RFC 6266 `filename*`,
+/// with an ASCII approximation for clients that only understand `filename`
+pub(crate) fn attachment(file_name: &str) -> HeaderValue {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            ' ' => ' ',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded = utf8_percent_encode(file_name, ATTR_CHAR);
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}"
+    ))
+    .expect("only visible ASCII remains")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachment_escapes_file_names() {
+        assert_eq!(
+            attachment("report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\"; filename*=UTF-8''report%202024.pdf"
+        );
+        assert_eq!(
+            attachment("a\"b\r\nc.txt"),
+            "attachment; filename=\"a_b__c.txt\"; filename*=UTF-8''a%22b%0D%0Ac.txt"
+        );
+        assert_eq!(
+            attachment("grüße.txt"),
+            "attachment; filename=\"gr__e.txt\"; filename*=UTF-8''gr%C3%BC%C3%9Fe.txt"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::configuration::config;
+use crate::handlers::download::UpDownFsm;
+use crate::handlers::get_any_meta;
+use crate::storage;
+use crate::storage::storage;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::expiration::{self, Expiration};
+use crate::structs::file_meta;
+use crate::structs::file_meta::FileMeta;
+use crate::structs::management_token::authorize_owner;
+use crate::websocket;
+use crate::websocket::WsEvent;
+use axum::Json;
+use axum::extract::Path;
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use std::str::FromStr;
+use time::UtcDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::info;
+
+/// Every field is optional, omitted fields stay unchanged
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AssetPatch {
+    /// Same format as the `expiration` upload parameter, measured from now
+    expiration: Option<String>,
+    /// `null` lifts the download limit
+    #[serde(default, deserialize_with = "double_option")]
+    #[schema(value_type = Option<u32>)]
+    max_downloads: Option<Option<u32>>,
+    /// New download filename, or archive name for multi-file assets
+    name: Option<String>,
+    /// New MIME type
+    content_type: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct AssetSummary {
+    name: String,
+    content_type: String,
+    /// RFC 3339, absent when the asset only expires by downloads
+    expires: Option<String>,
+    remaining_downloads: Option<u32>,
+}
+
+impl AssetSummary {
+    fn new(meta: &FileMeta) -> TapferResult<Self> {
+        Ok(Self {
+            name: meta.name().to_owned(),
+            content_type: meta.content_type().to_owned(),
+            expires: meta
+                .expires_on_utc()
+                .map(|e| e.format(&Rfc3339))
+                .transpose()?,
+            remaining_downloads: meta.remaining_downloads(),
+        })
+    }
+}
+
+/// Distinguishes an explicit `null` from an omitted field
+fn double_option<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/uploads/{id}",
+    params(
+        ("Authorization" = String, Header, description = "`Bearer <management token>` as returned on upload"),
+    ),
+    request_body = AssetPatch,
+    responses(
+        (status = 200, description = "Asset after the modification", body = AssetSummary),
+        (status = 400, description = "Invalid modification"),
+        (status = 401, description = "Management token missing"),
+        (status = 403, description = "Management token does not match"),
+        (status = 404, description = "Asset does not exist"),
+        (status = 409, description = "Asset is still uploading, or the new name is taken by another file of the asset"),
+    ),
+)]
+pub async fn modify_asset(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    Json(patch): Json<AssetPatch>,
+) -> TapferResult<impl IntoResponse> {
+    let ((id, meta), fsm) = get_any_meta(&path).await?;
+    authorize_owner(&headers, meta.management_token_hash())?;
+    if matches!(fsm, UpDownFsm::UpdownInProgress { .. }) {
+        return Err(TapferError::AssetInProgress);
+    }
+
+    let expiration = patch
+        .expiration
+        .as_deref()
+        .map(|e| Expiration::from_str(e).map_err(|_| TapferError::InvalidExpiration(e.to_owned())))
+        .transpose()?;
+    if let Some(name) = &patch.name {
+        file_meta::validate_file_name(name).map_err(TapferError::InvalidAssetPatch)?;
+    }
+    if let Some(content_type) = &patch.content_type {
+        mime::Mime::from_str(content_type).map_err(|_| {
+            TapferError::InvalidAssetPatch(format!("{content_type:?} is not a valid MIME type"))
+        })?;
+    }
+
+    let _guard = FileMeta::lock(id).await;
+    // Re-read under the lock, downloads may have been counted in the meantime
+    let mut meta = FileMeta::read_from_id(id).await?;
+
+    if expiration.is_some() || patch.max_downloads.is_some() {
+        let policy = expiration::patch_removal_policy(
+            meta.removal_policy(),
+            meta.created(),
+            UtcDateTime::now(),
+            expiration,
+            patch.max_downloads,
+            &config().expiration,
+            config().retention.maximum_age,
+        )
+        .map_err(TapferError::InvalidAssetPatch)?;
+        meta.set_removal_policy(policy);
+        if meta.remaining_downloads() == Some(0) {
+            return Err(TapferError::InvalidAssetPatch(format!(
+                "the asset was already downloaded {} times",
+                meta.downloads()
+            )));
+        }
+    }
+    if let Some(content_type) = patch.content_type {
+        if meta.is_multi_file() {
+            return Err(TapferError::InvalidAssetPatch(
+                "multi-file assets are always downloaded as application/zip".to_owned(),
+            ));
+        }
+        meta.set_content_type(content_type);
+    }
+
+    let old_name = meta.name().to_owned();
+    let mut renamed = patch.name.filter(|n| *n != old_name);
+    // The name of a multi-file asset only names its archive, and deduplicated payloads are stored by hash,
+    // so no file is moved
+    if (meta.is_multi_file() || meta.blob().is_some())
+        && let Some(name) = renamed.take()
+    {
+        meta.set_name(name);
+    }
+    if let Some(name) = &renamed {
+        if storage().exists(&storage::asset_key(id, name)).await? {
+            return Err(TapferError::FileNameTaken(name.clone()));
+        }
+        storage()
+            .rename(
+                &storage::asset_key(id, &old_name),
+                &storage::asset_key(id, name),
+            )
+            .await?;
+        meta.set_name(name.clone());
+    }
+
+    if let Err(e) = meta.write_to_id(id).await {
+        // Keep the payload where the unchanged metadata expects it
+        if let Some(name) = &renamed {
+            storage()
+                .rename(
+                    &storage::asset_key(id, name),
+                    &storage::asset_key(id, &old_name),
+                )
+                .await?;
+        }
+        return Err(e);
+    }
+    info!("Modified {id} as requested");
+
+    websocket::broadcast_event(id, WsEvent::AssetUpdated)?;
+    Ok(Json(AssetSummary::new(&meta)?))
+}
@@ -1,4 +1,4 @@
-use crate::configuration::{EMBED_DESCRIPTION, EMBED_TITLE, FAVICON};
+use crate::configuration::config;
 use askama::Template;
 
 #[derive(Template)]
@@ -12,9 +12,9 @@ pub struct NotFound {
 impl Default for NotFound {
     fn default() -> Self {
         Self {
-            embed_image_url: FAVICON,
-            embed_description: EMBED_DESCRIPTION,
-            embed_title: EMBED_TITLE,
+            embed_image_url: &config().embed.favicon,
+            embed_description: &config().embed.description,
+            embed_title: &config().embed.title,
         }
     }
 }
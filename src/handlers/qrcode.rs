@@ -1,42 +1,43 @@
-use crate::configuration::{QR_CODE_ECC, QR_CODE_SIZE};
+use crate::configuration::config;
 use crate::handlers::get_any_meta;
+use crate::public_url::PublicUrls;
+use crate::structs::asset_password::AssetCredentials;
 use crate::structs::error::TapferResult;
 use crate::structs::tapfer_id::TapferId;
 use axum::body::Body;
 use axum::extract::Path;
 use axum::response::IntoResponse;
-use axum_extra::extract::Host;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use qrcode_generator::QrCodeEcc;
 use std::iter::once;
 
-fn qr_from_id(id: TapferId, host: &str) -> TapferResult<Vec<u8>> {
+fn qr_from_id(id: TapferId, urls: &PublicUrls) -> TapferResult<Vec<u8>> {
     let qrc = qrcode_generator::to_png_to_vec_from_str(
         // Uppercase such that this falls into the Alphanumeric encoding for higher efficiency
         // https://en.wikipedia.org/wiki/QR_code
-        format!("https://{host}/uploads/{id}",).to_ascii_uppercase(),
-        QR_CODE_ECC,
-        QR_CODE_SIZE,
+        urls.asset_page(id).to_ascii_uppercase(),
+        config().qr_code.ecc.into(),
+        config().qr_code.size,
     )?;
     Ok(qrc)
 }
 
-pub fn base64_qr_from_id(id: TapferId, host: &str) -> TapferResult<String> {
-    let data = qr_from_id(id, host)?;
+pub fn base64_qr_from_id(id: TapferId, urls: &PublicUrls) -> TapferResult<String> {
+    let data = qr_from_id(id, urls)?;
     Ok(BASE64_STANDARD.encode(&data))
 }
 
-pub fn random_base64_qr_from_id(host: &str) -> TapferResult<String> {
-    base64_qr_from_id(TapferId::new_random(), host)
+pub fn random_base64_qr_from_id(urls: &PublicUrls) -> TapferResult<String> {
+    base64_qr_from_id(TapferId::new_random(), urls)
 }
 
 #[allow(dead_code)]
-pub fn tiny_qr_from_id(id: TapferId, host: &str) -> TapferResult<String> {
+pub fn tiny_qr_from_id(id: TapferId, urls: &PublicUrls) -> TapferResult<String> {
     let mut qrc = qrcode_generator::to_matrix_from_str(
         // Uppercase such that this falls into the Alphanumeric encoding for higher efficiency
         // https://en.wikipedia.org/wiki/QR_code
-        format!("{host}/uploads/{id}",).to_ascii_uppercase(),
+        urls.asset_page(id).to_ascii_uppercase(),
         QrCodeEcc::Low,
     )?;
     let full = '█';
@@ -77,14 +78,17 @@ pub fn tiny_qr_from_id(id: TapferId, host: &str) -> TapferResult<String> {
     path = "/qrcg/{id}",
     responses(
         (status = 200, description = "Returns QR code"),
+        (status = 401, description = "Asset is password protected"),
         (status = 404, description = "Asset does not exist"),
     ),
 )]
 pub async fn get_qrcode_from_id(
     Path(path): Path<String>,
-    Host(host): Host,
+    urls: PublicUrls,
+    credentials: AssetCredentials,
 ) -> TapferResult<impl IntoResponse> {
-    let ((id, _), _) = get_any_meta(&path).await?;
-    let qrc = qr_from_id(id, &host)?;
+    let ((id, meta), _) = get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    let qrc = qr_from_id(id, &urls)?;
     Ok(Body::from(qrc))
 }
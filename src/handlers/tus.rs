@@ -0,0 +1,564 @@
+//! Resumable uploads following tus 1.0 with the creation, termination and checksum extensions.
+//! See <https://tus.io/protocols/resumable-upload>
+
+use crate::UPLOAD_POOL;
+use crate::blob_store;
+use crate::configuration::config;
+use crate::handlers::checksum;
+use crate::handlers::upload::expiration_field;
+use crate::index;
+use crate::public_url::PublicUrls;
+use crate::storage;
+use crate::storage::storage;
+use crate::structs::asset_password;
+use crate::structs::asset_password::MAX_PASSWORD_LEN;
+use crate::structs::digest::DigestAlgorithm;
+use crate::structs::error::{TapferError, TapferErrorExt, TapferResult};
+use crate::structs::file_meta;
+use crate::structs::file_meta::{FileMeta, FileMetaBuilder};
+use crate::structs::management_token::{MANAGEMENT_TOKEN_HEADER, ManagementToken, authorize_owner};
+use crate::structs::tapfer_id::TapferId;
+use crate::updown::resumable;
+use crate::updown::resumable::{
+    RESUMABLE_UPLOADS, ResumableUpload, TUS_RESUMABLE, TUS_VERSION, UPLOAD_LENGTH, UPLOAD_OFFSET,
+};
+use crate::websocket;
+use crate::websocket::WsEvent;
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{HeaderMap, HeaderName, Method, Request, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use futures_util::StreamExt;
+use sha2::digest::DynDigest;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+const UPLOAD_METADATA: &str = "upload-metadata";
+const UPLOAD_CHECKSUM: &str = "upload-checksum";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Status the checksum extension assigns to mismatching chunks
+fn checksum_mismatch() -> StatusCode {
+    StatusCode::from_u16(460).expect("460 is a valid status code")
+}
+
+fn tus_error(status_code: StatusCode, message: impl Into<String>) -> TapferError {
+    TapferError::TusProtocol {
+        status_code,
+        message: message.into(),
+    }
+}
+
+/// Every request but OPTIONS has to state the protocol version
+fn check_version(headers: &HeaderMap) -> TapferResult<()> {
+    match headers.get(TUS_RESUMABLE).and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(tus_error(
+            StatusCode::PRECONDITION_FAILED,
+            format!("Only tus {TUS_VERSION} is supported"),
+        )),
+    }
+}
+
+fn parse_header<T: FromStr>(headers: &HeaderMap, name: &str) -> TapferResult<Option<T>> {
+    headers
+        .get(name)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, format!("Invalid {name} header")))
+        })
+        .transpose()
+}
+
+/// `Upload-Metadata` is a comma separated list of keys, each followed by an optional base64 value
+fn parse_metadata(headers: &HeaderMap) -> TapferResult<HashMap<String, String>> {
+    let Some(raw) = headers.get(UPLOAD_METADATA) else {
+        return Ok(HashMap::new());
+    };
+    let invalid = || tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Metadata header");
+    let mut metadata = HashMap::new();
+    for pair in raw.to_str().map_err(|_| invalid())?.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let key = parts.next().filter(|k| !k.is_empty()).ok_or_else(invalid)?;
+        let value = match parts.next() {
+            Some(encoded) => BASE64_STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or_else(invalid)?,
+            None => String::new(),
+        };
+        metadata.insert(key.to_owned(), value);
+    }
+    Ok(metadata)
+}
+
+/// `Upload-Checksum: <algorithm> <base64 digest>` of the request body
+struct UploadChecksum {
+    hasher: Box<dyn DynDigest + Send>,
+    expected: Vec<u8>,
+}
+
+fn parse_checksum(headers: &HeaderMap) -> TapferResult<Option<UploadChecksum>> {
+    let Some(raw) = headers.get(UPLOAD_CHECKSUM) else {
+        return Ok(None);
+    };
+    let invalid = || tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Checksum header");
+    let (algorithm, digest) = raw
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().split_once(' '))
+        .ok_or_else(invalid)?;
+    let hasher: Box<dyn DynDigest + Send> = match algorithm {
+        "sha1" => Box::new(sha1::Sha1::default()),
+        "sha256" => Box::new(sha2::Sha256::default()),
+        "sha512" => Box::new(sha2::Sha512::default()),
+        _ => {
+            return Err(tus_error(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported checksum algorithm {algorithm:?}"),
+            ));
+        }
+    };
+    let expected = BASE64_STANDARD
+        .decode(digest.trim())
+        .map_err(|_| invalid())?;
+    Ok(Some(UploadChecksum { hasher, expected }))
+}
+
+fn not_found() -> TapferError {
+    tus_error(StatusCode::NOT_FOUND, "Upload does not exist")
+}
+
+#[utoipa::path(
+    options,
+    path = "/tus",
+    responses(
+        (status = 204, description = "Protocol versions, extensions and limits of the tus endpoint", headers
+            (
+                ("tus-version" = String, description = "Supported protocol versions"),
+                ("tus-extension" = String, description = "Supported extensions"),
+                ("tus-max-size" = u64, description = "Largest accepted upload in bytes"),
+                ("tus-checksum-algorithm" = String, description = "Algorithms accepted in `Upload-Checksum`"),
+            )
+        ),
+    ),
+)]
+pub async fn tus_options() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_RESUMABLE, TUS_VERSION.to_owned()),
+            ("tus-version", TUS_VERSION.to_owned()),
+            ("tus-extension", "creation,termination,checksum".to_owned()),
+            (
+                "tus-max-size",
+                config().limits.max_upload_size.bytes().to_string(),
+            ),
+            ("tus-checksum-algorithm", "sha1,sha256,sha512".to_owned()),
+        ],
+    )
+}
+
+/// The CORS layer answers every OPTIONS request as a preflight,
+/// so protocol discovery by non-browser clients is routed around it
+pub async fn discovery_middleware(request: Request<Body>, next: Next) -> Response {
+    if request.method() == Method::OPTIONS
+        && request.uri().path() == "/tus"
+        && !request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return tus_options().await.into_response();
+    }
+    next.run(request).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/tus",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Upload-Length" = u64, Header, description = "Size of the whole upload in bytes"),
+        ("Upload-Metadata" = Option<String>, Header, description = "Base64 encoded `filename`, `filetype`, `timezone`, `password`, and `expiration` and `max_downloads` in the format of the regular upload parameters"),
+    ),
+    responses(
+        (status = 201, description = "Upload created, the body holds the URL of the asset page", headers
+            (
+                ("location" = String, description = "URL to append to and query the upload at"),
+                ("tapfer-management-token" = String, description = "Secret required to resume, delete or modify the asset, shown only once"),
+            )
+        ),
+        (status = 400, description = "Missing or invalid headers"),
+        (status = 412, description = "Unsupported protocol version"),
+        (status = 413, description = "Upload-Length exceeds the maximum upload size"),
+    ),
+)]
+pub async fn tus_create(urls: PublicUrls, headers: HeaderMap) -> TapferResult<Response> {
+    check_version(&headers)?;
+    let length: u64 = parse_header(&headers, UPLOAD_LENGTH)?.ok_or_else(|| {
+        tus_error(
+            StatusCode::BAD_REQUEST,
+            "Upload-Length is required, deferring it is not supported",
+        )
+    })?;
+    if length > config().limits.max_upload_size.bytes() {
+        return Err(tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Upload-Length exceeds the maximum upload size",
+        ));
+    }
+
+    let id = TapferId::new_random();
+    let mut metadata = parse_metadata(&headers)?;
+    let name = metadata
+        .remove("filename")
+        .unwrap_or_else(|| id.to_string());
+    file_meta::validate_file_name(&name).map_err(|e| tus_error(StatusCode::BAD_REQUEST, e))?;
+    let content_type = metadata
+        .remove("filetype")
+        .filter(|t| mime::Mime::from_str(t).is_ok())
+        .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+    let max_downloads = metadata
+        .get("max_downloads")
+        .map(|n| {
+            n.parse()
+                .map_err(|_| tus_error(StatusCode::BAD_REQUEST, "Invalid max_downloads"))
+        })
+        .transpose()?;
+
+    let token = ManagementToken::new_random();
+    let mut builder = FileMetaBuilder {
+        management_token_hash: Some(token.hash()),
+        timezone: metadata.remove("timezone"),
+        ..Default::default()
+    };
+    expiration_field(
+        metadata.get("expiration").map(String::as_str),
+        max_downloads,
+        &mut builder,
+    )?;
+    if let Some(password) = metadata.remove("password").filter(|p| !p.is_empty()) {
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(TapferError::InvalidPassword);
+        }
+        builder.password_hash = Some(asset_password::hash_password(password).await?);
+    }
+    let meta = builder.build(name, content_type, Some(length));
+
+    index::begin_upload(id, &meta).await?;
+    storage()
+        .writer(&storage::asset_key(id, meta.name()), 0)
+        .await?;
+    let handle = UPLOAD_POOL.handle(id, meta, Some(1));
+    // Nothing is sent until the first PATCH
+    handle.write_fsm().await.stall();
+    let upload = Arc::new(ResumableUpload::new(handle, length));
+    RESUMABLE_UPLOADS.insert(id, upload.clone());
+    info!("Created resumable upload {id} of {length} bytes");
+
+    if length == 0 {
+        complete(id, &upload).await?;
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("{}/tus/{id}", urls.download())),
+            (
+                HeaderName::from_static(MANAGEMENT_TOKEN_HEADER),
+                token.secret().to_owned(),
+            ),
+            (
+                HeaderName::from_static(TUS_RESUMABLE),
+                TUS_VERSION.to_owned(),
+            ),
+        ],
+        format!("{}\n", urls.asset_page(id)),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    head,
+    path = "/tus/{id}",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Authorization" = String, Header, description = "`Bearer <management token>` as returned on creation"),
+    ),
+    responses(
+        (status = 200, description = "Current offset of the upload", headers
+            (
+                ("upload-offset" = u64, description = "Bytes received so far"),
+                ("upload-length" = u64, description = "Size of the whole upload"),
+            )
+        ),
+        (status = 401, description = "Management token missing"),
+        (status = 403, description = "Management token does not match"),
+        (status = 404, description = "Upload does not exist"),
+    ),
+)]
+pub async fn tus_head(Path(path): Path<String>, headers: HeaderMap) -> TapferResult<Response> {
+    check_version(&headers)?;
+    let id = TapferId::from_str(&path).map_err(|_| not_found())?;
+
+    let (offset, length) = match RESUMABLE_UPLOADS.get(&id).map(|u| u.clone()) {
+        Some(upload) => {
+            authorize_owner(
+                &headers,
+                upload.handle().file_meta().management_token_hash(),
+            )?;
+            let offset = upload
+                .handle()
+                .read_fsm()
+                .await
+                .get_progress()
+                .ok_or_else(not_found)?;
+            (offset, upload.length())
+        }
+        // Lets clients that missed the final response learn that the upload is complete
+        None => {
+            let meta = FileMeta::read_from_id(id).await.map_err(|_| not_found())?;
+            authorize_owner(&headers, meta.management_token_hash())?;
+            (meta.size(), meta.size())
+        }
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, offset.to_string()),
+            (UPLOAD_LENGTH, length.to_string()),
+            (header::CACHE_CONTROL.as_str(), "no-store".to_owned()),
+            (TUS_RESUMABLE, TUS_VERSION.to_owned()),
+        ],
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    patch,
+    path = "/tus/{id}",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Authorization" = String, Header, description = "`Bearer <management token>` as returned on creation"),
+        ("Upload-Offset" = u64, Header, description = "Must match the current offset of the upload"),
+        ("Upload-Checksum" = Option<String>, Header, description = "`sha1`, `sha256` or `sha512` followed by the base64 digest of the body. The body is only made available to downloaders once it is verified"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Body appended", headers
+            (
+                ("upload-offset" = u64, description = "New offset of the upload"),
+            )
+        ),
+        (status = 401, description = "Management token missing"),
+        (status = 403, description = "Management token does not match"),
+        (status = 404, description = "Upload does not exist"),
+        (status = 409, description = "Upload-Offset does not match the current offset"),
+        (status = 413, description = "Body exceeds Upload-Length"),
+        (status = 415, description = "Content-Type is not application/offset+octet-stream"),
+        (status = 423, description = "Another request is appending to this upload"),
+        (status = 460, description = "Checksum mismatch, the body was discarded"),
+    ),
+)]
+pub async fn tus_patch(
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> TapferResult<Response> {
+    check_version(&headers)?;
+    let id = TapferId::from_str(&path).map_err(|_| not_found())?;
+    let upload = RESUMABLE_UPLOADS
+        .get(&id)
+        .map(|u| u.clone())
+        .ok_or_else(not_found)?;
+    authorize_owner(
+        &headers,
+        upload.handle().file_meta().management_token_hash(),
+    )?;
+    if headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        != Some(OFFSET_CONTENT_TYPE)
+    {
+        return Err(tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Content-Type must be {OFFSET_CONTENT_TYPE}"),
+        ));
+    }
+    let offset: u64 = parse_header(&headers, UPLOAD_OFFSET)?
+        .ok_or_else(|| tus_error(StatusCode::BAD_REQUEST, "Upload-Offset is required"))?;
+    let checksum = parse_checksum(&headers)?;
+
+    let _writer = upload.try_lock_writer().ok_or_else(|| {
+        tus_error(
+            StatusCode::LOCKED,
+            "Another request is appending to this upload",
+        )
+    })?;
+    upload.touch();
+    let current = upload
+        .handle()
+        .read_fsm()
+        .await
+        .get_progress()
+        .ok_or_else(not_found)?;
+    if offset != current {
+        return Err(tus_error(
+            StatusCode::CONFLICT,
+            format!("Upload-Offset is {offset}, but the upload is at {current}"),
+        ));
+    }
+
+    upload.handle().write_fsm().await.resume();
+    let res = append(id, &upload, offset, body, checksum).await;
+    upload.touch();
+    let progress = upload.handle().read_fsm().await.get_progress();
+    if progress == Some(upload.length()) {
+        complete(id, &upload).await?;
+    } else {
+        upload.handle().write_fsm().await.stall();
+    }
+    res?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [
+            (UPLOAD_OFFSET, progress.unwrap_or(offset).to_string()),
+            (TUS_RESUMABLE, TUS_VERSION.to_owned()),
+        ],
+    )
+        .into_response())
+}
+
+/// Writes the body at `offset`. Without a checksum, downloaders see every chunk as soon as it is on disk
+/// and an interrupted body keeps what arrived. With a checksum, the body only counts once it is verified
+async fn append(
+    id: TapferId,
+    upload: &ResumableUpload,
+    offset: u64,
+    body: Body,
+    mut checksum: Option<UploadChecksum>,
+) -> TapferResult<()> {
+    let handle = upload.handle();
+    let key = storage::asset_key(id, handle.file_meta().name());
+    // Drops leftovers of an interrupted or rejected request beyond the acknowledged offset
+    let mut file = storage().writer(&key, offset).await?;
+
+    let mut written = 0;
+    let mut stream = body.into_data_stream();
+    let mut outcome = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!(
+                    "Upload {id} was interrupted at {} bytes: {e}",
+                    offset + written
+                );
+                outcome = Err(tus_error(StatusCode::BAD_REQUEST, "Body was interrupted"));
+                break;
+            }
+        };
+        if offset + written + chunk.len() as u64 > upload.length() {
+            outcome = Err(tus_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Body exceeds Upload-Length",
+            ));
+            break;
+        }
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+        match &mut checksum {
+            Some(checksum) => checksum.hasher.update(&chunk),
+            None => {
+                file.flush().await?;
+                add_progress(upload, chunk.len()).await?;
+            }
+        }
+    }
+    file.flush().await?;
+
+    if let Some(checksum) = checksum {
+        if outcome.is_ok() && *checksum.hasher.finalize() != *checksum.expected {
+            outcome = Err(tus_error(checksum_mismatch(), "Checksum mismatch"));
+        }
+        if outcome.is_err() {
+            drop(file);
+            storage().writer(&key, offset).await?;
+            return outcome;
+        }
+        add_progress(upload, usize::try_from(written).unwrap_or(usize::MAX)).await?;
+    }
+    outcome
+}
+
+async fn add_progress(upload: &ResumableUpload, n: usize) -> TapferResult<()> {
+    let handle = upload.handle();
+    // Fails when the upload was terminated meanwhile
+    let progress = handle.write_fsm().await.add_progress(n)?;
+    websocket::broadcast_event(
+        handle.id(),
+        WsEvent::UploadProgress {
+            progress,
+            total: upload.length(),
+        },
+    )
+    .log_error("Error broadcasting event");
+    handle.notify_all_downloaders();
+    Ok(())
+}
+
+async fn complete(id: TapferId, upload: &ResumableUpload) -> TapferResult<()> {
+    storage()
+        .commit(&storage::asset_key(id, upload.handle().file_meta().name()))
+        .await?;
+    upload.handle().file_meta().write_to_id(id).await?;
+    upload.handle().write_fsm().await.mark_complete();
+    RESUMABLE_UPLOADS.remove(&id);
+    upload.handle().notify_all_downloaders();
+    info!("Completed resumable upload of {id}");
+    checksum::spawn_checksum(id, DigestAlgorithm::Sha512);
+    blob_store::spawn_intern(id);
+    websocket::broadcast_event(id, WsEvent::UploadComplete)?;
+    Ok(())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/tus/{id}",
+    params(
+        ("Tus-Resumable" = String, Header, description = "Must be `1.0.0`"),
+        ("Authorization" = String, Header, description = "`Bearer <management token>` as returned on creation"),
+    ),
+    responses(
+        (status = 204, description = "Upload terminated and deleted"),
+        (status = 401, description = "Management token missing"),
+        (status = 403, description = "Management token does not match"),
+        (status = 404, description = "Upload does not exist or is already complete"),
+    ),
+)]
+pub async fn tus_delete(Path(path): Path<String>, headers: HeaderMap) -> TapferResult<Response> {
+    check_version(&headers)?;
+    let id = TapferId::from_str(&path).map_err(|_| not_found())?;
+    let upload = RESUMABLE_UPLOADS
+        .get(&id)
+        .map(|u| u.clone())
+        .ok_or_else(not_found)?;
+    authorize_owner(
+        &headers,
+        upload.handle().file_meta().management_token_hash(),
+    )?;
+    info!("Terminating resumable upload {id} as requested");
+    resumable::abort(id).await?;
+    Ok((StatusCode::NO_CONTENT, [(TUS_RESUMABLE, TUS_VERSION)]).into_response())
+}
@@ -0,0 +1,96 @@
+use crate::configuration::config;
+use crate::handlers::get_any_meta;
+use crate::public_url::PublicUrls;
+use crate::structs::asset_password::{check_password, unlock_cookie_name, unlock_token};
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::tapfer_id::TapferId;
+use askama::Template;
+use axum::Form;
+use axum::extract::Path;
+use axum::http::{StatusCode, header};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+
+#[derive(Template)]
+#[template(path = "locked.html")]
+struct LockedTemplate<'a> {
+    unlock_url: String,
+    error: Option<&'a str>,
+    embed_image_url: &'a str,
+    embed_description: &'a str,
+    embed_title: &'a str,
+}
+
+/// Password prompt shown instead of the asset page. Reveals nothing about the asset
+fn locked_page(id: TapferId, error: Option<&str>) -> TapferResult<Html<String>> {
+    let template = LockedTemplate {
+        unlock_url: format!("/uploads/{id}/unlock"),
+        error,
+        embed_image_url: &config().embed.favicon,
+        embed_description: &config().embed.description,
+        embed_title: &config().embed.title,
+    };
+    Ok(Html(template.render()?))
+}
+
+/// Answers a failed password check with the prompt instead of a plain-text error
+pub fn locked_response(id: TapferId, error: TapferError) -> TapferResult<Response> {
+    match error {
+        TapferError::AssetLocked => Ok(locked_page(id, None)?.into_response()),
+        TapferError::WrongPassword => Ok((
+            StatusCode::FORBIDDEN,
+            locked_page(id, Some("Wrong password"))?,
+        )
+            .into_response()),
+        TapferError::PasswordThrottled(remaining) => Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())],
+            locked_page(id, Some("Too many wrong passwords, try again later"))?,
+        )
+            .into_response()),
+        e => Err(e),
+    }
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub struct UnlockForm {
+    password: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/uploads/{id}/unlock",
+    request_body(content = UnlockForm, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 303, description = "Password accepted, sets the unlock cookie and redirects to the asset page"),
+        (status = 403, description = "Wrong password"),
+        (status = 404, description = "Asset does not exist"),
+        (status = 429, description = "Too many wrong passwords for this asset"),
+    ),
+)]
+pub async fn unlock_asset(
+    Path(path): Path<String>,
+    urls: PublicUrls,
+    Form(form): Form<UnlockForm>,
+) -> TapferResult<Response> {
+    let ((id, meta), _) = get_any_meta(&path).await?;
+    if let Err(e) = check_password(id, &meta, &form.password).await {
+        return locked_response(id, e);
+    }
+
+    let secure = if urls.base().starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{secure}",
+        unlock_cookie_name(id),
+        unlock_token(id, &meta),
+        config().passwords.unlock_duration.whole_seconds(),
+    );
+    Ok((
+        [(header::SET_COOKIE, cookie)],
+        Redirect::to(&format!("/uploads/{id}")),
+    )
+        .into_response())
+}
@@ -1,39 +1,68 @@
-use crate::configuration::UPLOAD_BUFSIZE;
+use crate::blob_store;
+use crate::configuration::config;
 use crate::handlers::checksum;
+use crate::index;
+use crate::public_url::PublicUrls;
 use crate::retention_control::delete_asset;
+use crate::storage;
+use crate::storage::storage;
+use crate::structs::asset_password;
+use crate::structs::asset_password::MAX_PASSWORD_LEN;
+use crate::structs::digest;
+use crate::structs::digest::{Checksums, DigestAlgorithm, MultiHasher};
 use crate::structs::error::{TapferError, TapferErrorExt, TapferResult};
-use crate::structs::file_meta::{FileMeta, FileMetaBuilder, RemovalPolicy};
+use crate::structs::expiration;
+use crate::structs::expiration::Expiration;
+use crate::structs::file_meta;
+use crate::structs::file_meta::{FileEntry, FileMeta, FileMetaBuilder, PathSet};
+use crate::structs::management_token::{MANAGEMENT_TOKEN_HEADER, ManagementToken};
 use crate::structs::tapfer_id::TapferId;
 use crate::updown::upload_handle::UploadHandle;
 use crate::updown::upload_pool::UploadFsm;
 use crate::websocket::WsEvent;
 use crate::{PROGRESS_TOKEN_LUT, UPLOAD_POOL, websocket};
+use axum::body::{Body, Bytes, HttpBody};
 use axum::extract::multipart::Field;
-use axum::extract::{Multipart, Path, Query};
-use axum::http::StatusCode;
+use axum::extract::{FromRequest, Multipart, Path, Query, Request};
+use axum::http::{StatusCode, header};
 use axum::response::Html;
 use axum::response::IntoResponse;
-use axum_extra::extract::Host;
-use futures_util::TryStreamExt;
+use futures_util::future::ready;
+use futures_util::{Stream, TryStreamExt};
 use scopeguard::defer;
 use std::io::Error;
 use std::pin::{Pin, pin};
 use std::str::FromStr;
 use std::task::{Context, Poll};
-use time::Duration as TimeDuration;
-use tokio::fs::File;
+use time::UtcDateTime;
 use tokio::io::{AsyncWrite, BufReader, copy_buf};
-use tokio::{fs, task};
+use tokio::task;
 use tokio_util::io::StreamReader;
 use tracing::{error, info, warn};
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct UploadParameters {
     file_size: Option<u64>,
+    file_count: Option<usize>,
     progress_token: Option<String>,
     expiration: Option<String>,
+    max_downloads: Option<u32>,
     timezone: Option<String>,
     deposit: Option<u64>,
+    filename: Option<String>,
+    sha256: Option<String>,
+}
+
+/// The payload of an upload, either a browser form or the plain request body
+enum UploadBody {
+    Multipart(Multipart),
+    Raw {
+        file_name: Option<String>,
+        content_type: String,
+        /// RFC 9530 `Content-Digest` of the body, in the algorithms that are verified
+        content_digest: Checksums,
+        body: Body,
+    },
 }
 
 #[utoipa::path(
@@ -41,52 +70,141 @@ pub struct UploadParameters {
     path = "/",
     params(
         ("source" = Option<String>, description = "`frontend` when using frontend, unset otherwise"),
-        ("file_size" = Option<u64>, description = "optional file size of asset"),
+        ("file_size" = Option<u64>, description = "optional file size of asset, the upload is discarded when it differs"),
+        ("file_count" = Option<u32>, description = "optional number of files in the form, the upload is discarded when it differs. Without it, a download that starts while the first file is uploading only receives that file"),
         ("progress_token" = Option<u32>, description = "random ID to associate upload with frontend"),
         ("timezone" = Option<String>, description = "client timezone in IANA string format, UTC otherwise"),
-        ("expiration" = Option<String>, description = "Expiration either as `single_download` or `24_hours`"),
-        ("deposit" = Option<u64>, description = "Deposit ID to notify uploader about")
+        ("expiration" = Option<String>, description = "`single_download` (default), a duration such as `30m`, `7d`, `1d12h` or ISO 8601 `PT6H`, or an RFC 3339 timestamp such as `2025-01-31T18:00:00Z`. Lifetimes are clamped to the limits configured by the operator"),
+        ("max_downloads" = Option<u32>, description = "Remove the asset after this many completed downloads. Combined with a duration or timestamp `expiration`, whichever comes first"),
+        ("deposit" = Option<u64>, description = "Deposit ID to notify uploader about"),
+        ("filename" = Option<String>, description = "Name of the asset when the body is not a multipart form, its ID otherwise"),
+        ("sha256" = Option<String>, description = "Hex SHA-256 of the file, the upload is discarded when it differs. When hash-first uploads are enabled and a raw body's content is stored already, the asset is created without reading the body"),
+        ("Content-Digest" = Option<String>, Header, description = "RFC 9530 `sha-256` or `sha-512` digest of a raw body, verified like `sha256`"),
     ),
+    request_body(description = "A `multipart/form-data` form with an optional `password` and the `file` field, or the raw file contents"),
     responses(
-        (status = 200, description = "URL to asset page"),
+        (status = 200, description = "URL to asset page", headers
+            (
+                ("tapfer-management-token" = String, description = "Secret required to delete or modify the asset, shown only once"),
+            )
+        ),
+        (status = 400, description = "Invalid parameters or file name, or the body does not match its declared size or digest"),
+        (status = 413, description = "The form contains more than `limits.max_files` files"),
     ),
 )]
 #[axum::debug_handler]
 pub async fn accept_form(
-    Host(mut host): Host,
+    urls: PublicUrls,
+    Query(params): Query<UploadParameters>,
+    request: Request,
+) -> TapferResult<impl IntoResponse> {
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+    let body = if is_form {
+        UploadBody::Multipart(Multipart::from_request(request, &()).await?)
+    } else {
+        let file_name = params.filename.clone();
+        raw_body(file_name, request)
+    };
+    accept_upload(urls, params, body).await
+}
+
+#[utoipa::path(
+    put,
+    path = "/{filename}",
+    params(
+        ("filename" = String, Path, description = "Name of the asset"),
+        ("timezone" = Option<String>, Query, description = "client timezone in IANA string format, UTC otherwise"),
+        ("expiration" = Option<String>, Query, description = "Same as for `POST /`"),
+        ("max_downloads" = Option<u32>, Query, description = "Same as for `POST /`"),
+        ("deposit" = Option<u64>, Query, description = "Deposit ID to notify uploader about"),
+        ("sha256" = Option<String>, Query, description = "Same as for `POST /`. Send `Expect: 100-continue` to skip the upload of known content"),
+        ("Content-Digest" = Option<String>, Header, description = "Same as for `POST /`"),
+    ),
+    request_body(description = "The raw file contents. Without `Content-Length` the size is tracked while receiving"),
+    responses(
+        (status = 200, description = "URL to asset page", headers
+            (
+                ("tapfer-management-token" = String, description = "Secret required to delete or modify the asset, shown only once"),
+            )
+        ),
+        (status = 400, description = "Invalid parameters or file name, or the body does not match its declared size or digest"),
+    ),
+)]
+pub async fn accept_raw(
+    urls: PublicUrls,
+    Path(file_name): Path<String>,
     Query(params): Query<UploadParameters>,
-    multipart: Multipart,
+    request: Request,
+) -> TapferResult<impl IntoResponse> {
+    accept_upload(urls, params, raw_body(Some(file_name), request)).await
+}
+
+/// `curl -T` sends no content type and `curl --data-binary` a form one, neither describes the file
+fn raw_body(file_name: Option<String>, request: Request) -> UploadBody {
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| *v != mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
+        .filter(|v| mime::Mime::from_str(v).is_ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
+        .to_owned();
+    let content_digest = request
+        .headers()
+        .get(digest::CONTENT_DIGEST)
+        .and_then(|v| v.to_str().ok())
+        .map(digest::parse_field)
+        .unwrap_or_default();
+    UploadBody::Raw {
+        file_name,
+        content_type,
+        content_digest,
+        body: request.into_body(),
+    }
+}
+
+async fn accept_upload(
+    urls: PublicUrls,
+    params: UploadParameters,
+    body: UploadBody,
 ) -> TapferResult<impl IntoResponse> {
     let id = TapferId::new_random();
-    fs::create_dir(&format!("data/{id}")).await?;
+    let token = ManagementToken::new_random();
 
     info!("Beginning upload of {id}");
-    let res = do_upload(multipart, id, &params).await;
+    let res = do_upload(body, id, &params, &token).await;
     if res.is_err() {
-        delete_asset(id).await?;
+        // The upload error is what the client needs to see
+        delete_asset(id)
+            .await
+            .log_error(&format!("Failed to remove the failed upload {id}:"));
     }
     res?;
     info!("Completed upload of {id}");
-    checksum::spawn_sha512_checksum(id);
+    blob_store::spawn_intern(id);
 
-    let method = if host.contains("localhost") {
-        host = String::new();
-        ""
-    } else {
-        host = host.replace("cdn.", "");
-        "https://"
-    };
-    Ok((StatusCode::OK, format!("{method}{host}/uploads/{id}\n")))
+    Ok((
+        StatusCode::OK,
+        [(MANAGEMENT_TOKEN_HEADER, token.secret().to_owned())],
+        format!("{}\n", urls.asset_page(id)),
+    ))
 }
 
 async fn do_upload(
-    mut multipart: Multipart,
+    body: UploadBody,
     id: TapferId,
     params: &UploadParameters,
+    token: &ManagementToken,
 ) -> TapferResult<()> {
-    let mut meta = FileMetaBuilder::default();
+    let mut meta = FileMetaBuilder {
+        management_token_hash: Some(token.hash()),
+        ..Default::default()
+    };
 
-    let size: Option<u64> = params.file_size;
     let in_progress_token: Option<u32> = params
         .progress_token
         .as_ref()
@@ -99,13 +217,11 @@ async fn do_upload(
         error!("Missing tapfer-timezone parameter");
     }
 
-    if size.is_some() != in_progress_token.is_some() {
-        warn!(
-            "Size is {size:?} and progress token is {in_progress_token:?}. The frontend might not be sending both?"
-        );
-    }
-
-    expiration_field(params.expiration.as_deref(), &mut meta)?;
+    expiration_field(
+        params.expiration.as_deref(),
+        params.max_downloads,
+        &mut meta,
+    )?;
 
     if let Some(tok) = in_progress_token {
         info!("Adding progress token {tok}");
@@ -123,74 +239,287 @@ async fn do_upload(
         websocket::broadcast_event(deposit, WsEvent::DepositReady { id })?;
     }
 
+    let hash = params
+        .sha256
+        .as_deref()
+        .map(blob_store::parse_hash)
+        .transpose()?;
+    let mut expected: Checksums = hash
+        .iter()
+        .map(|hash| (DigestAlgorithm::Sha256, hash.clone()))
+        .collect();
+
+    match body {
+        UploadBody::Multipart(multipart) => {
+            let declared = (params.file_size, params.file_count);
+            multipart_fields(multipart, id, meta, declared, &expected, in_progress_token).await
+        }
+        UploadBody::Raw {
+            file_name,
+            content_type,
+            content_digest,
+            body,
+        } => {
+            // Chunked bodies have no exact size hint, their size is tracked while writing
+            let size = body.size_hint().exact();
+            let file_name = file_name.unwrap_or_else(|| id.to_string());
+            file_meta::validate_file_name(&file_name).map_err(TapferError::InvalidFileName)?;
+            if let Some(hash) = hash.filter(|_| config().storage.hash_first_uploads)
+                && let Some(size) = blob_store::adopt(&hash, size).await?
+            {
+                // The content is stored already, the body is never read
+                let mut meta = meta.build(file_name, content_type, Some(size));
+                meta.set_blob(hash.clone());
+                if let Err(e) = meta.write_to_id(id).await {
+                    blob_store::release(&hash).await?;
+                    return Err(e);
+                }
+                checksum::write_checksum(id, DigestAlgorithm::Sha256, &hash).await?;
+                checksum::spawn_checksum(id, DigestAlgorithm::Sha512);
+                return Ok(());
+            }
+            expected.extend(content_digest);
+            let meta = meta.build(file_name.clone(), content_type.clone(), size);
+            index::begin_upload(id, &meta).await?;
+            let upload = UploadGuard(UPLOAD_POOL.handle(id, meta.clone(), Some(1)));
+            let stream = body.into_data_stream().map_err(TapferError::AxumBody);
+            let entry = write_entry(stream, id, &upload.0, 0, file_name, content_type).await?;
+            complete_upload(id, &upload.0, meta, vec![entry], &expected).await
+        }
+    }
+}
+
+async fn multipart_fields(
+    mut multipart: Multipart,
+    id: TapferId,
+    mut meta: FileMetaBuilder,
+    (size, file_count): (Option<u64>, Option<usize>),
+    expected: &Checksums,
+    in_progress_token: Option<u32>,
+) -> TapferResult<()> {
+    if file_count.is_some_and(|n| n > config().limits.max_files) {
+        return Err(TapferError::TooManyFiles(config().limits.max_files));
+    }
+    if size.is_some() != in_progress_token.is_some() {
+        warn!(
+            "Size is {size:?} and progress token is {in_progress_token:?}. The frontend might not be sending both?"
+        );
+    }
+
+    let mut upload: Option<(UploadGuard, FileMeta)> = None;
+    let mut entries: Vec<(FileEntry, Checksums)> = vec![];
+    let mut paths = PathSet::default();
     while let Some(field) = multipart.next_field().await? {
         let name = field
             .name()
             .ok_or(TapferError::MultipartFieldNameMissing)?
             .to_string();
-        if name.as_str() == "file" {
-            payload_field(field, id, meta.clone(), size).await?;
-        } else {
-            error!("Got unexpected form field {name}");
-            Err(TapferError::UnknownMultipartField {
-                field_name: name.clone(),
-            })?;
+        match name.as_str() {
+            "file" => {
+                let path = field
+                    .file_name()
+                    .map_or_else(|| id.to_string(), ToOwned::to_owned);
+                let content_type = field
+                    .content_type()
+                    .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
+                    .to_string();
+                file_meta::validate_entry_path(&path).map_err(TapferError::InvalidFileName)?;
+                if entries.len() >= config().limits.max_files {
+                    return Err(TapferError::TooManyFiles(config().limits.max_files));
+                }
+                if let Some(n) = file_count
+                    && entries.len() >= n
+                {
+                    return Err(TapferError::UploadSizeMismatch(format!(
+                        "contains more than the declared {n} files"
+                    )));
+                }
+                if !paths.insert(&path) {
+                    return Err(TapferError::InvalidFileName(format!(
+                        "{path:?} appears twice or clashes with a directory"
+                    )));
+                }
+
+                let start = entries.iter().map(|(e, _)| e.size).sum();
+                let handle = match &upload {
+                    Some((guard, _)) => {
+                        guard
+                            .0
+                            .begin_entry(path.clone(), content_type.clone(), start);
+                        guard.0.clone()
+                    }
+                    None => {
+                        // The first file registers the upload, so downloads may begin right away
+                        let meta = meta.clone().build(path.clone(), content_type.clone(), size);
+                        index::begin_upload(id, &meta).await?;
+                        let handle = UPLOAD_POOL.handle(id, meta.clone(), file_count);
+                        upload = Some((UploadGuard(handle.clone()), meta));
+                        handle
+                    }
+                };
+                let stream = field.map_err(TapferError::AxumMultipart);
+                let entry = write_entry(stream, id, &handle, start, path, content_type).await?;
+                entries.push(entry);
+            }
+            // The metadata is fixed once the payload streams, so the password has to come first
+            "password" if upload.is_some() => Err(TapferError::BadMultipartOrder)?,
+            "password" => password_field(field, &mut meta).await?,
+            _ => {
+                error!("Got unexpected form field {name}");
+                Err(TapferError::UnknownMultipartField {
+                    field_name: name.clone(),
+                })?;
+            }
+        }
+    }
+
+    if let Some(n) = file_count
+        && entries.len() != n
+    {
+        return Err(TapferError::UploadSizeMismatch(format!(
+            "contains {} files instead of the declared {n}",
+            entries.len()
+        )));
+    }
+    if let Some((guard, meta)) = upload {
+        complete_upload(id, &guard.0, meta, entries, expected).await?;
+    }
+    Ok(())
+}
+
+async fn password_field(mut field: Field<'_>, meta: &mut FileMetaBuilder) -> TapferResult<()> {
+    let mut password = vec![];
+    while let Some(chunk) = field.chunk().await? {
+        password.extend_from_slice(&chunk);
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(TapferError::InvalidPassword);
         }
     }
+    let password = String::from_utf8(password).map_err(|_| TapferError::InvalidPassword)?;
+    // Browsers submit empty inputs as well
+    if !password.is_empty() {
+        meta.password_hash = Some(asset_password::hash_password(password).await?);
+    }
     Ok(())
 }
 
-async fn payload_field(
-    field: Field<'_>,
+/// Streams one file of the asset to disk, making it available to downloaders as it arrives.
+/// Returns the file alongside its streamed checksums.
+/// `start` is where the file begins within the asset, which must not grow beyond its declared size
+async fn write_entry(
+    stream: impl Stream<Item = TapferResult<Bytes>> + Unpin,
     id: TapferId,
-    metadata_builder: FileMetaBuilder,
-    size: Option<u64>,
-) -> TapferResult<()> {
-    let file_name = field
-        .file_name()
-        .map_or_else(|| id.to_string(), ToOwned::to_owned);
-    let content_type = field
-        .content_type()
-        .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
-        .to_string();
-
-    let metadata = metadata_builder.build(file_name.clone(), content_type.clone(), size);
-    // Only permit updown stream when the files final size was transmitted by the client
-    let handle = UPLOAD_POOL.handle(id, metadata.clone());
-    let f = File::create(format!("data/{id}/{file_name}")).await?;
-    let mut f = UpdownWriter::new(f, handle.clone(), metadata, size.is_none());
+    handle: &UploadHandle,
+    start: u64,
+    path: String,
+    mimetype: String,
+) -> TapferResult<(FileEntry, Checksums)> {
+    let key = storage::asset_key(id, &path);
+    let f = storage().writer(&key, 0).await?;
+    let mut f = UpdownWriter::new(f, handle.clone());
+    let mut crc = crc32fast::Hasher::new();
+    let declared = handle.file_meta().known_size();
+    let mut received = start;
+    let stream = stream.and_then(|chunk| {
+        received += chunk.len() as u64;
+        // Downloaders already rely on the declared size, so the excess is refused right away
+        ready(match declared {
+            Some(declared) if received > declared => Err(too_large(declared)),
+            _ => Ok(chunk),
+        })
+    });
     let mut s = BufReader::with_capacity(
-        UPLOAD_BUFSIZE,
-        StreamReader::new(field.map_err(TapferError::AxumMultipart)),
+        config().limits.upload_bufsize.as_usize(),
+        StreamReader::new(stream.inspect_ok(|chunk| crc.update(chunk))),
     );
-    copy_buf(&mut s, &mut f).await?;
-    let metadata = f.metadata();
-    fs::write(
-        format!("data/{id}/meta.toml"),
-        toml::to_string_pretty(&metadata)?.as_bytes(),
-    )
-    .await?;
+    let copied = copy_buf(&mut s, &mut f).await;
+    drop(s);
+    // The error is flattened into an I/O error on its way through the reader
+    if let Some(declared) = declared
+        && received > declared
+    {
+        return Err(too_large(declared));
+    }
+    let size = copied?;
+    storage().commit(&key).await?;
+    let entry = FileEntry {
+        path,
+        size,
+        mimetype,
+        crc32: crc.finalize(),
+    };
+    Ok((entry, f.checksums()))
+}
+
+fn too_large(declared: u64) -> TapferError {
+    TapferError::UploadSizeMismatch(format!("received more than the declared {declared} bytes"))
+}
+
+/// Persists the metadata once every file is on disk. A single file without directories stays a plain asset
+async fn complete_upload(
+    id: TapferId,
+    handle: &UploadHandle,
+    mut meta: FileMeta,
+    entries: Vec<(FileEntry, Checksums)>,
+    expected: &Checksums,
+) -> TapferResult<()> {
+    let (entries, checksums): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+    let total = entries.iter().map(|e| e.size).sum();
+    match meta.known_size() {
+        None => meta.add_size(total)?,
+        Some(declared) if declared != total => {
+            return Err(TapferError::UploadSizeMismatch(format!(
+                "declared {declared} bytes but received {total}"
+            )));
+        }
+        Some(_) => {}
+    }
+    // The checksums of a single file are known from streaming it, the archive of several is hashed afterwards
+    let mut sha512 = None;
+    if entries.len() > 1 || entries.iter().any(|e| e.path.contains('/')) {
+        if !expected.is_empty() {
+            return Err(TapferError::InvalidDigest(
+                "a checksum can only be verified for the upload of a single file".to_owned(),
+            ));
+        }
+        meta.set_entries(entries);
+    } else if let (Some(entry), Some(checksums)) = (entries.first(), checksums.into_iter().next()) {
+        digest::verify(expected, &checksums)?;
+        meta.set_crc32(entry.crc32);
+        for (algorithm, chksum) in checksums {
+            checksum::write_checksum(id, algorithm, &chksum).await?;
+            if algorithm == DigestAlgorithm::Sha512 {
+                sha512 = Some(chksum);
+            }
+        }
+    }
+    meta.write_to_id(id).await?;
     // The upload is complete, mark the upload as complete
     handle.write_fsm().await.mark_complete();
     websocket::broadcast_event(id, WsEvent::UploadComplete)?;
+    match sha512 {
+        Some(chksum) => checksum::announce_sha512(id, chksum),
+        None => checksum::spawn_checksum(id, DigestAlgorithm::Sha512),
+    }
     Ok(())
 }
 
-fn expiration_field(field: Option<&str>, meta: &mut FileMetaBuilder) -> TapferResult<()> {
-    let Some(f) = field else {
-        return Ok(());
-    };
-    match f {
-        "single_download" => meta.expiration = Some(RemovalPolicy::SingleDownload),
-        "24_hours" => {
-            meta.expiration = Some(RemovalPolicy::Expiry {
-                after: TimeDuration::hours(24),
-            });
-        }
-        _ => {
-            Err(TapferError::InvalidExpiration(f.to_owned()))?;
-        }
-    }
+pub(crate) fn expiration_field(
+    field: Option<&str>,
+    max_downloads: Option<u32>,
+    meta: &mut FileMetaBuilder,
+) -> TapferResult<()> {
+    let expiration = field
+        .map(|f| Expiration::from_str(f).map_err(|_| TapferError::InvalidExpiration(f.to_owned())))
+        .transpose()?;
+    let policy = expiration::removal_policy(
+        expiration,
+        max_downloads,
+        UtcDateTime::now(),
+        &config().expiration,
+    )
+    .map_err(TapferError::InvalidRemovalPolicy)?;
+    meta.expiration = Some(policy);
     Ok(())
 }
 
@@ -214,27 +543,22 @@ pub async fn progress_token_to_id(Path(path): Path<String>) -> TapferResult<impl
 pub struct UpdownWriter<S> {
     file: S,
     upload_handle: UploadHandle,
-    metadata: FileMeta,
-    write_to_meta: bool,
+    /// Fed with every written byte, so the file needn't be read again for its checksums
+    hasher: MultiHasher,
 }
 
 impl<S> UpdownWriter<S> {
-    pub fn new(
-        file: S,
-        upload_handle: UploadHandle,
-        metadata: FileMeta,
-        write_to_meta: bool,
-    ) -> Self {
+    pub fn new(file: S, upload_handle: UploadHandle) -> Self {
         Self {
             file,
             upload_handle,
-            metadata,
-            write_to_meta,
+            hasher: MultiHasher::new(&DigestAlgorithm::STREAMED),
         }
     }
 
-    pub fn metadata(&self) -> &FileMeta {
-        &self.metadata
+    /// Checksums of everything written
+    pub fn checksums(self) -> Checksums {
+        self.hasher.finalize()
     }
 }
 
@@ -257,11 +581,7 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for UpdownWriter<S> {
         #[cfg(feature = "dev-slow-upload")]
         std::thread::sleep(std::time::Duration::from_millis(100));
         if let Poll::Ready(Ok(n)) = pollres {
-            if self.write_to_meta {
-                self.metadata
-                    .add_size(n as u64)
-                    .expect("since write_to_meta is set this should not panic");
-            }
+            self.hasher.update(&buf[..n]);
             let handle = self.upload_handle.clone();
             task::spawn(async move {
                 match handle.write_fsm().await.add_progress(n) {
@@ -297,9 +617,12 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for UpdownWriter<S> {
     }
 }
 
-impl<S> Drop for UpdownWriter<S> {
+/// Fails the upload unless it completed, also when the request is dropped midway
+struct UploadGuard(UploadHandle);
+
+impl Drop for UploadGuard {
     fn drop(&mut self) {
-        let mut fsm = self.upload_handle.write_fsm_blocking();
+        let mut fsm = self.0.write_fsm_blocking();
         if !fsm.is_complete() {
             *fsm = UploadFsm::Failed;
         }
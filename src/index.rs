@@ -0,0 +1,439 @@
+use crate::configuration::{StorageBackend, config};
+use crate::handlers::checksum;
+use crate::storage::storage;
+use crate::structs::digest::DigestAlgorithm;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::FileMeta;
+use crate::structs::tapfer_id::TapferId;
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use time::UtcDateTime;
+use time::format_description::well_known::Rfc3339;
+use tokio::task;
+use tracing::{info, warn};
+
+/// Searchable copy of the metadata of every asset, so that neither page views nor retention parse `meta.toml`.
+/// The files stay authoritative, the index is rebuilt from them when it is missing.
+/// SQLite blocks on disk, so the connection is only used through [`with_index`]
+static INDEX: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+/// Bumped whenever the schema changes, which rebuilds the index
+const SCHEMA_VERSION: i32 = 2;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS assets (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        policy TEXT NOT NULL,
+        download_limit INTEGER,
+        downloads INTEGER NOT NULL,
+        created INTEGER NOT NULL,
+        expiry INTEGER,
+        state TEXT NOT NULL,
+        checksum TEXT,
+        blob TEXT,
+        pinned INTEGER NOT NULL,
+        meta TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS assets_created ON assets (created);
+    CREATE INDEX IF NOT EXISTS assets_expiry ON assets (expiry);
+";
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetState {
+    /// Receiving its payload, the metadata is not stored yet
+    Uploading,
+    Complete,
+}
+
+impl AssetState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Uploading => "uploading",
+            Self::Complete => "complete",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "uploading" => Self::Uploading,
+            _ => Self::Complete,
+        }
+    }
+}
+
+/// Criteria of [`list`], unset ones match every asset
+#[derive(Debug, Default)]
+pub struct AssetFilter {
+    pub state: Option<AssetState>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub created_before: Option<UtcDateTime>,
+    pub created_after: Option<UtcDateTime>,
+    pub pinned: Option<bool>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// An asset as recorded in the index
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct IndexedAsset {
+    id: String,
+    name: String,
+    size: u64,
+    state: AssetState,
+    /// `single_download`, `expiry`, `max_downloads` or `max_downloads_or_expiry`
+    removal_policy: String,
+    download_limit: Option<u32>,
+    downloads: u32,
+    /// RFC 3339
+    created: String,
+    /// RFC 3339, absent when the asset only expires by downloads
+    expires: Option<String>,
+    /// Hex encoded SHA-256, once it was computed
+    sha256: Option<String>,
+    /// Whether the payload lives in the blob store, shared with identical assets
+    deduplicated: bool,
+    pinned: bool,
+}
+
+/// What the stored assets occupy, according to the index
+#[derive(Debug, Default, serde::Serialize, utoipa::ToSchema)]
+pub struct AssetUsage {
+    /// Complete assets
+    pub assets: u64,
+    pub pinned: u64,
+    /// Sum of the sizes of complete assets
+    pub logical_bytes: u64,
+    /// Bytes complete assets occupy, counting every deduplicated blob once
+    pub stored_bytes: u64,
+    pub blobs: u64,
+}
+
+/// The configured index file, in memory for the memory backend
+fn path() -> Option<PathBuf> {
+    let storage = &config().storage;
+    match (&storage.index, storage.backend) {
+        (Some(path), _) => Some(path.clone()),
+        (None, StorageBackend::Memory) => None,
+        (None, _) => Some(storage.root.join("index.sqlite")),
+    }
+}
+
+/// Runs `f` on the index on a blocking thread. The lock is held only for as long as `f` runs
+async fn with_index<T: Send + 'static>(
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> TapferResult<T> {
+    task::spawn_blocking(move || {
+        let index = INDEX
+            .get()
+            .expect("the index is opened before anything reads it")
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        f(&index)
+    })
+    .await
+    .map_err(|e| TapferError::StdIo(e.into()))?
+    .map_err(Into::into)
+}
+
+fn open(path: Option<PathBuf>) -> rusqlite::Result<Connection> {
+    let connection = match path {
+        Some(path) => Connection::open(path)?,
+        None => Connection::open_in_memory()?,
+    };
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    let version: i32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version != SCHEMA_VERSION {
+        connection.execute_batch("DROP TABLE IF EXISTS assets")?;
+        connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Opens the index and brings it in line with the stored assets.
+/// Uploads interrupted by the last shutdown are removed, as they cannot be resumed
+pub async fn init() -> TapferResult<()> {
+    let path = path();
+    if let Some(parent) = path.as_deref().and_then(Path::parent) {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let connection = task::spawn_blocking(move || open(path))
+        .await
+        .map_err(|e| TapferError::StdIo(e.into()))??;
+    if INDEX.set(Mutex::new(connection)).is_err() {
+        panic!("index was initialized twice");
+    }
+
+    let mut indexed: HashMap<TapferId, bool> = with_index(|index| {
+        let mut query = index.prepare("SELECT id, state FROM assets")?;
+        let indexed = query
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| {
+                let (id, state) = row.ok()?;
+                Some((TapferId::from_str(&id).ok()?, state == "complete"))
+            })
+            .collect();
+        Ok(indexed)
+    })
+    .await?;
+    let (mut added, mut skipped) = (0, 0);
+    for id in storage().list_assets().await? {
+        match indexed.remove(&id) {
+            Some(true) => continue,
+            Some(false) => {
+                warn!("Removing {id} as it was interrupted while uploading");
+                storage().remove_asset(id).await?;
+                with_index(move |index| remove(index, id)).await?;
+                continue;
+            }
+            None => {}
+        }
+        match FileMeta::read_stored(id).await {
+            Ok(meta) => {
+                with_index(move |index| upsert(index, id, &meta, AssetState::Complete)).await?;
+                if let Some(sha256) = checksum::read_checksum(id, DigestAlgorithm::Sha256).await {
+                    set_checksum(id, &sha256).await?;
+                }
+                added += 1;
+            }
+            // Uploads only write their metadata once they complete
+            Err(TapferError::StdIo(e)) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("Removing {id} as it was interrupted while uploading");
+                storage().remove_asset(id).await?;
+            }
+            // Such as a storage failure or metadata of another version, the asset is kept for the next start
+            Err(e) => {
+                warn!("Skipping {id} as its metadata cannot be read: {e}");
+                skipped += 1;
+            }
+        }
+    }
+    // Removed while the server was down
+    for id in indexed.into_keys() {
+        with_index(move |index| remove(index, id)).await?;
+    }
+    if added > 0 {
+        info!("Indexed {added} assets");
+    }
+    if skipped > 0 {
+        warn!("{skipped} assets are not served until their metadata can be read");
+    }
+    Ok(())
+}
+
+/// Applies `persist` to storage, then `change` to the index in a transaction of its own.
+/// The index is left alone when storage fails, so it never records what was not persisted
+pub async fn transaction(
+    persist: impl Future<Output = io::Result<()>>,
+    change: impl FnOnce(&Connection) -> rusqlite::Result<()> + Send + 'static,
+) -> TapferResult<()> {
+    persist.await?;
+    with_index(|index| {
+        let transaction = index.unchecked_transaction()?;
+        change(&transaction)?;
+        transaction.commit()
+    })
+    .await
+}
+
+/// Records the metadata of an asset. The checksum is left to [`set_checksum`]
+pub fn upsert(
+    index: &Connection,
+    id: TapferId,
+    meta: &FileMeta,
+    state: AssetState,
+) -> rusqlite::Result<()> {
+    let policy = meta.removal_policy();
+    let toml =
+        toml::to_string(meta).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+    index.execute(
+        "INSERT INTO assets (id, name, size, policy, download_limit, downloads, created, expiry, state, checksum, blob, pinned, meta)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, NULL, ?10, ?11, ?12)
+        ON CONFLICT (id) DO UPDATE SET
+            name = excluded.name, size = excluded.size, policy = excluded.policy,
+            download_limit = excluded.download_limit, downloads = excluded.downloads,
+            created = excluded.created, expiry = excluded.expiry, state = excluded.state,
+            blob = excluded.blob, pinned = excluded.pinned, meta = excluded.meta",
+        params![
+            id.to_string(),
+            meta.name(),
+            meta.size(),
+            policy.kind(),
+            policy.download_limit(),
+            meta.downloads(),
+            meta.created().unix_timestamp(),
+            meta.expires_on_utc().map(UtcDateTime::unix_timestamp),
+            state.as_str(),
+            meta.blob(),
+            meta.pinned(),
+            toml,
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn remove(index: &Connection, id: TapferId) -> rusqlite::Result<()> {
+    index.execute("DELETE FROM assets WHERE id = ?1", [id.to_string()])?;
+    Ok(())
+}
+
+/// Lists an upload that has begun, so it is cleaned up should the server stop before it completes
+pub async fn begin_upload(id: TapferId, meta: &FileMeta) -> TapferResult<()> {
+    let meta = meta.clone();
+    with_index(move |index| upsert(index, id, &meta, AssetState::Uploading)).await
+}
+
+/// Metadata of a complete asset
+pub async fn get(id: TapferId) -> TapferResult<Option<FileMeta>> {
+    let meta: Option<String> = with_index(move |index| {
+        index
+            .query_row(
+                "SELECT meta FROM assets WHERE id = ?1 AND state = 'complete'",
+                [id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+    })
+    .await?;
+    Ok(meta.map(|meta| toml::from_str(&meta)).transpose()?)
+}
+
+/// Records the SHA-256 of an asset once it is known
+pub async fn set_checksum(id: TapferId, sha256: &str) -> TapferResult<()> {
+    let sha256 = sha256.to_owned();
+    with_index(move |index| {
+        index.execute(
+            "UPDATE assets SET checksum = ?2 WHERE id = ?1",
+            params![id.to_string(), sha256],
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+/// Complete assets created before `created_before` or expiring before `now`, unless they are pinned
+pub async fn expired(
+    created_before: UtcDateTime,
+    now: UtcDateTime,
+) -> TapferResult<Vec<(TapferId, FileMeta)>> {
+    let rows: Vec<(String, String)> = with_index(move |index| {
+        let mut query = index.prepare(
+            "SELECT id, meta FROM assets
+            WHERE state = 'complete' AND NOT pinned AND (created < ?1 OR expiry < ?2)",
+        )?;
+        let rows = query.query_map(
+            [created_before.unix_timestamp(), now.unix_timestamp()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        rows.collect()
+    })
+    .await?;
+    let mut assets = vec![];
+    for (id, meta) in rows {
+        assets.push((TapferId::from_str(&id)?, toml::from_str(&meta)?));
+    }
+    Ok(assets)
+}
+
+/// Assets matching `filter`, newest first
+pub async fn list(filter: AssetFilter) -> TapferResult<Vec<IndexedAsset>> {
+    with_index(move |index| {
+        let mut query = index.prepare(
+            "SELECT id, name, size, state, policy, download_limit, downloads, created, expiry, checksum, blob, pinned
+            FROM assets
+            WHERE (?1 IS NULL OR state = ?1) AND (?2 IS NULL OR size >= ?2) AND (?3 IS NULL OR size <= ?3)
+                AND (?4 IS NULL OR created < ?4) AND (?5 IS NULL OR created > ?5) AND (?6 IS NULL OR pinned = ?6)
+            ORDER BY created DESC, id
+            LIMIT ?7 OFFSET ?8",
+        )?;
+        let rows = query.query_map(
+            params![
+                filter.state.map(AssetState::as_str),
+                filter.min_size,
+                filter.max_size,
+                filter.created_before.map(UtcDateTime::unix_timestamp),
+                filter.created_after.map(UtcDateTime::unix_timestamp),
+                filter.pinned,
+                filter.limit,
+                filter.offset,
+            ],
+            |row| {
+                Ok(IndexedAsset {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    size: row.get(2)?,
+                    state: AssetState::parse(&row.get::<_, String>(3)?),
+                    removal_policy: row.get(4)?,
+                    download_limit: row.get(5)?,
+                    downloads: row.get(6)?,
+                    created: rfc3339(7, row.get(7)?)?,
+                    expires: row
+                        .get::<_, Option<i64>>(8)?
+                        .map(|expiry| rfc3339(8, expiry))
+                        .transpose()?,
+                    sha256: row.get(9)?,
+                    deduplicated: row.get::<_, Option<String>>(10)?.is_some(),
+                    pinned: row.get(11)?,
+                })
+            },
+        )?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Formats the unix timestamp read from `column`
+fn rfc3339(column: usize, timestamp: i64) -> rusqlite::Result<String> {
+    let conversion = |e: Box<dyn std::error::Error + Send + Sync>| {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Integer, e)
+    };
+    UtcDateTime::from_unix_timestamp(timestamp)
+        .map_err(|e| conversion(e.into()))?
+        .format(&Rfc3339)
+        .map_err(|e| conversion(e.into()))
+}
+
+pub async fn usage() -> TapferResult<AssetUsage> {
+    with_index(move |index| {
+        let mut usage = index.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(pinned), 0), COALESCE(SUM(size), 0),
+                COALESCE(SUM(CASE WHEN blob IS NULL THEN size END), 0)
+            FROM assets WHERE state = 'complete'",
+            [],
+            |row| {
+                Ok(AssetUsage {
+                    assets: row.get(0)?,
+                    pinned: row.get(1)?,
+                    logical_bytes: row.get(2)?,
+                    stored_bytes: row.get(3)?,
+                    blobs: 0,
+                })
+            },
+        )?;
+        let (blobs, blob_bytes): (u64, u64) = index.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM (
+                SELECT MAX(size) AS size FROM assets WHERE state = 'complete' AND blob IS NOT NULL GROUP BY blob
+            )",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        usage.blobs = blobs;
+        usage.stored_bytes += blob_bytes;
+        Ok(usage)
+    })
+    .await
+}
@@ -0,0 +1,377 @@
+use crate::blob_store;
+use crate::configuration::config;
+use crate::handlers::checksum;
+use crate::storage::storage;
+use crate::structs::digest::DigestAlgorithm;
+use crate::structs::error::{TapferErrorExt, TapferResult};
+use crate::structs::tapfer_id::TapferId;
+use crate::websocket::{WsEvent, broadcast_event};
+use dashmap::DashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use time::UtcDateTime;
+use time::format_description::well_known::Rfc3339;
+use tokio::sync::{Notify, Semaphore};
+use tracing::{error, info, warn};
+
+/// Post-processing of a complete asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Checksum { algorithm: DigestAlgorithm },
+    Deduplicate,
+}
+
+impl JobKind {
+    /// Also names the file the queued job is persisted in
+    pub fn name(self) -> String {
+        match self {
+            Self::Checksum { algorithm } => format!("checksum.{}", algorithm.name()),
+            Self::Deduplicate => "deduplicate".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+}
+
+/// What is persisted of a queued job
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct JobRecord {
+    asset: TapferId,
+    job: JobKind,
+    created: UtcDateTime,
+    /// Failed runs so far
+    attempts: u32,
+    /// Earliest time of the next run, pushed back after failures
+    not_before: UtcDateTime,
+}
+
+impl JobRecord {
+    fn key(&self) -> String {
+        format!("jobs/{}.{}.toml", self.asset, self.job.name())
+    }
+
+    async fn persist(&self) -> TapferResult<()> {
+        storage()
+            .write(&self.key(), toml::to_string_pretty(self)?.into_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+struct Job {
+    record: JobRecord,
+    state: JobState,
+    /// Bytes processed and total of a running job
+    progress: Option<(u64, u64)>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    fn queued(record: JobRecord) -> Self {
+        Self {
+            record,
+            state: JobState::Queued,
+            progress: None,
+            cancelled: Arc::default(),
+        }
+    }
+}
+
+static JOBS: LazyLock<DashMap<(TapferId, JobKind), Job>> = LazyLock::new(DashMap::new);
+/// Woken when a job becomes due
+static QUEUED: Notify = Notify::const_new();
+static SLOTS: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(Semaphore::new(config().jobs.concurrency)));
+
+/// Loads the jobs queued before the last shutdown and starts running them
+pub async fn init() -> TapferResult<()> {
+    for name in storage().list("jobs").await? {
+        let key = format!("jobs/{name}");
+        let record = match storage().read(&key).await {
+            Ok(s) => {
+                toml::from_str::<JobRecord>(&String::from_utf8_lossy(&s)).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match record {
+            // Deleted while the server was down
+            Ok(record) if !storage().exists(&record.asset.to_string()).await? => {
+                storage().remove(&key).await?;
+            }
+            Ok(record) => {
+                JOBS.insert((record.asset, record.job), Job::queued(record));
+            }
+            Err(e) => {
+                warn!("Removing unreadable job {key}: {e}");
+                storage().remove(&key).await?;
+            }
+        }
+    }
+    if !JOBS.is_empty() {
+        info!("Resuming {} queued jobs", JOBS.len());
+    }
+    tokio::spawn(run_queue());
+    Ok(())
+}
+
+/// Queues `job` for the asset, unless it is queued or running already
+pub fn enqueue(asset: TapferId, job: JobKind) {
+    let now = UtcDateTime::now();
+    let record = JobRecord {
+        asset,
+        job,
+        created: now,
+        attempts: 0,
+        not_before: now,
+    };
+    match JOBS.entry((asset, job)) {
+        dashmap::Entry::Occupied(_) => return,
+        dashmap::Entry::Vacant(v) => {
+            v.insert(Job::queued(record.clone()));
+        }
+    }
+    tokio::spawn(async move {
+        record
+            .persist()
+            .await
+            .log_error("Failed to persist job, it is lost on restart");
+        QUEUED.notify_one();
+    });
+}
+
+/// Drops the queued jobs of a deleted asset, running ones stop at their next read
+pub async fn cancel(asset: TapferId) {
+    let keys: Vec<_> = JOBS
+        .iter()
+        .filter(|j| j.key().0 == asset)
+        .map(|j| *j.key())
+        .collect();
+    for key in keys {
+        if let Some((_, job)) = JOBS.remove(&key) {
+            job.cancelled.store(true, Ordering::Relaxed);
+            let _ = storage().remove(&job.record.key()).await;
+        }
+    }
+}
+
+/// Queued and running jobs of the asset, oldest first
+pub fn jobs_of(asset: TapferId) -> Vec<JobSummary> {
+    summaries(|a| a == asset)
+        .into_iter()
+        .map(|(_, j)| j)
+        .collect()
+}
+
+/// Jobs of every asset, oldest first
+pub fn all_jobs() -> Vec<AssetJobSummary> {
+    summaries(|_| true)
+        .into_iter()
+        .map(|(asset, job)| AssetJobSummary {
+            asset: asset.to_string(),
+            job,
+        })
+        .collect()
+}
+
+fn summaries(filter: impl Fn(TapferId) -> bool) -> Vec<(TapferId, JobSummary)> {
+    let mut jobs: Vec<_> = JOBS
+        .iter()
+        .filter(|j| filter(j.key().0))
+        .map(|j| (j.record.created, j.key().0, JobSummary::new(j.value())))
+        .collect();
+    jobs.sort_by_key(|(created, _, _)| *created);
+    jobs.into_iter().map(|(_, asset, j)| (asset, j)).collect()
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct AssetJobSummary {
+    asset: String,
+    #[serde(flatten)]
+    job: JobSummary,
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct JobSummary {
+    /// `checksum.<algorithm>` or `deduplicate`
+    job: String,
+    state: JobState,
+    attempts: u32,
+    /// RFC 3339 time of the next run, for jobs waiting to be retried
+    retry_at: Option<String>,
+    /// Bytes processed so far by a running job
+    processed: Option<u64>,
+    /// Bytes the running job processes in total
+    total: Option<u64>,
+}
+
+impl JobSummary {
+    fn new(job: &Job) -> Self {
+        Self {
+            job: job.record.job.name(),
+            state: job.state,
+            attempts: job.record.attempts,
+            retry_at: (job.record.attempts > 0 && job.state == JobState::Queued)
+                .then(|| job.record.not_before.format(&Rfc3339).ok())
+                .flatten(),
+            processed: job.progress.map(|(processed, _)| processed),
+            total: job.progress.map(|(_, total)| total),
+        }
+    }
+}
+
+/// Handed to a running job to report its progress and notice its cancellation
+#[derive(Clone)]
+pub struct JobContext {
+    asset: TapferId,
+    job: JobKind,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobContext {
+    pub fn progress(&self, processed: u64, total: u64) {
+        if let Some(mut job) = JOBS.get_mut(&(self.asset, self.job)) {
+            job.progress = Some((processed, total));
+        }
+        broadcast_event(
+            self.asset,
+            WsEvent::JobProgress {
+                job: self.job.name(),
+                processed,
+                total,
+            },
+        )
+        .log_error("Failed to broadcast job progress");
+    }
+
+    /// Fails once the asset was deleted, aborting the blocking work of the job
+    pub fn check_cancelled(&self) -> io::Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("the asset was deleted"));
+        }
+        Ok(())
+    }
+}
+
+/// Starts due jobs whenever one of the `jobs.concurrency` slots is free
+async fn run_queue() {
+    loop {
+        let slot = SLOTS
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the job semaphore is never closed");
+        let Some((key, context)) = next_due() else {
+            drop(slot);
+            // Retries become due without notification
+            let next_retry = JOBS
+                .iter()
+                .filter(|j| j.state == JobState::Queued)
+                .map(|j| j.record.not_before - UtcDateTime::now())
+                .min()
+                .map(|wait| wait.try_into().unwrap_or_default())
+                .unwrap_or(std::time::Duration::from_secs(60));
+            let _ = tokio::time::timeout(next_retry, QUEUED.notified()).await;
+            continue;
+        };
+        tokio::spawn(async move {
+            let result = run(key, context.clone()).await;
+            drop(slot);
+            finish(key, &context, result).await;
+        });
+    }
+}
+
+/// Marks the oldest due job as running
+fn next_due() -> Option<((TapferId, JobKind), JobContext)> {
+    let now = UtcDateTime::now();
+    let mut due: Vec<_> = JOBS
+        .iter()
+        .filter(|j| j.state == JobState::Queued && j.record.not_before <= now)
+        .map(|j| (j.record.created, *j.key()))
+        .collect();
+    due.sort_by_key(|(created, _)| *created);
+    for (_, key) in due {
+        // Cancelled meanwhile
+        let Some(mut job) = JOBS.get_mut(&key) else {
+            continue;
+        };
+        if job.state == JobState::Queued {
+            job.state = JobState::Running;
+            let context = JobContext {
+                asset: key.0,
+                job: key.1,
+                cancelled: job.cancelled.clone(),
+            };
+            return Some((key, context));
+        }
+    }
+    None
+}
+
+async fn run((asset, job): (TapferId, JobKind), context: JobContext) -> TapferResult<()> {
+    match job {
+        JobKind::Checksum { algorithm } => {
+            checksum::compute(asset, algorithm, context).await?;
+        }
+        JobKind::Deduplicate => blob_store::intern(asset, context).await?,
+    }
+    Ok(())
+}
+
+async fn finish(key: (TapferId, JobKind), context: &JobContext, result: TapferResult<()>) {
+    let (asset, job) = key;
+    if context.cancelled.load(Ordering::Relaxed) {
+        return;
+    }
+    let Err(e) = result else {
+        if let Some((_, job)) = JOBS.remove(&key) {
+            let _ = storage().remove(&job.record.key()).await;
+        }
+        return;
+    };
+    let record = {
+        let Some(mut entry) = JOBS.get_mut(&key) else {
+            return;
+        };
+        entry.record.attempts += 1;
+        if entry.record.attempts >= config().jobs.max_attempts {
+            None
+        } else {
+            let delay = config()
+                .jobs
+                .retry_delay
+                .saturating_mul(2_i32.saturating_pow(entry.record.attempts - 1));
+            entry.record.not_before = UtcDateTime::now() + delay;
+            entry.state = JobState::Queued;
+            entry.progress = None;
+            Some(entry.record.clone())
+        }
+    };
+    match record {
+        Some(record) => {
+            warn!(
+                "Job {} of {asset} failed, retrying at {}: {e}",
+                job.name(),
+                record.not_before
+            );
+            record
+                .persist()
+                .await
+                .log_error("Failed to persist job, it is lost on restart");
+            QUEUED.notify_one();
+        }
+        None => {
+            error!("Job {} of {asset} failed for good: {e}", job.name());
+            if let Some((_, job)) = JOBS.remove(&key) {
+                let _ = storage().remove(&job.record.key()).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::configuration::{ListenAddress, config};
+use crate::structs::error::TapferResult;
+use axum::Router;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs::Permissions;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::fs;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// Binds every configured address and serves `app` on all of them until one fails
+pub async fn serve_all(app: Router) -> TapferResult<()> {
+    let mut servers = JoinSet::new();
+    for address in &config().server.listen {
+        match address {
+            ListenAddress::Tcp(addr) => {
+                let listener = bind_tcp(*addr)?;
+                info!("listening on {}", listener.local_addr()?);
+                let app = app
+                    .clone()
+                    .into_make_service_with_connect_info::<SocketAddr>();
+                servers.spawn(axum::serve(listener, app).into_future());
+            }
+            ListenAddress::Unix(path) => {
+                let listener = bind_unix(path, config().server.unix_socket_mode).await?;
+                info!("listening on {address}");
+                servers.spawn(axum::serve(listener, app.clone()).into_future());
+            }
+        }
+    }
+
+    while let Some(res) = servers.join_next().await {
+        res.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Without this `[::]` would also claim the IPv4 port, so `0.0.0.0` could not be listed alongside it
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    // A previous instance that did not shut down gracefully leaves its socket behind
+    match fs::symlink_metadata(path).await {
+        Ok(meta) if meta.file_type().is_socket() => {
+            debug!("removing stale socket {}", path.display());
+            fs::remove_file(path).await?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // Binding creates the socket with the umask's permissions, so it is bound inside a directory
+    // only we can enter and only moved to `path` once `mode` applies
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(".tapfer-{}", Uuid::new_v4()));
+    fs::DirBuilder::new().mode(0o700).create(&private).await?;
+    let bound = async {
+        let tmp = private.join("socket");
+        let listener = UnixListener::bind(&tmp)?;
+        fs::set_permissions(&tmp, Permissions::from_mode(mode)).await?;
+        fs::rename(&tmp, path).await?;
+        Ok(listener)
+    }
+    .await;
+    fs::remove_dir_all(&private).await?;
+    bound
+}
@@ -1,37 +1,43 @@
 mod api_doc;
+mod blob_store;
 mod case_insensitive_path;
+mod cli;
 mod configuration;
 mod handlers;
+mod index;
+mod jobs;
+mod listener;
+mod public_url;
 mod retention_control;
+mod storage;
 mod structs;
 mod updown;
 mod websocket;
 
 use crate::api_doc::ApiDoc;
 use crate::case_insensitive_path::lowercase_path_middleware;
-use crate::configuration::MAX_UPLOAD_SIZE;
+use crate::cli::{Cli, Command};
+use crate::configuration::{Configuration, config};
 use crate::handlers::deposit;
 use crate::handlers::upload;
-use crate::retention_control::{GlobalRetentionPolicy, check_all_assets};
+use crate::retention_control::check_all_assets;
 use crate::structs::error::TapferErrorExt;
+use crate::updown::resumable;
 use crate::updown::upload_pool::UploadPool;
 use crate::websocket::{WsDestination, WsEvent};
-use axum::routing::{any, get_service};
+use axum::routing::{any, get_service, options, post, put};
 use axum::{Router, extract::DefaultBodyLimit, middleware, routing::get};
 use dashmap::DashMap;
 use handlers::homepage;
-use http::HeaderValue;
+use std::env;
 use std::process;
 use std::sync::LazyLock;
 use std::thread;
 use std::time::Duration;
-use std::{env, fs};
 use structs::error::TapferResult;
 use structs::tapfer_id::TapferId;
 use tokio::time::sleep;
 use tower::ServiceBuilder;
-use tower_http::cors::CorsLayer;
-use tower_http::cors::{AllowOrigin, Any};
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::services::ServeDir;
 use tracing::{info, warn};
@@ -40,12 +46,32 @@ use utoipa::OpenApi;
 use utoipa_scalar::{Scalar, Servable};
 
 pub static PROGRESS_TOKEN_LUT: LazyLock<DashMap<u32, TapferId>> = LazyLock::new(DashMap::new);
-pub static GLOBAL_RETENTION_POLICY: LazyLock<GlobalRetentionPolicy> =
-    LazyLock::new(GlobalRetentionPolicy::default);
 pub static UPLOAD_POOL: LazyLock<UploadPool> = LazyLock::new(UploadPool::new);
 
 #[tokio::main]
 async fn main() -> TapferResult<()> {
+    let cli = Cli::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{}", cli::USAGE);
+        process::exit(2);
+    });
+    if cli.command == Command::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    let configuration = match Configuration::load(cli.config.as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    if cli.command == Command::CheckConfig {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+    configuration::init(configuration);
+
     ctrlc::set_handler(move || {
         let _ = websocket::broadcast_event(WsDestination::All, WsEvent::Shutdown);
         warn!("Shutting down in 1000ms");
@@ -63,21 +89,28 @@ async fn main() -> TapferResult<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    init_datadir();
+    if config().server.public_url.is_none() && !config().server.trust_host_header {
+        warn!(
+            "server.public_url is not set, links will point to {}",
+            public_url::PublicUrls::configured().base()
+        );
+    }
+
+    storage::init().await?;
+    index::init().await?;
+    blob_store::init().await?;
+    jobs::init().await?;
 
     let static_dir_service = get_service(ServeDir::new("static"));
 
-    let cors = CorsLayer::new()
-        .allow_methods(Any)
-        .allow_origin(AllowOrigin::list([
-            HeaderValue::from_static("https://tapfer.lkl.lol"),
-            HeaderValue::from_static("https://cdn.tapfer.lkl.lol"),
-        ]));
+    let cors = config().cors_layer();
 
     let lowercase_router = Router::new()
         .route(
             "/uploads/{id}",
-            get(handlers::download::download_html).delete(handlers::delete::request_delete_asset),
+            get(handlers::download::download_html)
+                .delete(handlers::delete::request_delete_asset)
+                .patch(handlers::modify::modify_asset),
         )
         .layer(cors.clone());
 
@@ -89,6 +122,13 @@ async fn main() -> TapferResult<()> {
     // build our application with some routes
     let app = Router::new()
         .route("/", get(homepage::show_form).post(upload::accept_form))
+        .route(
+            "/{filename}",
+            put(upload::accept_raw)
+                .post(upload::accept_raw)
+                .fallback_service(fallback_service.clone()),
+        )
+        .route("/bundle", get(handlers::bundle::download_bundle))
         .route("/deposit", get(deposit::show_form))
         .route("/deposit/ws", any(deposit::start_ws))
         .route(
@@ -100,47 +140,78 @@ async fn main() -> TapferResult<()> {
             get(handlers::download::download_file),
         )
         .route(
-            "/uploads/{id}/checksum.sha512",
-            get(handlers::checksum::get_sha512sum),
+            "/uploads/{id}/files/{*path}",
+            get(handlers::download::download_entry),
+        )
+        .route(
+            "/uploads/{id}/contents",
+            get(handlers::contents::show_archive_contents),
+        )
+        .route(
+            "/uploads/{id}/entry/{*path}",
+            get(handlers::contents::download_archive_entry),
+        )
+        .route(
+            "/uploads/{id}/unlock",
+            // Passwords are small, unlike the uploads the other routes accept
+            post(handlers::unlock::unlock_asset).layer(DefaultBodyLimit::max(16 * 1024)),
+        )
+        .route(
+            "/uploads/{id}/checksum.{algorithm}",
+            get(handlers::checksum::get_checksum),
         )
+        .route("/uploads/{id}/jobs", get(handlers::jobs::list_jobs))
+        .route("/uploads/{id}/{sums}", get(handlers::checksum::get_sums))
         .route("/uploads/{uuid}/ws", any(websocket::start_ws))
+        .route(
+            "/tus",
+            options(handlers::tus::tus_options).post(handlers::tus::tus_create),
+        )
+        .route(
+            "/tus/{id}",
+            axum::routing::head(handlers::tus::tus_head)
+                .patch(handlers::tus::tus_patch)
+                .delete(handlers::tus::tus_delete),
+        )
         .route("/qrcg/{id}", get(handlers::qrcode::get_qrcode_from_id))
+        .route("/admin/assets", get(handlers::admin::list_assets))
+        .route(
+            "/admin/assets/{id}",
+            axum::routing::delete(handlers::admin::delete_asset),
+        )
+        .route(
+            "/admin/assets/{id}/pin",
+            put(handlers::admin::pin_asset).delete(handlers::admin::unpin_asset),
+        )
+        .route("/admin/uploads", get(handlers::admin::list_uploads))
+        .route("/admin/jobs", get(handlers::admin::list_all_jobs))
+        .route("/admin/storage", get(handlers::admin::storage_usage))
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(MAX_UPLOAD_SIZE))
+        .layer(RequestBodyLimitLayer::new(
+            config().limits.max_upload_size.as_usize(),
+        ))
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .nest_service("/static", static_dir_service)
         .merge(Scalar::with_url("/docs", <ApiDoc as OpenApi>::openapi()))
         .fallback_service(fallback_service)
-        .layer(cors);
-
-    let main_service = ServiceBuilder::new().service(app);
-
-    // run it with hyper
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    tracing::debug!("listening on {}", listener.local_addr()?);
+        .layer(cors)
+        .layer(middleware::from_fn(handlers::tus::discovery_middleware));
 
     tokio::spawn(async {
         loop {
             // TODO: Handle errors in a better way
             info!("Checking for stale assets");
             check_all_assets().await.log_error("Checking assets failed");
+            resumable::reap_stalled()
+                .await
+                .log_error("Reaping stalled uploads failed");
 
             sleep(Duration::from_secs_f64(
-                GLOBAL_RETENTION_POLICY.recheck_interval.as_seconds_f64(),
+                config().retention.recheck_interval.as_seconds_f64(),
             ))
             .await;
         }
     });
 
-    axum::serve(listener, main_service).await?;
-    Ok(())
-}
-
-pub fn init_datadir() {
-    fs::create_dir_all("data").unwrap();
-    fs::write(
-        "./data/CACHEDIR.TAG",
-        "Signature: 8a477f597d28d172789f06886806bc55",
-    )
-    .unwrap();
+    listener::serve_all(app).await
 }
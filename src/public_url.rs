@@ -0,0 +1,143 @@
+use crate::configuration::{ListenAddress, config};
+use crate::handlers::is_localhost;
+use crate::structs::tapfer_id::TapferId;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::request::Parts;
+use axum_extra::extract::Host;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::convert::Infallible;
+
+/// Everything but unreserved characters is escaped within a path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Base URLs used for every link handed out to clients.
+///
+/// Resolved from `server.public_url` and `server.download_url`.
+/// Only when `server.trust_host_header` is enabled and no public URL is configured, the (spoofable) Host header is used.
+#[derive(Debug, Clone)]
+pub struct PublicUrls {
+    /// Pages, QR codes and WebSockets, e.g. `https://tapfer.example.com`
+    base: String,
+    /// Uploads and downloads, e.g. `https://cdn.tapfer.example.com`
+    download: String,
+}
+
+impl PublicUrls {
+    /// Resolves the URLs without a request at hand
+    pub fn configured() -> Self {
+        Self::resolve(None, None)
+    }
+
+    fn resolve(host: Option<&str>, forwarded_proto: Option<&str>) -> Self {
+        let server = &config().server;
+        if let Some(public) = &server.public_url {
+            let download = server.download_url.as_ref().unwrap_or(public);
+            return Self {
+                base: public.to_string(),
+                download: download.to_string(),
+            };
+        }
+
+        if server.trust_host_header
+            && let Some(host) = host
+        {
+            let localhost = is_localhost(host);
+            let scheme = match forwarded_proto {
+                Some(proto @ ("http" | "https")) => proto,
+                _ if localhost => "http",
+                _ => "https",
+            };
+            let host = host.strip_prefix("cdn.").unwrap_or(host);
+            let base = format!("{scheme}://{host}");
+            let download = if localhost {
+                base.clone()
+            } else {
+                format!("{scheme}://cdn.{host}")
+            };
+            return Self { base, download };
+        }
+
+        let port = server
+            .listen
+            .iter()
+            .find_map(|l| match l {
+                ListenAddress::Tcp(addr) => Some(addr.port()),
+                ListenAddress::Unix(_) => None,
+            })
+            .unwrap_or(3000);
+        let base = format!("http://localhost:{port}");
+        Self {
+            download: base.clone(),
+            base,
+        }
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn download(&self) -> &str {
+        &self.download
+    }
+
+    pub fn asset_page(&self, id: TapferId) -> String {
+        format!("{}/uploads/{id}", self.base)
+    }
+
+    pub fn asset_download(&self, id: TapferId) -> String {
+        format!("{}/uploads/{id}/download", self.download)
+    }
+
+    /// Download of a single file of an asset, `path` being relative to the asset
+    pub fn asset_entry(&self, id: TapferId, path: &str) -> String {
+        format!("{}/uploads/{id}/files/{}", self.download, encode_path(path))
+    }
+
+    /// Extraction of a file from within an uploaded archive
+    pub fn archive_entry(&self, id: TapferId, path: &str) -> String {
+        format!("{}/uploads/{id}/entry/{}", self.download, encode_path(path))
+    }
+
+    /// WebSocket URL for `path`, which must start with a slash
+    pub fn websocket(&self, path: &str) -> String {
+        let ws_base = if let Some(rest) = self.base.strip_prefix("https://") {
+            format!("wss://{rest}")
+        } else {
+            self.base.replacen("http://", "ws://", 1)
+        };
+        format!("{ws_base}{path}")
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for PublicUrls {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if config().server.public_url.is_some() || !config().server.trust_host_header {
+            return Ok(Self::configured());
+        }
+        let host = <Host as OptionalFromRequestParts<S>>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .flatten();
+        let forwarded_proto = parts
+            .headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok());
+        Ok(Self::resolve(
+            host.as_ref().map(|h| h.0.as_str()),
+            forwarded_proto,
+        ))
+    }
+}
@@ -1,18 +1,25 @@
-pub(crate) use crate::GLOBAL_RETENTION_POLICY;
+use crate::blob_store;
+use crate::configuration::config;
+use crate::handlers::{contents, download};
+use crate::index;
+use crate::jobs;
+use crate::structs::asset_password;
 use crate::structs::error::{TapferErrorExt, TapferResult};
 use crate::structs::file_meta::FileMeta;
+use crate::structs::human_duration;
 use crate::structs::tapfer_id::TapferId;
+use crate::websocket;
 use crate::websocket::WsEvent;
-use crate::{UPLOAD_POOL, websocket};
-use std::ops::{Add, Not};
-use std::str::FromStr;
+use std::ops::Add;
 use time::{Duration, UtcDateTime};
-use tokio::fs;
-use tokio::fs::remove_dir_all;
-use tracing::{info};
+use tracing::info;
 
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct GlobalRetentionPolicy {
+    #[serde(deserialize_with = "human_duration::deserialize")]
     pub maximum_age: Duration,
+    #[serde(deserialize_with = "human_duration::deserialize")]
     pub recheck_interval: Duration,
 }
 
@@ -25,11 +32,15 @@ impl Default for GlobalRetentionPolicy {
     }
 }
 
+/// Deletes the asset when it outlived either its own expiration or the global maximum age
 pub async fn check_against_global_retention(
     (id, meta): (TapferId, FileMeta),
     now: UtcDateTime,
 ) -> TapferResult<()> {
-    if meta.created().add(GLOBAL_RETENTION_POLICY.maximum_age) < now {
+    if meta.created().add(config().retention.maximum_age) < now {
+        info!("Deleting {id} as it has exceeded the maximum age");
+        delete_asset(id).await?;
+    } else if meta.expires_on_utc().is_some_and(|expiry| expiry < now) {
         info!("Deleting {id} as it has expired");
         delete_asset(id).await?;
     }
@@ -39,45 +50,25 @@ pub async fn check_against_global_retention(
 pub async fn delete_asset(asset: TapferId) -> TapferResult<()> {
     websocket::broadcast_event(asset, WsEvent::DeleteAsset)
         .log_error("Failed to broadcast deletion event");
-    fs::remove_dir_all(format!("data/{asset}")).await?;
+    {
+        // Jobs persist their results under the lock, so none lands after the removal
+        let _guard = FileMeta::lock(asset).await;
+        jobs::cancel(asset).await;
+    }
+    blob_store::remove_asset(asset).await?;
+    FileMeta::forget_lock(asset);
+    asset_password::forget_attempts(asset);
+    download::forget_delivered_ranges(asset);
+    contents::forget_listing(asset);
     Ok(())
 }
 
+/// Deletes every asset the index lists as expired. Uploads in progress are not checked
 pub async fn check_all_assets() -> TapferResult<()> {
     let now = UtcDateTime::now();
-    let mut dir = fs::read_dir("data").await?;
-    while let Some(entry) =  dir.next_entry().await? {
-        let file_meta = match entry.metadata().await {
-            Ok(m) => m,
-            e => {
-                e.log_error("Failed to get metadata");
-                continue;
-            }
-        };
-        // Skip cachedir tag
-        if file_meta.is_dir().not() {
-            continue;
-        }
-        let mut path = entry.path().to_path_buf();
-        path.push("meta.toml");
-        let id = match TapferId::from_str(&entry.file_name().to_string_lossy()) {
-            Ok(t) => {t}
-            e => {e.log_error(&format!("Failed get ID from {}", path.display())); continue}
-        };
-
-        if let Ok(meta) = FileMeta::read_from_id(id).await {
-            check_against_global_retention((id, meta), now).await?;
-        } else {
-            // Only delete element if it isn't in progress
-            if !UPLOAD_POOL.uploads.contains_key(&id) {
-                // Delete asset straight up when metadata is missing or corrupt
-                info!(
-                    "Deleting {} as its metadata appears corrupt",
-                    entry.path().as_os_str().to_string_lossy()
-                );
-                remove_dir_all(entry.path()).await?;
-            }
-        }
+    let created_before = now - config().retention.maximum_age;
+    for asset in index::expired(created_before, now).await? {
+        check_against_global_retention(asset, now).await?;
     }
     Ok(())
 }
@@ -0,0 +1,200 @@
+use crate::storage::{BlockingReader, Storage, StorageReader, StorageWriter};
+use async_trait::async_trait;
+use std::io;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncSeekExt;
+use uuid::Uuid;
+
+/// Directory below the root where [`Storage::write`] stages files before renaming them into place.
+/// Outside of every asset directory, so staged files never clash with uploaded names
+const STAGING: &str = ".tmp";
+
+/// Stores everything in a directory, `data` by default
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    /// Creates the root and marks it as cache, so backup tools skip it.
+    /// Drops files staged by writes that the last shutdown interrupted
+    pub fn open(root: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        match std::fs::remove_dir_all(root.join(STAGING)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::create_dir(root.join(STAGING))?;
+        std::fs::write(
+            root.join("CACHEDIR.TAG"),
+            "Signature: 8a477f597d28d172789f06886806bc55",
+        )?;
+        Ok(Self {
+            root: root.to_owned(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    async fn create_parent(&self, key: &str) -> io::Result<PathBuf> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)).await
+    }
+
+    async fn write(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        let path = self.create_parent(key).await?;
+        let tmp = self.path(&format!("{STAGING}/{}", Uuid::new_v4()));
+        fs::write(&tmp, data).await?;
+        if let Err(e) = fs::rename(&tmp, path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        fs::try_exists(self.path(key)).await
+    }
+
+    async fn len(&self, key: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(key)).await?.len())
+    }
+
+    async fn reader(&self, key: &str, offset: u64) -> io::Result<StorageReader> {
+        let mut file = File::open(self.path(key)).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    fn blocking_reader(&self, key: &str) -> io::Result<BlockingReader> {
+        Ok(Box::new(std::fs::File::open(self.path(key))?))
+    }
+
+    async fn writer(&self, key: &str, offset: u64) -> io::Result<StorageWriter> {
+        let path = self.create_parent(key).await?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.create_parent(to).await?;
+        fs::rename(self.path(from), to).await
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key)).await
+    }
+
+    async fn remove_all(&self, prefix: &str) -> io::Result<()> {
+        match fs::remove_dir_all(self.path(prefix)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut dir = match fs::read_dir(self.path(prefix)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            dir => dir?,
+        };
+        let mut names = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::digest::DigestAlgorithm;
+    use crate::structs::file_meta;
+    use crate::structs::tapfer_id::TapferId;
+    use tokio::io::AsyncWriteExt;
+
+    fn temp_storage() -> (PathBuf, FsStorage) {
+        let root = std::env::temp_dir().join(format!("tapfer-test-{}", Uuid::new_v4()));
+        let storage = FsStorage::open(&root).unwrap();
+        (root, storage)
+    }
+
+    /// Names an upload or a rename may pick that resemble the files kept next to the payload
+    fn staging_lookalikes() -> impl Iterator<Item = String> {
+        DigestAlgorithm::ALL
+            .into_iter()
+            .map(|algorithm| format!("{}.tmp", algorithm.file_name()))
+            .chain(["meta.toml.tmp".to_owned()])
+    }
+
+    async fn write_metadata(storage: &FsStorage, id: TapferId) {
+        storage
+            .write(&format!("{id}/meta.toml"), b"meta".to_vec())
+            .await
+            .unwrap();
+        for algorithm in DigestAlgorithm::ALL {
+            let checksum = format!("{id}/{}", algorithm.file_name());
+            storage.write(&checksum, b"digest".to_vec()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_never_clobber_uploaded_files() {
+        let (root, storage) = temp_storage();
+        let id = TapferId::new_random();
+        for name in staging_lookalikes() {
+            file_meta::validate_file_name(&name).unwrap();
+            let payload = format!("{id}/{name}");
+            let mut writer = storage.writer(&payload, 0).await.unwrap();
+            writer.write_all(b"uploaded").await.unwrap();
+            writer.shutdown().await.unwrap();
+
+            write_metadata(&storage, id).await;
+            assert_eq!(storage.read(&payload).await.unwrap(), b"uploaded", "{name}");
+            storage.remove(&payload).await.unwrap();
+        }
+        assert!(storage.list(STAGING).await.unwrap().is_empty());
+        assert_eq!(storage.list_assets().await.unwrap(), [id]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_never_clobber_uploaded_files() {
+        let (root, storage) = temp_storage();
+        let id = TapferId::new_random();
+        let mut payload = format!("{id}/upload.bin");
+        storage.write(&payload, b"uploaded".to_vec()).await.unwrap();
+        // As a PATCH renaming the file of the asset does
+        for name in staging_lookalikes() {
+            file_meta::validate_file_name(&name).unwrap();
+            let renamed = format!("{id}/{name}");
+            storage.rename(&payload, &renamed).await.unwrap();
+            payload = renamed;
+
+            write_metadata(&storage, id).await;
+            assert_eq!(storage.read(&payload).await.unwrap(), b"uploaded", "{name}");
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::storage::{BlockingReader, Storage, StorageReader, StorageWriter};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+/// Keeps everything in memory, for tests and ephemeral instances. Nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: DashMap<String, Contents>,
+}
+
+impl MemoryStorage {
+    fn get(&self, key: &str) -> io::Result<Contents> {
+        self.files
+            .get(key)
+            .map(|f| f.value().clone())
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        Ok(self.get(key)?.read().expect("poisoned file").clone())
+    }
+
+    async fn write(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        // Readers of the replaced file keep reading its old contents, as with a rename on disk
+        self.files
+            .insert(key.to_owned(), Arc::new(RwLock::new(data)));
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        let dir = format!("{key}/");
+        Ok(self
+            .files
+            .iter()
+            .any(|f| f.key() == key || f.key().starts_with(&dir)))
+    }
+
+    async fn len(&self, key: &str) -> io::Result<u64> {
+        Ok(self.get(key)?.read().expect("poisoned file").len() as u64)
+    }
+
+    async fn reader(&self, key: &str, offset: u64) -> io::Result<StorageReader> {
+        Ok(Box::new(MemoryReader {
+            contents: self.get(key)?,
+            position: offset,
+        }))
+    }
+
+    fn blocking_reader(&self, key: &str) -> io::Result<BlockingReader> {
+        Ok(Box::new(MemoryReader {
+            contents: self.get(key)?,
+            position: 0,
+        }))
+    }
+
+    async fn writer(&self, key: &str, offset: u64) -> io::Result<StorageWriter> {
+        let contents = self.files.entry(key.to_owned()).or_default().clone();
+        let len = usize::try_from(offset).map_err(io::Error::other)?;
+        contents.write().expect("poisoned file").resize(len, 0);
+        Ok(Box::new(MemoryWriter { contents }))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (_, contents) = self
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.files.insert(to.to_owned(), contents);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        self.files
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn remove_all(&self, prefix: &str) -> io::Result<()> {
+        let dir = format!("{prefix}/");
+        self.files.retain(|key, _| !key.starts_with(&dir));
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let dir = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{prefix}/")
+        };
+        let names: BTreeSet<_> = self
+            .files
+            .iter()
+            .filter_map(|f| {
+                let rest = f.key().strip_prefix(&dir)?;
+                Some(rest.split('/').next().unwrap_or(rest).to_owned())
+            })
+            .collect();
+        Ok(names.into_iter().collect())
+    }
+}
+
+struct MemoryReader {
+    contents: Contents,
+    position: u64,
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let contents = self.contents.read().expect("poisoned file");
+        let start = usize::try_from(self.position)
+            .unwrap_or(usize::MAX)
+            .min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MemoryReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.contents.read().expect("poisoned file").len() as u64;
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl AsyncRead for MemoryReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = Read::read(&mut *self, buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct MemoryWriter {
+    contents: Contents,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.contents
+            .write()
+            .expect("poisoned file")
+            .extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,115 @@
+pub mod filesystem;
+pub mod memory;
+pub mod s3;
+
+use crate::configuration::{StorageBackend, config};
+use crate::storage::filesystem::FsStorage;
+use crate::storage::memory::MemoryStorage;
+use crate::storage::s3::S3Storage;
+use crate::structs::tapfer_id::TapferId;
+use async_trait::async_trait;
+use std::io;
+use std::io::{Read, Seek};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+/// The storage backend picked by the configuration
+pub fn storage() -> &'static dyn Storage {
+    STORAGE
+        .get()
+        .expect("storage is initialized before anything reads it")
+        .as_ref()
+}
+
+/// Opens the configured backend for the rest of the process lifetime
+pub async fn init() -> io::Result<()> {
+    let backend: Box<dyn Storage> = match config().storage.backend {
+        StorageBackend::Filesystem => Box::new(FsStorage::open(&config().storage.root)?),
+        StorageBackend::Memory => Box::new(MemoryStorage::default()),
+        StorageBackend::S3 => Box::new(S3Storage::open(&config().storage.s3).await?),
+    };
+    if STORAGE.set(backend).is_err() {
+        panic!("storage was initialized twice");
+    }
+    Ok(())
+}
+
+pub type StorageReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type StorageWriter = Box<dyn AsyncWrite + Send + Unpin>;
+pub type BlockingReader = Box<dyn ReadSeek + Send>;
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Where assets, their metadata and everything kept next to them is stored.
+///
+/// Files are addressed by keys of `/` separated components relative to the storage root,
+/// such as `{id}/meta.toml`. Directories exist implicitly while files below them do
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Reads a small file, such as metadata, in full
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Replaces a small file atomically, so readers never observe it partially written
+    async fn write(&self, key: &str, data: Vec<u8>) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    async fn len(&self, key: &str) -> io::Result<u64>;
+
+    /// Reads from `offset` on. Reads at the end return nothing until a writer appends more
+    async fn reader(&self, key: &str, offset: u64) -> io::Result<StorageReader>;
+
+    /// For use within `spawn_blocking`, such as by archive readers that need to seek
+    fn blocking_reader(&self, key: &str) -> io::Result<BlockingReader>;
+
+    /// Writes from `offset` on, dropping anything stored beyond it. Creates the file when it is missing
+    async fn writer(&self, key: &str, offset: u64) -> io::Result<StorageWriter>;
+
+    /// Completes a file written through [`Storage::writer`].
+    /// Backends storing it in parts meanwhile assemble it here, for everything but the writer it is ready afterwards
+    async fn commit(&self, _key: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves a file, replacing any file at `to`
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    async fn remove(&self, key: &str) -> io::Result<()>;
+
+    /// Removes a directory and everything below it, succeeding when there is none
+    async fn remove_all(&self, prefix: &str) -> io::Result<()>;
+
+    /// Names of the files and directories directly below `prefix`, the root when empty.
+    /// Empty when there is no such directory
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// IDs of all stored assets, including those still uploading
+    async fn list_assets(&self) -> io::Result<Vec<TapferId>> {
+        Ok(self
+            .list("")
+            .await?
+            .iter()
+            .filter_map(|name| TapferId::from_str(name).ok())
+            .collect())
+    }
+
+    /// Removes an asset along with its metadata
+    async fn remove_asset(&self, id: TapferId) -> io::Result<()> {
+        self.remove_all(&id.to_string()).await
+    }
+}
+
+/// Key of the metadata of an asset
+pub fn meta_key(id: TapferId) -> String {
+    format!("{id}/meta.toml")
+}
+
+/// Key of a file within the directory of an asset
+pub fn asset_key(id: TapferId, path: &str) -> String {
+    format!("{id}/{path}")
+}
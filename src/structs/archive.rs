@@ -0,0 +1,669 @@
+use crate::structs::file_meta::FileMeta;
+use crate::structs::tapfer_id::TapferId;
+use time::UtcDateTime;
+use tokio_util::bytes::Bytes;
+
+/// Sizes and offsets at or above this do not fit the classic ZIP fields and need ZIP64 extra fields
+const ZIP64_THRESHOLD: u64 = 0xFFFF_FFFF;
+
+/// Largest size the 11 octal digits of a ustar header can hold
+const USTAR_MAX_SIZE: u64 = 0o77_777_777_777;
+
+const TAR_BLOCK: u64 = 512;
+
+/// An archive as a sequence of generated headers and file contents.
+/// Its exact length is known before a single file is read
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    parts: Vec<ArchivePart>,
+    len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchivePart {
+    Bytes(Bytes),
+    File { key: String, len: u64 },
+}
+
+impl Archive {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn into_parts(self) -> Vec<ArchivePart> {
+        self.parts
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// Uncompressed ZIP, with ZIP64 records where needed
+    #[default]
+    Zip,
+    /// POSIX tar, with PAX headers for long paths and large files
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+/// A stored file to be added to an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveFile {
+    /// Path within the archive, with `/` separators
+    pub name: String,
+    /// Storage key of its contents
+    pub key: String,
+    pub size: u64,
+    /// Only used by ZIP archives
+    pub crc32: u32,
+    pub modified: UtcDateTime,
+}
+
+/// The files of a multi-file asset as an uncompressed ZIP archive.
+/// Byte-for-byte reproducible, so its checksum and length stay valid across downloads
+pub fn zip_asset(id: TapferId, meta: &FileMeta) -> Archive {
+    let mut zip = ArchiveBuilder::new(ArchiveFormat::Zip);
+    for entry in meta.entries() {
+        zip.add_file(ArchiveFile {
+            name: entry.path.clone(),
+            key: meta.storage_key(id, &entry.path),
+            size: entry.size,
+            crc32: entry.crc32,
+            modified: meta.created(),
+        });
+    }
+    zip.finish()
+}
+
+/// Assembles an archive without compression, so it can be streamed straight from the stored files
+pub struct ArchiveBuilder {
+    format: ArchiveFormat,
+    archive: Archive,
+    central_directory: Vec<u8>,
+    entries: u64,
+}
+
+impl ArchiveBuilder {
+    pub fn new(format: ArchiveFormat) -> Self {
+        Self {
+            format,
+            archive: Archive::default(),
+            central_directory: vec![],
+            entries: 0,
+        }
+    }
+
+    pub fn add_file(&mut self, file: ArchiveFile) {
+        match self.format {
+            ArchiveFormat::Zip => self.add_zip_file(file),
+            ArchiveFormat::Tar => self.add_tar_file(file),
+        }
+        self.entries += 1;
+    }
+
+    /// Parts added since the last call, while the total length keeps counting them
+    pub fn take_parts(&mut self) -> Vec<ArchivePart> {
+        std::mem::take(&mut self.archive.parts)
+    }
+
+    /// Appends the trailer. Holds only the parts not yet taken, but the length of the entire archive
+    pub fn finish(mut self) -> Archive {
+        let trailer = match self.format {
+            ArchiveFormat::Zip => self.zip_end(),
+            // Two empty blocks mark the end
+            ArchiveFormat::Tar => vec![0; 2 * TAR_BLOCK as usize],
+        };
+        self.push_bytes(trailer);
+        self.archive
+    }
+
+    fn push_bytes(&mut self, bytes: Vec<u8>) {
+        self.archive.len += bytes.len() as u64;
+        self.archive
+            .parts
+            .push(ArchivePart::Bytes(Bytes::from(bytes)));
+    }
+
+    fn push_file(&mut self, key: String, len: u64) {
+        self.archive.len += len;
+        self.archive.parts.push(ArchivePart::File { key, len });
+    }
+
+    fn add_zip_file(&mut self, file: ArchiveFile) {
+        let ArchiveFile {
+            name,
+            key,
+            size,
+            crc32,
+            modified,
+        } = file;
+        let offset = self.archive.len;
+        let size_zip64 = size >= ZIP64_THRESHOLD;
+        let offset_zip64 = offset >= ZIP64_THRESHOLD;
+        let version: u16 = if size_zip64 || offset_zip64 { 45 } else { 20 };
+        let size32 = u32::try_from(size).unwrap_or(u32::MAX);
+        let offset32 = u32::try_from(offset).unwrap_or(u32::MAX);
+        let common = ZipCommon {
+            version,
+            crc32,
+            size32,
+            modified: dos_date_time(modified),
+        };
+
+        let mut local_extra = vec![];
+        if size_zip64 {
+            put_u16(&mut local_extra, 0x0001);
+            put_u16(&mut local_extra, 16);
+            put_u64(&mut local_extra, size);
+            put_u64(&mut local_extra, size);
+        }
+        let mut local = Vec::with_capacity(30 + name.len() + local_extra.len());
+        put_u32(&mut local, 0x0403_4b50);
+        common.put(&mut local);
+        put_u16(&mut local, name_len(&name));
+        put_u16(&mut local, local_extra.len() as u16);
+        local.extend_from_slice(name.as_bytes());
+        local.extend_from_slice(&local_extra);
+        self.push_bytes(local);
+        self.push_file(key, size);
+
+        // The central directory only carries the ZIP64 fields that overflowed, in this order
+        let mut central_extra = vec![];
+        if size_zip64 || offset_zip64 {
+            let fields = u16::from(size_zip64) * 16 + u16::from(offset_zip64) * 8;
+            put_u16(&mut central_extra, 0x0001);
+            put_u16(&mut central_extra, fields);
+            if size_zip64 {
+                put_u64(&mut central_extra, size);
+                put_u64(&mut central_extra, size);
+            }
+            if offset_zip64 {
+                put_u64(&mut central_extra, offset);
+            }
+        }
+        let cd = &mut self.central_directory;
+        put_u32(cd, 0x0201_4b50);
+        // Made by Unix, so the permissions in the external attributes apply
+        put_u16(cd, 3 << 8 | version);
+        common.put(cd);
+        put_u16(cd, name_len(&name));
+        put_u16(cd, central_extra.len() as u16);
+        // Comment length, disk number and internal attributes
+        put_u16(cd, 0);
+        put_u16(cd, 0);
+        put_u16(cd, 0);
+        put_u32(cd, 0o100_644 << 16);
+        put_u32(cd, offset32);
+        cd.extend_from_slice(name.as_bytes());
+        cd.extend_from_slice(&central_extra);
+    }
+
+    fn zip_end(&mut self) -> Vec<u8> {
+        let cd_offset = self.archive.len;
+        let cd_size = self.central_directory.len() as u64;
+        let mut end = std::mem::take(&mut self.central_directory);
+
+        let zip64 =
+            self.entries >= 0xFFFF || cd_offset >= ZIP64_THRESHOLD || cd_size >= ZIP64_THRESHOLD;
+        if zip64 {
+            let record_offset = cd_offset + cd_size;
+            put_u32(&mut end, 0x0606_4b50);
+            // Size of the remaining record
+            put_u64(&mut end, 44);
+            put_u16(&mut end, 3 << 8 | 45);
+            put_u16(&mut end, 45);
+            put_u32(&mut end, 0);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, self.entries);
+            put_u64(&mut end, self.entries);
+            put_u64(&mut end, cd_size);
+            put_u64(&mut end, cd_offset);
+
+            put_u32(&mut end, 0x0706_4b50);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, record_offset);
+            put_u32(&mut end, 1);
+        }
+
+        let entries16 = u16::try_from(self.entries).unwrap_or(u16::MAX);
+        let narrow = |value: u64| {
+            if zip64 {
+                u32::MAX
+            } else {
+                u32::try_from(value).unwrap_or(u32::MAX)
+            }
+        };
+        put_u32(&mut end, 0x0605_4b50);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, if zip64 { u16::MAX } else { entries16 });
+        put_u16(&mut end, if zip64 { u16::MAX } else { entries16 });
+        put_u32(&mut end, narrow(cd_size));
+        put_u32(&mut end, narrow(cd_offset));
+        // Comment length
+        put_u16(&mut end, 0);
+        end
+    }
+
+    fn add_tar_file(&mut self, file: ArchiveFile) {
+        let mtime = file.modified.unix_timestamp().max(0) as u64;
+        // Paths that do not fit the ustar name field, and sizes beyond its digits, go into a PAX header
+        let mut pax = String::new();
+        if file.name.len() > 100 || !file.name.is_ascii() {
+            pax_record(&mut pax, "path", &file.name);
+        }
+        if file.size > USTAR_MAX_SIZE {
+            pax_record(&mut pax, "size", &file.size.to_string());
+        }
+
+        let mut header = vec![];
+        if !pax.is_empty() {
+            header.extend_from_slice(&ustar_header(
+                "././@PaxHeader",
+                pax.len() as u64,
+                mtime,
+                b'x',
+            ));
+            header.extend_from_slice(pax.as_bytes());
+            pad_block(&mut header);
+        }
+        let mut name = file.name.as_str();
+        while name.len() > 100 {
+            let mut end = 100;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = &name[..end];
+        }
+        let size = if file.size > USTAR_MAX_SIZE {
+            0
+        } else {
+            file.size
+        };
+        header.extend_from_slice(&ustar_header(name, size, mtime, b'0'));
+        self.push_bytes(header);
+        self.push_file(file.key, file.size);
+
+        let padding = (TAR_BLOCK - file.size % TAR_BLOCK) % TAR_BLOCK;
+        if padding > 0 {
+            self.push_bytes(vec![0; padding as usize]);
+        }
+    }
+}
+
+/// Fields shared by local and central ZIP headers, from the version needed up to the sizes
+struct ZipCommon {
+    version: u16,
+    crc32: u32,
+    size32: u32,
+    modified: (u16, u16),
+}
+
+impl ZipCommon {
+    fn put(&self, buf: &mut Vec<u8>) {
+        put_u16(buf, self.version);
+        // Names are UTF-8
+        put_u16(buf, 1 << 11);
+        // Stored without compression
+        put_u16(buf, 0);
+        put_u16(buf, self.modified.0);
+        put_u16(buf, self.modified.1);
+        put_u32(buf, self.crc32);
+        put_u32(buf, self.size32);
+        put_u32(buf, self.size32);
+    }
+}
+
+/// DOS timestamps start in 1980 and have a two second resolution
+fn dos_date_time(modified: UtcDateTime) -> (u16, u16) {
+    let year = u16::try_from(modified.year().clamp(1980, 2107) - 1980).unwrap_or_default();
+    let date = year << 9 | u16::from(u8::from(modified.month())) << 5 | u16::from(modified.day());
+    let time = u16::from(modified.hour()) << 11
+        | u16::from(modified.minute()) << 5
+        | u16::from(modified.second() / 2);
+    (time, date)
+}
+
+/// Paths are validated to be at most 4096 bytes long
+fn name_len(name: &str) -> u16 {
+    u16::try_from(name.len()).unwrap_or(u16::MAX)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// A `<length> <key>=<value>\n` record, the length counting its own digits
+fn pax_record(pax: &mut String, key: &str, value: &str) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    pax.push_str(&format!("{len} {key}={value}\n"));
+}
+
+fn ustar_header(name: &str, size: u64, mtime: u64, typeflag: u8) -> [u8; TAR_BLOCK as usize] {
+    let mut header = [0; TAR_BLOCK as usize];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header[100..108], 0o644);
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // Summed with the checksum field itself filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    put_octal(&mut header[148..155], u64::from(checksum));
+    header
+}
+
+/// Zero padded octal digits, terminated by a NUL in the last byte of `field`
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let octal = format!("{value:0digits$o}");
+    field[..digits].copy_from_slice(&octal.as_bytes()[octal.len() - digits..]);
+    field[digits] = 0;
+}
+
+fn pad_block(buf: &mut Vec<u8>) {
+    let padding = (TAR_BLOCK - buf.len() as u64 % TAR_BLOCK) % TAR_BLOCK;
+    buf.resize(buf.len() + padding as usize, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{Read, Seek, SeekFrom};
+
+    const GIB: u64 = 1 << 30;
+
+    /// Reads an archive, taking file contents from `contents` and zeros for files not in it,
+    /// so archives with huge files can be parsed without holding them
+    struct ArchiveReader {
+        parts: Vec<(u64, ArchivePart)>,
+        contents: HashMap<String, Vec<u8>>,
+        len: u64,
+        position: u64,
+    }
+
+    impl ArchiveReader {
+        fn new(archive: Archive, contents: HashMap<String, Vec<u8>>) -> Self {
+            let len = archive.len();
+            let mut start = 0;
+            let parts = archive
+                .into_parts()
+                .into_iter()
+                .map(|part| {
+                    let part_start = start;
+                    start += match &part {
+                        ArchivePart::Bytes(bytes) => bytes.len() as u64,
+                        ArchivePart::File { len, .. } => *len,
+                    };
+                    (part_start, part)
+                })
+                .collect();
+            assert_eq!(start, len, "parts add up to the announced length");
+            Self {
+                parts,
+                contents,
+                len,
+                position: 0,
+            }
+        }
+    }
+
+    impl Read for ArchiveReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((start, part)) = self
+                .parts
+                .iter()
+                .rev()
+                .find(|(start, _)| *start <= self.position)
+            else {
+                return Ok(0);
+            };
+            let within = self.position - start;
+            let n = match part {
+                ArchivePart::Bytes(bytes) => {
+                    let rest = &bytes[(within as usize).min(bytes.len())..];
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    n
+                }
+                ArchivePart::File { key, len } => {
+                    let n = (len - within).min(buf.len() as u64) as usize;
+                    match self.contents.get(key) {
+                        Some(content) => {
+                            buf[..n].copy_from_slice(&content[within as usize..within as usize + n])
+                        }
+                        None => buf[..n].fill(0),
+                    }
+                    n
+                }
+            };
+            self.position += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Seek for ArchiveReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => self.len.checked_add_signed(offset).unwrap(),
+                SeekFrom::Current(offset) => self.position.checked_add_signed(offset).unwrap(),
+            };
+            Ok(self.position)
+        }
+    }
+
+    fn file(name: &str, size: u64, crc32: u32) -> ArchiveFile {
+        ArchiveFile {
+            name: name.to_owned(),
+            key: name.to_owned(),
+            size,
+            crc32,
+            modified: UtcDateTime::new(
+                time::Date::from_calendar_date(2024, time::Month::March, 9).unwrap(),
+                time::Time::from_hms(13, 37, 42).unwrap(),
+            ),
+        }
+    }
+
+    fn archive_of(format: ArchiveFormat, files: &[(&str, &[u8])]) -> ArchiveReader {
+        let mut builder = ArchiveBuilder::new(format);
+        for (name, content) in files {
+            builder.add_file(file(name, content.len() as u64, crc32fast::hash(content)));
+        }
+        let contents = files
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_vec()))
+            .collect();
+        ArchiveReader::new(builder.finish(), contents)
+    }
+
+    const LONG_NAME: &str = "a directory with a rather long name/containing another directory/and finally a file named ünïcödé.txt";
+
+    #[test]
+    fn zip_archives_can_be_extracted() {
+        let files: &[(&str, &[u8])] = &[
+            ("hello.txt", b"hello world"),
+            ("empty", b""),
+            (LONG_NAME, &[7; 1000]),
+        ];
+        let mut zip = zip::ZipArchive::new(archive_of(ArchiveFormat::Zip, files)).unwrap();
+        assert_eq!(zip.len(), files.len());
+        for (i, (name, content)) in files.iter().enumerate() {
+            let mut entry = zip.by_index(i).unwrap();
+            assert_eq!(entry.name(), *name);
+            let mut read = vec![];
+            // Fails on a CRC-32 mismatch
+            entry.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *content);
+            assert_eq!(
+                entry
+                    .last_modified()
+                    .map(|m| (m.year(), m.hour(), m.second())),
+                Some((2024, 13, 42))
+            );
+        }
+    }
+
+    #[test]
+    fn tar_archives_can_be_extracted() {
+        let files: &[(&str, &[u8])] = &[
+            ("hello.txt", b"hello world"),
+            ("empty", b""),
+            ("block", &[1; 512]),
+            (LONG_NAME, &[7; 513]),
+        ];
+        let mut tar = tar::Archive::new(archive_of(ArchiveFormat::Tar, files));
+        let mut extracted = vec![];
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut read = vec![];
+            entry.read_to_end(&mut read).unwrap();
+            extracted.push((name, read));
+        }
+        let expected: Vec<_> = files
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_vec()))
+            .collect();
+        assert_eq!(extracted, expected);
+    }
+
+    #[test]
+    fn zip64_starts_at_the_threshold() {
+        let local_extra_len = |size| {
+            let mut builder = ArchiveBuilder::new(ArchiveFormat::Zip);
+            builder.add_file(file("f", size, 0));
+            match &builder.take_parts()[0] {
+                ArchivePart::Bytes(local) => u16::from_le_bytes([local[28], local[29]]),
+                ArchivePart::File { .. } => unreachable!("the local header comes first"),
+            }
+        };
+        assert_eq!(local_extra_len(ZIP64_THRESHOLD - 1), 0);
+        assert_eq!(local_extra_len(ZIP64_THRESHOLD), 20);
+        assert_eq!(local_extra_len(5 * GIB), 20);
+    }
+
+    #[test]
+    fn zip_archives_beyond_4_gib_are_readable() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Zip);
+        builder.add_file(file("large", 5 * GIB, 0));
+        // Its header lies beyond 4 GiB, though its size fits 32 bits
+        builder.add_file(file("small", 11, crc32fast::hash(b"after large")));
+        let archive = builder.finish();
+        assert!(archive.len() > 5 * GIB);
+        let contents = HashMap::from([("small".to_owned(), b"after large".to_vec())]);
+        let mut zip = zip::ZipArchive::new(ArchiveReader::new(archive, contents)).unwrap();
+
+        assert_eq!(zip.by_index(0).unwrap().size(), 5 * GIB);
+        let mut small = zip.by_index(1).unwrap();
+        assert!(small.header_start() > 5 * GIB);
+        let mut read = vec![];
+        small.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"after large");
+    }
+
+    #[test]
+    fn zip_archives_with_many_entries_are_readable() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Zip);
+        for i in 0..0xFFFF {
+            builder.add_file(file(&i.to_string(), 0, 0));
+        }
+        let zip =
+            zip::ZipArchive::new(ArchiveReader::new(builder.finish(), HashMap::new())).unwrap();
+        assert_eq!(zip.len(), 0xFFFF);
+    }
+
+    #[test]
+    fn tar_archives_record_large_sizes_in_pax_headers() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Tar);
+        builder.add_file(file("fits", USTAR_MAX_SIZE, 0));
+        builder.add_file(file("large", USTAR_MAX_SIZE + 1, 0));
+        builder.add_file(file("after", 5, 0));
+        let contents = HashMap::from([("after".to_owned(), b"tail!".to_vec())]);
+        let mut tar = tar::Archive::new(ArchiveReader::new(builder.finish(), contents));
+
+        let mut entries = tar.entries_with_seek().unwrap();
+        let mut next = || entries.next().unwrap().unwrap();
+        let mut fits = next();
+        assert_eq!(fits.path().unwrap().to_str(), Some("fits"));
+        assert_eq!(fits.size(), USTAR_MAX_SIZE);
+        assert!(fits.pax_extensions().unwrap().is_none());
+        let large = next();
+        assert_eq!(large.path().unwrap().to_str(), Some("large"));
+        assert_eq!(large.size(), USTAR_MAX_SIZE + 1);
+        let mut after = next();
+        let mut read = vec![];
+        after.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"tail!");
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        for value_len in [0, 1, 88, 89, 90, 91, 92, 988, 989, 990, 991, 992] {
+            let mut pax = String::new();
+            pax_record(&mut pax, "path", &"x".repeat(value_len));
+            let (len, _) = pax.split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), pax.len(), "{pax:?}");
+        }
+    }
+
+    #[test]
+    fn dos_times_are_clamped_to_their_range() {
+        let at = |year| {
+            dos_date_time(UtcDateTime::new(
+                time::Date::from_calendar_date(year, time::Month::January, 1).unwrap(),
+                time::Time::MIDNIGHT,
+            ))
+        };
+        assert_eq!(at(1970), at(1980));
+        assert_eq!(at(1980), (0, 1 << 5 | 1));
+        assert_eq!(at(2200).1 >> 9, 127);
+    }
+
+    #[test]
+    fn parts_can_be_taken_while_building() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Tar);
+        builder.add_file(file("a", 3, 0));
+        let taken = builder.take_parts();
+        assert_eq!(taken.len(), 3);
+        builder.add_file(file("b", 512, 0));
+        let archive = builder.finish();
+        // Header, contents and padding of `a`, header and contents of `b`, the end
+        assert_eq!(archive.len(), 512 + 512 + 512 + 512 + 1024);
+        assert_eq!(archive.into_parts().len(), 3);
+    }
+}
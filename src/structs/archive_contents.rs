@@ -0,0 +1,245 @@
+use crate::blob_store;
+use crate::configuration::config;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::{FileMeta, validate_entry_path};
+use flate2::read::GzDecoder;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use time::{Date, Month, PrimitiveDateTime, Time, UtcDateTime};
+
+/// Archive formats whose contents can be listed and extracted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Recognized by content type, or by file name when the uploader sent none
+    pub fn detect(meta: &FileMeta) -> Option<Self> {
+        // Multi-file assets are archives built by us, their files are listed already
+        if meta.is_multi_file() {
+            return None;
+        }
+        let name = meta.name().to_ascii_lowercase();
+        let tar_gz = name.ends_with(".tar.gz") || name.ends_with(".tgz");
+        match meta.content_type() {
+            "application/zip" | "application/x-zip-compressed" => Some(Self::Zip),
+            "application/x-tar" => Some(Self::Tar),
+            "application/gzip" | "application/x-gzip" | "application/x-compressed-tar"
+                if tar_gz =>
+            {
+                Some(Self::TarGz)
+            }
+            "application/octet-stream" if name.ends_with(".zip") => Some(Self::Zip),
+            "application/octet-stream" if name.ends_with(".tar") => Some(Self::Tar),
+            "application/octet-stream" if tar_gz => Some(Self::TarGz),
+            _ => None,
+        }
+    }
+}
+
+/// The files within an archive, directories are implied by their paths
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveListing {
+    pub entries: Vec<ContentEntry>,
+    /// The archive holds more files than listed
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<UtcDateTime>,
+}
+
+impl ArchiveListing {
+    pub fn entry(&self, path: &str) -> Option<&ContentEntry> {
+        self.entries.iter().find(|e| e.path == path)
+    }
+}
+
+/// Reads the table of contents of the archive stored at `key`, blocking.
+/// Compressed tar archives are decompressed in full for this
+pub fn list(key: &str, kind: ArchiveKind, archive_size: u64) -> TapferResult<ArchiveListing> {
+    let max_entries = config().archive_browsing.max_entries;
+    let mut listing = ArchiveListing::default();
+    let mut push = |entry: ContentEntry| {
+        if listing.entries.len() < max_entries {
+            listing.entries.push(entry);
+        } else {
+            listing.truncated = true;
+        }
+    };
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(blob_store::blocking_reader(key)?))?;
+            for i in 0..zip.len() {
+                let file = zip.by_index_raw(i)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let Some(entry_path) = entry_path(file.name()) else {
+                    continue;
+                };
+                push(ContentEntry {
+                    path: entry_path,
+                    size: file.size(),
+                    modified: file.last_modified().and_then(zip_time),
+                });
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = tar::Archive::new(tar_reader(key, kind, archive_size)?);
+            for entry in tar.entries().map_err(unreadable)? {
+                let entry = entry.map_err(unreadable)?;
+                let header = entry.header();
+                if !header.entry_type().is_file() {
+                    continue;
+                }
+                let Some(entry_path) = entry_path(&String::from_utf8_lossy(&entry.path_bytes()))
+                else {
+                    continue;
+                };
+                push(ContentEntry {
+                    path: entry_path,
+                    size: entry.size(),
+                    modified: header
+                        .mtime()
+                        .ok()
+                        .and_then(|t| UtcDateTime::from_unix_timestamp(t as i64).ok()),
+                });
+            }
+        }
+    }
+    Ok(listing)
+}
+
+/// Writes `entry` of the archive into `out`, blocking.
+/// At most `entry.size` bytes are written, regardless of what the compressed data expands to
+pub fn extract(
+    key: &str,
+    kind: ArchiveKind,
+    archive_size: u64,
+    entry: &ContentEntry,
+    out: &mut impl Write,
+) -> TapferResult<()> {
+    check_entry_size(entry, archive_size)?;
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(blob_store::blocking_reader(key)?))?;
+            for i in 0..zip.len() {
+                let name = zip.by_index_raw(i)?.name().to_owned();
+                if entry_path(&name).as_deref() == Some(&entry.path) {
+                    let file = zip.by_index(i)?;
+                    io::copy(&mut file.take(entry.size), out)?;
+                    return Ok(());
+                }
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = tar::Archive::new(tar_reader(key, kind, archive_size)?);
+            for file in tar.entries().map_err(unreadable)? {
+                let file = file.map_err(unreadable)?;
+                if file.header().entry_type().is_file()
+                    && entry_path(&String::from_utf8_lossy(&file.path_bytes())).as_deref()
+                        == Some(&entry.path)
+                {
+                    io::copy(&mut file.take(entry.size), out)?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Err(TapferError::ArchiveContents(format!(
+        "{:?} vanished from the archive",
+        entry.path
+    )))
+}
+
+/// Refuses entries larger than configured, or expanding beyond the compression ratio
+pub fn check_entry_size(entry: &ContentEntry, archive_size: u64) -> TapferResult<()> {
+    let settings = &config().archive_browsing;
+    let limit = settings
+        .max_entry_size
+        .bytes()
+        .min(archive_size.saturating_mul(settings.max_compression_ratio));
+    if entry.size > limit {
+        return Err(TapferError::ArchiveContents(format!(
+            "{:?} is too large to be extracted on its own, download the whole archive instead",
+            entry.path
+        )));
+    }
+    Ok(())
+}
+
+/// Decompressed tar archives are cut off at the compression ratio
+fn tar_reader(key: &str, kind: ArchiveKind, archive_size: u64) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(blob_store::blocking_reader(key)?);
+    Ok(match kind {
+        ArchiveKind::TarGz => {
+            let limit =
+                archive_size.saturating_mul(config().archive_browsing.max_compression_ratio);
+            Box::new(DecompressionLimit {
+                inner: GzDecoder::new(file),
+                remaining: limit,
+            })
+        }
+        _ => Box::new(file),
+    })
+}
+
+/// Tar errors are plain I/O errors, but mostly describe the archive rather than the disk
+fn unreadable(e: io::Error) -> TapferError {
+    TapferError::ArchiveContents(e.to_string())
+}
+
+/// Fails reads beyond `remaining` bytes, unlike [`Read::take`] which would end the data silently
+struct DecompressionLimit<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for DecompressionLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        if max == 0 && !buf.is_empty() {
+            // At the limit, one more byte tells whether the data ends here
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(io::Error::other(
+                    "the archive decompresses beyond the permitted size",
+                )),
+            };
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Paths as listed and served. Entries that would escape the archive are left out
+fn entry_path(raw: &str) -> Option<String> {
+    let mut path = raw;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    let path = path.trim_start_matches('/');
+    validate_entry_path(path).ok()?;
+    Some(path.to_owned())
+}
+
+fn zip_time(time: zip::DateTime) -> Option<UtcDateTime> {
+    let date = Date::from_calendar_date(
+        i32::from(time.year()),
+        Month::try_from(time.month()).ok()?,
+        time.day(),
+    )
+    .ok()?;
+    let time = Time::from_hms(time.hour(), time.minute(), time.second()).ok()?;
+    Some(PrimitiveDateTime::new(date, time).as_utc())
+}
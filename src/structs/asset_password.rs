@@ -0,0 +1,213 @@
+use crate::configuration::config;
+use crate::structs::error::{TapferError, TapferResult};
+use crate::structs::file_meta::FileMeta;
+use crate::structs::tapfer_id::TapferId;
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::extract::{FromRequestParts, Query};
+use axum::http::header;
+use axum::http::request::Parts;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::sync::LazyLock;
+use std::time::Instant;
+use time::UtcDateTime;
+use tokio::task;
+use uuid::Uuid;
+
+/// Request header carrying the password, for clients that cannot keep cookies
+pub const PASSWORD_HEADER: &str = "tapfer-password";
+
+/// Passwords are read into memory in full before hashing
+pub const MAX_PASSWORD_LEN: usize = 1024;
+
+/// Signs unlock tokens. Regenerated on every start, which logs out all browsers
+static UNLOCK_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    key
+});
+
+static FAILED_ATTEMPTS: LazyLock<DashMap<TapferId, FailedAttempts>> = LazyLock::new(DashMap::new);
+
+struct FailedAttempts {
+    count: u32,
+    last: Instant,
+}
+
+/// Produces an Argon2id PHC string. Slow on purpose, so it runs on the blocking pool
+pub async fn hash_password(password: String) -> TapferResult<String> {
+    task::spawn_blocking(move || {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())?;
+        Ok(Argon2::default()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    })
+    .await
+    .map_err(|e| TapferError::StdIo(e.into()))?
+}
+
+/// Checks `password` against the asset, counting wrong guesses towards its lockout
+pub async fn check_password(id: TapferId, meta: &FileMeta, password: &str) -> TapferResult<()> {
+    let Some(stored) = meta.password_hash() else {
+        return Ok(());
+    };
+
+    // Counted before verifying, so parallel guesses cannot slip past the limit
+    {
+        let settings = &config().passwords;
+        let mut attempts = FAILED_ATTEMPTS.entry(id).or_insert(FailedAttempts {
+            count: 0,
+            last: Instant::now(),
+        });
+        if attempts.count >= settings.max_failed_attempts
+            && let Some(remaining) = settings
+                .lockout
+                .unsigned_abs()
+                .checked_sub(attempts.last.elapsed())
+        {
+            return Err(TapferError::PasswordThrottled(remaining));
+        }
+        attempts.count += 1;
+        attempts.last = Instant::now();
+    }
+
+    let (stored, password) = (stored.to_owned(), password.to_owned());
+    let matches = task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&stored)?;
+        Ok::<_, argon2::password_hash::Error>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+        )
+    })
+    .await
+    .map_err(|e| TapferError::StdIo(e.into()))??;
+
+    if matches {
+        FAILED_ATTEMPTS.remove(&id);
+        Ok(())
+    } else {
+        Err(TapferError::WrongPassword)
+    }
+}
+
+/// Drops the throttling state of a deleted asset
+pub fn forget_attempts(id: TapferId) {
+    FAILED_ATTEMPTS.remove(&id);
+}
+
+/// Name of the cookie holding the unlock token of `id`
+pub fn unlock_cookie_name(id: TapferId) -> String {
+    format!("tapfer_unlock_{id}")
+}
+
+/// `<unix expiry>.<hex HMAC>` over the asset and its password hash, so changing the password revokes it
+pub fn unlock_token(id: TapferId, meta: &FileMeta) -> String {
+    let expires = (UtcDateTime::now() + config().passwords.unlock_duration).unix_timestamp();
+    let mac = unlock_mac(id, expires, meta.password_hash().unwrap_or_default());
+    format!(
+        "{expires}.{}",
+        base16ct::lower::encode_string(&mac.finalize().into_bytes())
+    )
+}
+
+fn verify_unlock_token(id: TapferId, meta: &FileMeta, token: &str) -> bool {
+    let Some((expires, signature)) = token.split_once('.') else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<i64>() else {
+        return false;
+    };
+    let Ok(signature) = base16ct::lower::decode_vec(signature) else {
+        return false;
+    };
+    expires > UtcDateTime::now().unix_timestamp()
+        && unlock_mac(id, expires, meta.password_hash().unwrap_or_default())
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+fn unlock_mac(id: TapferId, expires: i64, password_hash: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(UNLOCK_KEY.as_slice()).expect("HMAC takes keys of any size");
+    mac.update(id.to_string().as_bytes());
+    mac.update(&expires.to_be_bytes());
+    mac.update(password_hash.as_bytes());
+    mac
+}
+
+#[derive(serde::Deserialize)]
+struct UnlockQuery {
+    unlock: Option<String>,
+}
+
+/// Everything a request may present to access a password protected asset:
+/// the unlock cookie set by the password prompt, an `unlock` query parameter for links to another host,
+/// or the password itself in the `tapfer-password` header
+#[derive(Debug, Clone, Default)]
+pub struct AssetCredentials {
+    cookies: Vec<String>,
+    query_token: Option<String>,
+    password: Option<String>,
+}
+
+impl AssetCredentials {
+    /// Passes unprotected assets, otherwise requires a valid unlock token or the correct password
+    pub async fn authorize(&self, id: TapferId, meta: &FileMeta) -> TapferResult<()> {
+        if meta.password_hash().is_none() {
+            return Ok(());
+        }
+
+        let cookie_name = unlock_cookie_name(id);
+        let cookie_token = self
+            .cookies
+            .iter()
+            .flat_map(|c| c.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| *name == cookie_name)
+            .map(|(_, value)| value);
+        if cookie_token
+            .into_iter()
+            .chain(self.query_token.as_deref())
+            .any(|token| verify_unlock_token(id, meta, token))
+        {
+            return Ok(());
+        }
+
+        match &self.password {
+            Some(password) => check_password(id, meta, password).await,
+            None => Err(TapferError::AssetLocked),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AssetCredentials {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let cookies = parts
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(ToOwned::to_owned)
+            .collect();
+        let query_token = Query::<UnlockQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|q| q.0.unlock);
+        let password = parts
+            .headers
+            .get(PASSWORD_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+        Ok(Self {
+            cookies,
+            query_token,
+            password,
+        })
+    }
+}
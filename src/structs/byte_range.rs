@@ -0,0 +1,234 @@
+use std::ops::Range;
+
+/// More ranges than this are answered with the whole asset, as RFC 9110 permits
+const MAX_RANGES: usize = 32;
+
+/// A satisfiable range of a `Range: bytes=` header, resolved against the asset size
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    /// Inclusive, like in the header
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Value of the `Content-Range` header for this range
+    pub fn content_range(self, total: u64) -> String {
+        format!("bytes {}-{}/{total}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RangeRequest {
+    /// No usable `Range` header, the whole asset is sent
+    Full,
+    Partial(Vec<ByteRange>),
+    /// Every range starts beyond the end of the asset
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header as described in RFC 9110 section 14.1.2.
+/// Malformed headers and units other than bytes are ignored
+pub fn parse_range(header: &str, total: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = vec![];
+    let mut any_spec = false;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        any_spec = true;
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            // Suffix range, the last `n` bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix > 0 && total > 0 {
+                ranges.push(ByteRange {
+                    start: total.saturating_sub(suffix),
+                    end: total - 1,
+                });
+            }
+            continue;
+        }
+
+        let Ok(start) = first.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = if last.is_empty() {
+            u64::MAX
+        } else {
+            match last.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return RangeRequest::Full,
+            }
+        };
+        if start < total {
+            ranges.push(ByteRange {
+                start,
+                end: end.min(total - 1),
+            });
+        }
+    }
+
+    match ranges.len() {
+        _ if !any_spec => RangeRequest::Full,
+        0 => RangeRequest::Unsatisfiable,
+        n if n > MAX_RANGES => RangeRequest::Full,
+        _ => RangeRequest::Partial(ranges),
+    }
+}
+
+/// Union of half-open byte intervals, kept sorted and merged
+#[derive(Debug, Clone, Default)]
+pub struct RangeSet(Vec<Range<u64>>);
+
+impl RangeSet {
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        self.0.push(range);
+        self.0.sort_unstable_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.0.len());
+        for r in self.0.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        self.0 = merged;
+    }
+
+    pub fn extend(&mut self, other: &RangeSet) {
+        for r in &other.0 {
+            self.insert(r.clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether every byte of an asset of `total` bytes is contained
+    pub fn covers(&self, total: u64) -> bool {
+        total == 0
+            || self
+                .0
+                .first()
+                .is_some_and(|r| r.start == 0 && r.end >= total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(&[(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), partial(&[(900, 999)]));
+        assert_eq!(parse_range("bytes=999-999", 1000), partial(&[(999, 999)]));
+        // Ends beyond the asset are cut to its size
+        assert_eq!(parse_range("bytes=500-5000", 1000), partial(&[(500, 999)]));
+        assert_eq!(parse_range(" bytes= 0 - 9 ", 1000), partial(&[(0, 9)]));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), partial(&[(900, 999)]));
+        // Longer than the asset, the whole asset
+        assert_eq!(parse_range("bytes=-5000", 1000), partial(&[(0, 999)]));
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn keeps_several_and_overlapping_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9, 20-29,-5", 100),
+            partial(&[(0, 9), (20, 29), (95, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-50,25-75", 100),
+            partial(&[(0, 50), (25, 75)])
+        );
+        // Unsatisfiable ones are dropped as long as one remains
+        assert_eq!(parse_range("bytes=0-9,200-300", 100), partial(&[(0, 9)]));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=1000-2000", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        for header in [
+            "items=0-9",
+            "bytes=",
+            "bytes=,",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=0-9,x",
+            "bytes=5",
+            "bytes=--5",
+        ] {
+            assert_eq!(parse_range(header, 1000), RangeRequest::Full, "{header}");
+        }
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{i}-{i}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            parse_range(&format!("bytes={many}"), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn merges_ranges_into_coverage() {
+        let mut set = RangeSet::default();
+        assert!(!set.covers(10));
+        assert!(set.covers(0));
+        set.insert(5..10);
+        set.insert(0..0);
+        assert!(!set.covers(10));
+        set.insert(0..3);
+        assert!(!set.covers(10));
+        // Touching and overlapping ranges merge
+        set.insert(2..5);
+        assert!(set.covers(10));
+        assert!(!set.covers(11));
+
+        let mut other = RangeSet::default();
+        other.insert(10..20);
+        set.extend(&other);
+        assert!(set.covers(20));
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// A size in bytes, parsed from either a plain integer or a string with a binary suffix such as `100G`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// Saturates on 32-bit targets, where sizes beyond `usize::MAX` cannot be buffered anyway
+    pub fn as_usize(self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (digits, suffix) = s.split_at(split);
+        let value: u64 = digits
+            .parse()
+            .map_err(|_| format!("invalid size {s:?}, expected e.g. `512K` or `100G`"))?;
+        // Same binary multiples as the `size!` macro
+        let multiplier: u64 = match suffix.trim().trim_end_matches(['B', 'b', 'i']) {
+            "" => 1,
+            "K" | "k" => 1 << 10,
+            "M" | "m" => 1 << 20,
+            "G" | "g" => 1 << 30,
+            "T" | "t" => 1 << 40,
+            other => return Err(format!("unknown size suffix {other:?} in {s:?}")),
+        };
+        value
+            .checked_mul(multiplier)
+            .map(Self)
+            .ok_or_else(|| format!("size {s:?} is too large"))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}B", self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(u64),
+            Str(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Int(i) => Ok(Self(i)),
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binary_suffixes() {
+        assert_eq!(ByteSize::from_str("512"), Ok(ByteSize(512)));
+        assert_eq!(ByteSize::from_str("512K"), Ok(ByteSize(512 << 10)));
+        assert_eq!(ByteSize::from_str(" 100 G "), Ok(ByteSize(100 << 30)));
+        assert_eq!(ByteSize::from_str("2MiB"), Ok(ByteSize(2 << 20)));
+        assert_eq!(ByteSize::from_str("1tb"), Ok(ByteSize(1 << 40)));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(ByteSize::from_str("").is_err());
+        assert!(ByteSize::from_str("G").is_err());
+        assert!(ByteSize::from_str("10X").is_err());
+        assert!(ByteSize::from_str("1.5G").is_err());
+        assert!(ByteSize::from_str("-1").is_err());
+        assert!(ByteSize::from_str("99999999999T").is_err());
+    }
+
+    #[test]
+    fn deserializes_integers_and_strings() {
+        #[derive(serde::Deserialize)]
+        struct T {
+            a: ByteSize,
+            b: ByteSize,
+        }
+        let t: T = toml::from_str("a = 1024\nb = \"1K\"").unwrap();
+        assert_eq!(t.a, t.b);
+        assert!(toml::from_str::<T>("a = -1\nb = 1").is_err());
+    }
+}
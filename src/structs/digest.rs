@@ -0,0 +1,216 @@
+use crate::structs::error::{TapferError, TapferResult};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use http::HeaderName;
+use sha2::Digest;
+use std::io;
+use std::str::FromStr;
+
+pub const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+pub const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+pub const WANT_REPR_DIGEST: HeaderName = HeaderName::from_static("want-repr-digest");
+
+/// Hash functions asset checksums are offered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+    /// Broken, only for tools that know nothing else
+    Md5,
+}
+
+impl DigestAlgorithm {
+    pub const ALL: [Self; 4] = [Self::Sha256, Self::Sha512, Self::Blake3, Self::Md5];
+
+    /// Computed while uploads stream, the others on first request
+    pub const STREAMED: [Self; 2] = [Self::Sha256, Self::Sha512];
+
+    /// As in `checksum.<name>`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+            Self::Md5 => "md5",
+        }
+    }
+
+    /// File kept next to the payload of an asset, holding its checksum
+    pub fn file_name(self) -> String {
+        format!("checksum.{}", self.name())
+    }
+
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        name.strip_prefix("checksum.")?.parse().ok()
+    }
+
+    /// Named after the files the coreutils and b3sum tools check
+    pub fn sums_file(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Sha512 => "SHA512SUMS",
+            Self::Blake3 => "B3SUMS",
+            Self::Md5 => "MD5SUMS",
+        }
+    }
+
+    /// Key in RFC 9530 digest fields, only for algorithms registered as secure
+    pub fn field_key(self) -> Option<&'static str> {
+        match self {
+            Self::Sha256 => Some("sha-256"),
+            Self::Sha512 => Some("sha-512"),
+            Self::Blake3 | Self::Md5 => None,
+        }
+    }
+
+    pub fn from_sums_file(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.sums_file() == name)
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            Self::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Self::Md5 => Hasher::Md5(md5::Md5::new()),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.name() == s)
+            .ok_or_else(|| format!("unknown digest algorithm {s:?}"))
+    }
+}
+
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Blake3(Box<blake3::Hasher>),
+    Md5(md5::Md5),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+            Self::Md5(h) => h.update(data),
+        }
+    }
+
+    /// Lowercase hex, as written to checksum files
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(h) => base16ct::lower::encode_string(&h.finalize()),
+            Self::Sha512(h) => base16ct::lower::encode_string(&h.finalize()),
+            Self::Blake3(h) => h.finalize().to_hex().to_string(),
+            Self::Md5(h) => base16ct::lower::encode_string(&h.finalize()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hex digests of the same bytes, by algorithm
+pub type Checksums = Vec<(DigestAlgorithm, String)>;
+
+/// Several digests of the same bytes at once
+pub struct MultiHasher(Vec<(DigestAlgorithm, Hasher)>);
+
+impl MultiHasher {
+    pub fn new(algorithms: &[DigestAlgorithm]) -> Self {
+        Self(algorithms.iter().map(|a| (*a, a.hasher())).collect())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, h) in &mut self.0 {
+            h.update(data);
+        }
+    }
+
+    pub fn finalize(self) -> Checksums {
+        self.0.into_iter().map(|(a, h)| (a, h.finalize())).collect()
+    }
+}
+
+/// `Repr-Digest` member for a hex digest
+pub fn field_member(algorithm: DigestAlgorithm, hex: &str) -> Option<String> {
+    let key = algorithm.field_key()?;
+    let bytes = base16ct::lower::decode_vec(hex).ok()?;
+    Some(format!("{key}=:{}:", BASE64_STANDARD.encode(bytes)))
+}
+
+/// Algorithms a `Want-Repr-Digest` field asks for, most preferred first.
+/// Unknown keys and those with a preference of 0 are left out
+pub fn wanted(field: &str) -> Vec<DigestAlgorithm> {
+    let mut wanted: Vec<(u8, DigestAlgorithm)> = field
+        .split(',')
+        .filter_map(|member| {
+            let (key, preference) = member.split_once('=')?;
+            let preference = preference.split(';').next()?.trim().parse().ok()?;
+            let key = key.trim();
+            let algorithm = DigestAlgorithm::ALL
+                .into_iter()
+                .find(|a| a.field_key() == Some(key))?;
+            (preference > 0).then_some((preference, algorithm))
+        })
+        .collect();
+    wanted.sort_by_key(|(preference, _)| std::cmp::Reverse(*preference));
+    wanted.into_iter().map(|(_, a)| a).collect()
+}
+
+/// Hex digests of a `Content-Digest` or `Repr-Digest` field, in the algorithms with a registered key.
+/// Malformed members are left out
+pub fn parse_field(field: &str) -> Checksums {
+    field
+        .split(',')
+        .filter_map(|member| {
+            let (key, value) = member.split_once('=')?;
+            let key = key.trim();
+            let algorithm = DigestAlgorithm::ALL
+                .into_iter()
+                .find(|a| a.field_key() == Some(key))?;
+            let value = value.split(';').next()?.trim();
+            let bytes = BASE64_STANDARD
+                .decode(value.strip_prefix(':')?.strip_suffix(':')?)
+                .ok()?;
+            Some((algorithm, base16ct::lower::encode_string(&bytes)))
+        })
+        .collect()
+}
+
+/// Fails unless every expected digest matches the computed one of the same algorithm
+pub fn verify(expected: &Checksums, computed: &Checksums) -> TapferResult<()> {
+    for (algorithm, expected) in expected {
+        let Some((_, computed)) = computed.iter().find(|(a, _)| a == algorithm) else {
+            continue;
+        };
+        if computed != expected {
+            return Err(TapferError::DigestMismatch(format!(
+                "the {} of the upload is {computed}, not the declared {expected}",
+                algorithm.name()
+            )));
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,8 @@
+use crate::structs::asset_password::MAX_PASSWORD_LEN;
+use crate::structs::tapfer_id::TapferId;
+use crate::updown::resumable::{TUS_RESUMABLE, TUS_VERSION};
 use crate::updown::upload_pool::UploadFsm;
-use axum::extract::multipart::MultipartError;
+use axum::extract::multipart::{MultipartError, MultipartRejection};
 use axum::response::{Html, IntoResponse, Response};
 use http::StatusCode;
 use http::header::{InvalidHeaderValue, ToStrError};
@@ -42,6 +45,84 @@ pub enum TapferError {
     #[error("Invalid expiration {0}")]
     InvalidExpiration(String),
 
+    #[error("Missing management token")]
+    MissingManagementToken,
+
+    #[error("Invalid management token")]
+    InvalidManagementToken,
+
+    #[error("Missing admin token")]
+    MissingAdminToken,
+
+    #[error("Invalid admin token")]
+    InvalidAdminToken,
+
+    #[error("The admin API is disabled")]
+    AdminDisabled,
+
+    #[error("Invalid removal policy: {0}")]
+    InvalidRemovalPolicy(String),
+
+    #[error("Invalid asset modification: {0}")]
+    InvalidAssetPatch(String),
+
+    #[error("Invalid file name: {0}")]
+    InvalidFileName(String),
+
+    #[error("File name {0:?} is already taken")]
+    FileNameTaken(String),
+
+    #[error("Upload contains more than {0} files")]
+    TooManyFiles(usize),
+
+    #[error("Asset is still uploading")]
+    AssetInProgress,
+
+    #[error("Archive of an asset that is still uploading")]
+    ArchiveInProgress,
+
+    #[error("Cannot read archive: {0}")]
+    ArchiveContents(String),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+
+    #[error("Invalid digest: {0}")]
+    InvalidDigest(String),
+
+    #[error("Upload size mismatch: {0}")]
+    UploadSizeMismatch(String),
+
+    #[error("Digest mismatch: {0}")]
+    DigestMismatch(String),
+
+    #[error("Bundled asset {0} is still uploading")]
+    BundleInProgress(TapferId),
+
+    #[error("Asset is password protected")]
+    AssetLocked,
+
+    #[error("Wrong password")]
+    WrongPassword,
+
+    #[error("Too many wrong passwords, retry in {0:?}")]
+    PasswordThrottled(std::time::Duration),
+
+    #[error("Password is not valid UTF-8 or too long")]
+    InvalidPassword,
+
+    #[error("tus protocol error {status_code}: {message}")]
+    TusProtocol {
+        status_code: StatusCode,
+        message: String,
+    },
+
+    #[error("invalid configuration:\n{}", .0.join("\n"))]
+    InvalidConfiguration(Vec<String>),
+
     #[error(transparent)]
     StdIo(#[from] io::Error),
 
@@ -63,6 +144,12 @@ pub enum TapferError {
     #[error(transparent)]
     AxumMultipart(#[from] MultipartError),
 
+    #[error(transparent)]
+    MultipartRejection(#[from] MultipartRejection),
+
+    #[error(transparent)]
+    AxumBody(#[from] axum::Error),
+
     #[error(transparent)]
     ParseIntError(#[from] ParseIntError),
 
@@ -80,6 +167,12 @@ pub enum TapferError {
 
     #[error(transparent)]
     Http(#[from] http::Error),
+
+    #[error(transparent)]
+    PasswordHash(#[from] argon2::password_hash::Error),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
 }
 
 impl IntoResponse for TapferError {
@@ -94,7 +187,11 @@ impl IntoResponse for TapferError {
         use axum::response::Html;
         use http::StatusCode;
         match self {
-            BadMultipartOrder => generic("multipart order"),
+            BadMultipartOrder => (
+                StatusCode::BAD_REQUEST,
+                "Form fields such as the password must precede the file\n",
+            )
+                .into_response(),
             UnknownMultipartField { .. } => generic("unknown multipart field"),
             MultipartFieldNameMissing => generic("multipart field name missing"),
             Custom { status_code, body } => (status_code, body).into_response(),
@@ -105,6 +202,12 @@ impl IntoResponse for TapferError {
             TomlSerialize(_) => generic("toml serialization"),
             InvalidHeader(_) => generic("invalid header"),
             AxumMultipart(_) => generic("axum multipart"),
+            MultipartRejection(rejection) => rejection.into_response(),
+            AxumBody(_) => (
+                StatusCode::BAD_REQUEST,
+                "The request body was interrupted or malformed\n",
+            )
+                .into_response(),
             ParseIntError(_) => generic("parse int error"),
             ToStrError(_) => generic("to str error"),
             AddSizeToAlreadyKnown => generic("add size to already known"),
@@ -114,7 +217,116 @@ impl IntoResponse for TapferError {
             )
                 .into_response(),
             QRCodeError(_) => generic("qr code generation"),
-            InvalidExpiration(s) => generic(&format!("invalid expiration: {s}")),
+            InvalidExpiration(s) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid expiration {s:?}. Expected `single_download`, a positive duration such as `30m`, `7d` or `PT6H`, or a future RFC 3339 timestamp\n"),
+            )
+                .into_response(),
+            MissingManagementToken => (
+                StatusCode::UNAUTHORIZED,
+                [(http::header::WWW_AUTHENTICATE, "Bearer")],
+                "This operation requires the management token handed out on upload\n",
+            )
+                .into_response(),
+            InvalidManagementToken => (
+                StatusCode::FORBIDDEN,
+                "The management token does not match this asset\n",
+            )
+                .into_response(),
+            MissingAdminToken => (
+                StatusCode::UNAUTHORIZED,
+                [(http::header::WWW_AUTHENTICATE, "Bearer")],
+                "This operation requires the admin token\n",
+            )
+                .into_response(),
+            InvalidAdminToken => (StatusCode::FORBIDDEN, "Invalid admin token\n").into_response(),
+            AdminDisabled => (
+                StatusCode::NOT_FOUND,
+                "The admin API is disabled, it is enabled by setting admin.token\n",
+            )
+                .into_response(),
+            InvalidRemovalPolicy(s) => (StatusCode::BAD_REQUEST, format!("{s}\n")).into_response(),
+            InvalidAssetPatch(s) => (StatusCode::BAD_REQUEST, format!("{s}\n")).into_response(),
+            InvalidFileName(s) => (StatusCode::BAD_REQUEST, format!("{s}\n")).into_response(),
+            TooManyFiles(max) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("An upload may contain at most {max} files\n"),
+            )
+                .into_response(),
+            FileNameTaken(name) => (
+                StatusCode::CONFLICT,
+                format!("The asset already contains a file named {name:?}\n"),
+            )
+                .into_response(),
+            AssetInProgress => (
+                StatusCode::CONFLICT,
+                "The asset cannot be modified until its upload has completed\n",
+            )
+                .into_response(),
+            ArchiveInProgress => (
+                StatusCode::CONFLICT,
+                "The archive of all files is available once the upload has completed. Files that were fully received can be downloaded individually\n",
+            )
+                .into_response(),
+            ArchiveContents(s) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Cannot read archive: {s}\n"),
+            )
+                .into_response(),
+            Zip(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Cannot read archive: {e}\n"),
+            )
+                .into_response(),
+            InvalidBundle(s) => (StatusCode::BAD_REQUEST, format!("Invalid bundle: {s}\n")).into_response(),
+            InvalidDigest(s) => (StatusCode::BAD_REQUEST, format!("Invalid digest: {s}\n")).into_response(),
+            UploadSizeMismatch(s) => (
+                StatusCode::BAD_REQUEST,
+                format!("The upload was discarded, it {s}\n"),
+            )
+                .into_response(),
+            DigestMismatch(s) => (
+                StatusCode::BAD_REQUEST,
+                format!("The upload was discarded, {s}\n"),
+            )
+                .into_response(),
+            BundleInProgress(id) => (
+                StatusCode::CONFLICT,
+                format!("Asset {id} is still uploading and can be bundled once it has completed\n"),
+            )
+                .into_response(),
+            AssetLocked => (
+                StatusCode::UNAUTHORIZED,
+                "This asset is password protected. Send the password in the `tapfer-password` header\n",
+            )
+                .into_response(),
+            WrongPassword => (StatusCode::FORBIDDEN, "Wrong password\n").into_response(),
+            PasswordThrottled(remaining) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(
+                    http::header::RETRY_AFTER,
+                    remaining.as_secs().max(1).to_string(),
+                )],
+                "Too many wrong passwords, try again later\n",
+            )
+                .into_response(),
+            InvalidPassword => (
+                StatusCode::BAD_REQUEST,
+                format!("Passwords must be UTF-8 and at most {MAX_PASSWORD_LEN} bytes long\n"),
+            )
+                .into_response(),
+            TusProtocol {
+                status_code,
+                message,
+            } => (
+                status_code,
+                [(TUS_RESUMABLE, TUS_VERSION), ("tus-version", TUS_VERSION)],
+                format!("{message}\n"),
+            )
+                .into_response(),
+            PasswordHash(_) => generic("password hash"),
+            Sqlite(_) => generic("sqlite"),
+            InvalidConfiguration(_) => generic("invalid configuration"),
             TimeFormat(_) => generic("time format"),
             UploadHandleSize(_) => generic("upload handle size"),
             TryFromSlice(_) => generic("try from slice"),
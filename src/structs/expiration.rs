@@ -0,0 +1,362 @@
+use crate::configuration::ExpirationSettings;
+use crate::structs::file_meta::RemovalPolicy;
+use crate::structs::human_duration::HumanDuration;
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime, UtcDateTime};
+
+/// An expiration as requested by a client, before it is clamped into a [`RemovalPolicy`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Expiration {
+    SingleDownload,
+    /// Relative to the upload, e.g. `30m`, `7d` or `PT6H`
+    After(Duration),
+    /// An RFC 3339 timestamp such as `2025-01-31T18:00:00+01:00`
+    At(UtcDateTime),
+}
+
+impl FromStr for Expiration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "single_download" => Ok(Self::SingleDownload),
+            // Kept for clients built against the original API
+            "24_hours" => Ok(Self::After(Duration::hours(24))),
+            s => {
+                if let Ok(at) = OffsetDateTime::parse(s, &Rfc3339) {
+                    let at = at.to_utc();
+                    if at <= UtcDateTime::now() {
+                        return Err(format!("expiration {s:?} must be in the future"));
+                    }
+                    return Ok(Self::At(at));
+                }
+                let after = HumanDuration::from_str(s)?.duration();
+                if !after.is_positive() {
+                    return Err(format!("expiration {s:?} must be in the future"));
+                }
+                Ok(Self::After(after))
+            }
+        }
+    }
+}
+
+impl Expiration {
+    /// Converts to a policy for an asset created at `now`, clamping the lifetime to the configured bounds
+    pub fn into_policy(self, now: UtcDateTime, bounds: &ExpirationSettings) -> RemovalPolicy {
+        let after = match self {
+            Expiration::SingleDownload => return RemovalPolicy::SingleDownload,
+            Expiration::After(after) => after,
+            Expiration::At(at) => at - now,
+        };
+        RemovalPolicy::Expiry {
+            after: clamp_lifetime(after, bounds),
+        }
+    }
+}
+
+/// Combines the `expiration` and `max_downloads` upload parameters.
+/// Without either the asset is removed after a single download
+pub fn removal_policy(
+    expiration: Option<Expiration>,
+    max_downloads: Option<u32>,
+    now: UtcDateTime,
+    bounds: &ExpirationSettings,
+) -> Result<RemovalPolicy, String> {
+    let policy = expiration.map(|e| e.into_policy(now, bounds));
+    Ok(match (policy, max_downloads) {
+        (_, Some(0)) => return Err("max_downloads must be at least 1".to_owned()),
+        (Some(RemovalPolicy::SingleDownload), Some(_)) => {
+            return Err("single_download cannot be combined with max_downloads".to_owned());
+        }
+        (None, Some(n)) => RemovalPolicy::MaxDownloads { n },
+        (Some(policy), Some(n)) => match policy.lifetime() {
+            Some(after) => RemovalPolicy::MaxDownloadsOrExpiry { n, after },
+            None => RemovalPolicy::MaxDownloads { n },
+        },
+        (Some(policy), None) => policy,
+        (None, None) => RemovalPolicy::SingleDownload,
+    })
+}
+
+/// Changes an existing asset's policy. Omitted parameters keep their current value,
+/// `max_downloads: Some(None)` lifts the download limit.
+/// New lifetimes start now and are clamped like on upload, but stored relative to `created`.
+/// They end no later than `maximum_age` after `created`, when retention deletes the asset regardless
+pub fn patch_removal_policy(
+    current: RemovalPolicy,
+    created: UtcDateTime,
+    now: UtcDateTime,
+    expiration: Option<Expiration>,
+    max_downloads: Option<Option<u32>>,
+    bounds: &ExpirationSettings,
+    maximum_age: Duration,
+) -> Result<RemovalPolicy, String> {
+    let (mut single, mut lifetime, mut limit) = match current {
+        RemovalPolicy::SingleDownload => (true, None, None),
+        RemovalPolicy::Expiry { after } => (false, Some(after), None),
+        RemovalPolicy::MaxDownloads { n } => (false, None, Some(n)),
+        RemovalPolicy::MaxDownloadsOrExpiry { n, after } => (false, Some(after), Some(n)),
+    };
+
+    let elapsed = now - created;
+    match expiration {
+        Some(Expiration::SingleDownload) => {
+            if matches!(max_downloads, Some(Some(_))) {
+                return Err("single_download cannot be combined with max_downloads".to_owned());
+            }
+            (single, lifetime, limit) = (true, None, None);
+        }
+        Some(Expiration::After(after)) => {
+            single = false;
+            lifetime = Some((elapsed + clamp_lifetime(after, bounds)).min(maximum_age));
+        }
+        Some(Expiration::At(at)) => {
+            single = false;
+            lifetime = Some((elapsed + clamp_lifetime(at - now, bounds)).min(maximum_age));
+        }
+        None => {}
+    }
+    match max_downloads {
+        Some(Some(0)) => return Err("max_downloads must be at least 1".to_owned()),
+        Some(Some(n)) => {
+            single = false;
+            limit = Some(n);
+        }
+        Some(None) => limit = None,
+        None => {}
+    }
+
+    match (single, lifetime, limit) {
+        (true, _, _) => Ok(RemovalPolicy::SingleDownload),
+        (false, Some(after), Some(n)) => Ok(RemovalPolicy::MaxDownloadsOrExpiry { n, after }),
+        (false, Some(after), None) => Ok(RemovalPolicy::Expiry { after }),
+        (false, None, Some(n)) => Ok(RemovalPolicy::MaxDownloads { n }),
+        (false, None, None) => {
+            Err("an asset needs an expiration, a download limit or both".to_owned())
+        }
+    }
+}
+
+/// Clamps a lifetime to `expiration.minimum..=expiration.maximum`
+pub fn clamp_lifetime(after: Duration, bounds: &ExpirationSettings) -> Duration {
+    after.clamp(bounds.minimum, bounds.maximum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> ExpirationSettings {
+        ExpirationSettings {
+            minimum: Duration::minutes(5),
+            maximum: Duration::hours(24),
+            presets: vec![],
+        }
+    }
+
+    fn patch(
+        current: RemovalPolicy,
+        elapsed: Duration,
+        expiration: Option<Expiration>,
+        max_downloads: Option<Option<u32>>,
+    ) -> Result<RemovalPolicy, String> {
+        let created = UtcDateTime::UNIX_EPOCH;
+        patch_removal_policy(
+            current,
+            created,
+            created + elapsed,
+            expiration,
+            max_downloads,
+            &bounds(),
+            Duration::hours(48),
+        )
+    }
+
+    #[test]
+    fn parses_expirations() {
+        assert_eq!(
+            Expiration::from_str("single_download"),
+            Ok(Expiration::SingleDownload)
+        );
+        assert_eq!(
+            Expiration::from_str("24_hours"),
+            Ok(Expiration::After(Duration::hours(24)))
+        );
+        assert_eq!(
+            Expiration::from_str("1d12h"),
+            Ok(Expiration::After(Duration::hours(36)))
+        );
+        assert!(Expiration::from_str("0s").is_err());
+        assert!(Expiration::from_str("2000-01-01T00:00:00Z").is_err());
+        assert!(Expiration::from_str("2999-01-01T00:00:00+01:00").is_ok());
+    }
+
+    #[test]
+    fn clamps_upload_lifetimes() {
+        let now = UtcDateTime::UNIX_EPOCH;
+        let policy = |e| Expiration::into_policy(e, now, &bounds());
+        assert_eq!(
+            policy(Expiration::After(Duration::seconds(1))),
+            RemovalPolicy::Expiry {
+                after: Duration::minutes(5)
+            }
+        );
+        assert_eq!(
+            policy(Expiration::At(now + Duration::days(30))),
+            RemovalPolicy::Expiry {
+                after: Duration::hours(24)
+            }
+        );
+    }
+
+    #[test]
+    fn combines_upload_parameters() {
+        let now = UtcDateTime::UNIX_EPOCH;
+        let policy = |e, n| removal_policy(e, n, now, &bounds());
+        assert_eq!(policy(None, None), Ok(RemovalPolicy::SingleDownload));
+        assert_eq!(
+            policy(None, Some(3)),
+            Ok(RemovalPolicy::MaxDownloads { n: 3 })
+        );
+        assert_eq!(
+            policy(Some(Expiration::After(Duration::hours(1))), Some(3)),
+            Ok(RemovalPolicy::MaxDownloadsOrExpiry {
+                n: 3,
+                after: Duration::hours(1)
+            })
+        );
+        assert!(policy(None, Some(0)).is_err());
+        assert!(policy(Some(Expiration::SingleDownload), Some(2)).is_err());
+    }
+
+    #[test]
+    fn patched_lifetimes_start_now() {
+        let current = RemovalPolicy::Expiry {
+            after: Duration::hours(1),
+        };
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(3),
+                Some(Expiration::After(Duration::hours(2))),
+                None
+            ),
+            Ok(RemovalPolicy::Expiry {
+                after: Duration::hours(5)
+            })
+        );
+        // Clamped to the bounds from now, not from the upload
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(3),
+                Some(Expiration::After(Duration::days(7))),
+                None
+            ),
+            Ok(RemovalPolicy::Expiry {
+                after: Duration::hours(27)
+            })
+        );
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(3),
+                Some(Expiration::After(Duration::seconds(1))),
+                None
+            ),
+            Ok(RemovalPolicy::Expiry {
+                after: Duration::hours(3) + Duration::minutes(5)
+            })
+        );
+    }
+
+    #[test]
+    fn patched_lifetimes_end_at_maximum_age() {
+        let current = RemovalPolicy::MaxDownloads { n: 2 };
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(40),
+                Some(Expiration::After(Duration::hours(24))),
+                None
+            ),
+            Ok(RemovalPolicy::MaxDownloadsOrExpiry {
+                n: 2,
+                after: Duration::hours(48)
+            })
+        );
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(24),
+                Some(Expiration::At(
+                    UtcDateTime::UNIX_EPOCH + Duration::hours(48)
+                )),
+                None
+            ),
+            Ok(RemovalPolicy::MaxDownloadsOrExpiry {
+                n: 2,
+                after: Duration::hours(48)
+            })
+        );
+        assert_eq!(
+            patch(
+                current,
+                Duration::hours(47) + Duration::minutes(59),
+                Some(Expiration::After(Duration::hours(1))),
+                None
+            ),
+            Ok(RemovalPolicy::MaxDownloadsOrExpiry {
+                n: 2,
+                after: Duration::hours(48)
+            })
+        );
+    }
+
+    #[test]
+    fn patches_download_limits() {
+        let both = RemovalPolicy::MaxDownloadsOrExpiry {
+            n: 2,
+            after: Duration::hours(1),
+        };
+        assert_eq!(
+            patch(both, Duration::ZERO, None, Some(None)),
+            Ok(RemovalPolicy::Expiry {
+                after: Duration::hours(1)
+            })
+        );
+        assert_eq!(
+            patch(
+                RemovalPolicy::SingleDownload,
+                Duration::ZERO,
+                None,
+                Some(Some(5))
+            ),
+            Ok(RemovalPolicy::MaxDownloads { n: 5 })
+        );
+        assert_eq!(
+            patch(both, Duration::ZERO, Some(Expiration::SingleDownload), None),
+            Ok(RemovalPolicy::SingleDownload)
+        );
+        assert!(patch(both, Duration::ZERO, None, Some(Some(0))).is_err());
+        assert!(
+            patch(
+                RemovalPolicy::MaxDownloads { n: 2 },
+                Duration::ZERO,
+                None,
+                Some(None)
+            )
+            .is_err()
+        );
+        assert!(
+            patch(
+                both,
+                Duration::ZERO,
+                Some(Expiration::SingleDownload),
+                Some(Some(2))
+            )
+            .is_err()
+        );
+    }
+}
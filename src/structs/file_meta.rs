@@ -1,10 +1,19 @@
+use crate::blob_store;
+use crate::index;
+use crate::index::AssetState;
+use crate::storage;
+use crate::storage::storage;
+use crate::structs::digest::DigestAlgorithm;
 use crate::structs::error::{TapferError, TapferResult};
 use crate::structs::tapfer_id::TapferId;
 use crate::updown::upload_handle::UploadHandle;
-use std::path::Path;
-use std::str::FromStr;
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, LazyLock};
 use time::{Duration, OffsetDateTime, UtcDateTime};
 use time_tz::{OffsetDateTimeExt, timezones};
+use tokio::sync::{Mutex, OwnedMutexGuard};
 use tracing::error;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -14,14 +23,155 @@ pub struct FileMeta {
     created: UtcDateTime,
     removal_policy: RemovalPolicy,
     mimetype: String,
+    /// Completed downloads, only tracked for policies with a download limit
+    #[serde(default)]
+    downloads: u32,
+    /// SHA-256 of the owner's management token, absent for assets uploaded before tokens existed
+    #[serde(default)]
+    management_token_hash: Option<String>,
+    /// Argon2 PHC string, set when the uploader protected the asset with a password
+    #[serde(default)]
+    password_hash: Option<String>,
+    /// Files of a multi-file asset in upload order, empty when the asset is the single file `name`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    entries: Vec<FileEntry>,
+    /// CRC-32 of a single-file asset, multi-file assets record it per entry.
+    /// Absent for assets uploaded before it was recorded, or through tus
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32: Option<u32>,
+    /// SHA-256 of a single-file asset moved to the blob store, which then holds its payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<String>,
+    /// Exempts the asset from expiry and the global maximum age, set through the admin API
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
 }
 
-#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+/// A file of a multi-file asset, stored at `{id}/{path}`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FileEntry {
+    /// Relative path with `/` separators, as picked by the uploader
+    pub path: String,
+    pub size: u64,
+    pub mimetype: String,
+    /// Recorded while uploading, so archives can be assembled without reading the files twice
+    pub crc32: u32,
+}
+
+/// Whether `name` is one of the files next to the payload that an uploaded name must never overwrite.
+/// Derived from the stored files, so every further checksum algorithm is reserved as well
+fn is_reserved(name: &str) -> bool {
+    name == "meta.toml" || DigestAlgorithm::from_file_name(name).is_some()
+}
+
+/// Longest relative path accepted for a file of a multi-file asset
+const MAX_PATH_LEN: usize = 4096;
+
+/// Rejects names that would escape the asset directory or clash with its metadata
+pub fn validate_file_name(name: &str) -> Result<(), String> {
+    if is_valid_component(name) && !is_reserved(name) {
+        Ok(())
+    } else {
+        Err(format!("{name:?} is not a valid file name"))
+    }
+}
+
+/// Like [`validate_file_name`], but permits directories separated by `/`
+pub fn validate_entry_path(path: &str) -> Result<(), String> {
+    let first = path.split('/').next().unwrap_or_default();
+    if path.len() <= MAX_PATH_LEN && path.split('/').all(is_valid_component) && !is_reserved(first)
+    {
+        Ok(())
+    } else {
+        Err(format!("{path:?} is not a valid file path"))
+    }
+}
+
+fn is_valid_component(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\'])
+        // They would end up in `Content-Disposition` and terminal output
+        && !name.contains(char::is_control)
+}
+
+/// Paths of files that are to coexist, where none may equal another or be a directory of another
+#[derive(Debug, Default)]
+pub struct PathSet {
+    files: HashSet<String>,
+    directories: HashSet<String>,
+}
+
+impl PathSet {
+    /// Adds `path` unless it collides with a path added before
+    pub fn insert(&mut self, path: &str) -> bool {
+        let mut ancestors = path.match_indices('/').map(|(i, _)| &path[..i]);
+        if self.files.contains(path)
+            || self.directories.contains(path)
+            || ancestors.any(|a| self.files.contains(a))
+        {
+            return false;
+        }
+        for (i, _) in path.match_indices('/') {
+            self.directories.insert(path[..i].to_owned());
+        }
+        self.files.insert(path.to_owned());
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum RemovalPolicy {
     SingleDownload,
-    Expiry { after: Duration },
+    Expiry {
+        after: Duration,
+    },
+    MaxDownloads {
+        n: u32,
+    },
+    /// Removed after `n` downloads or once `after` has passed, whichever comes first
+    MaxDownloadsOrExpiry {
+        n: u32,
+        after: Duration,
+    },
+}
+
+impl RemovalPolicy {
+    /// Name of the variant, as recorded in the index
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RemovalPolicy::SingleDownload => "single_download",
+            RemovalPolicy::Expiry { .. } => "expiry",
+            RemovalPolicy::MaxDownloads { .. } => "max_downloads",
+            RemovalPolicy::MaxDownloadsOrExpiry { .. } => "max_downloads_or_expiry",
+        }
+    }
+
+    pub fn download_limit(&self) -> Option<u32> {
+        match self {
+            RemovalPolicy::SingleDownload => Some(1),
+            RemovalPolicy::Expiry { .. } => None,
+            RemovalPolicy::MaxDownloads { n } | RemovalPolicy::MaxDownloadsOrExpiry { n, .. } => {
+                Some(*n)
+            }
+        }
+    }
+
+    pub fn lifetime(&self) -> Option<Duration> {
+        match self {
+            RemovalPolicy::SingleDownload | RemovalPolicy::MaxDownloads { .. } => None,
+            RemovalPolicy::Expiry { after } | RemovalPolicy::MaxDownloadsOrExpiry { after, .. } => {
+                Some(*after)
+            }
+        }
+    }
 }
 
+/// Serializes read-modify-write cycles of `meta.toml` per asset
+static META_LOCKS: LazyLock<DashMap<TapferId, Arc<Mutex<()>>>> = LazyLock::new(DashMap::new);
+
 /// A wrapper for the size of an upload asset
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum FileSize {
@@ -51,30 +201,181 @@ impl FileSize {
 }
 
 impl FileMeta {
-    pub fn remove_after_download(&self) -> bool {
-        matches!(self.removal_policy, RemovalPolicy::SingleDownload)
+    /// Downloads left before the asset is removed, if its policy limits them
+    pub fn remaining_downloads(&self) -> Option<u32> {
+        self.removal_policy
+            .download_limit()
+            .map(|limit| limit.saturating_sub(self.downloads))
     }
 
-    pub async fn read_from_id_path(path: impl AsRef<Path>) -> TapferResult<(TapferId, Self)> {
-        let id = TapferId::from_str(&path.as_ref().to_string_lossy())?;
-        let meta: FileMeta = Self::read_from_id(id).await?;
-        Ok((id, meta))
+    /// The metadata of a complete asset, as recorded in the index
+    pub async fn read_from_id(id: TapferId) -> TapferResult<Self> {
+        index::get(id)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound).into())
     }
 
-    pub async fn read_from_id(id: TapferId) -> TapferResult<Self> {
-        Ok(toml::from_str(
-            &tokio::fs::read_to_string(format!("data/{id}/meta.toml")).await?,
-        )?)
+    /// Reads `meta.toml` itself, for rebuilding the index
+    pub async fn read_stored(id: TapferId) -> TapferResult<Self> {
+        let meta = storage().read(&storage::meta_key(id)).await?;
+        Ok(toml::from_str(&String::from_utf8_lossy(&meta))?)
+    }
+
+    /// Replaces `meta.toml` atomically, so readers never observe a partially written file.
+    /// The index is updated once it was written, and not at all when writing fails
+    pub async fn write_to_id(&self, id: TapferId) -> TapferResult<()> {
+        let data = toml::to_string_pretty(self)?.into_bytes();
+        let meta = self.clone();
+        index::transaction(
+            storage().write(&storage::meta_key(id), data),
+            move |index| index::upsert(index, id, &meta, AssetState::Complete),
+        )
+        .await
+    }
+
+    /// Applies `f` to the stored metadata and persists the result.
+    /// Concurrent updates of the same asset are applied one after another
+    pub async fn update(id: TapferId, f: impl FnOnce(&mut FileMeta)) -> TapferResult<FileMeta> {
+        let _guard = Self::lock(id).await;
+        let mut meta = Self::read_from_id(id).await?;
+        f(&mut meta);
+        meta.write_to_id(id).await?;
+        Ok(meta)
+    }
+
+    /// Excludes concurrent [`FileMeta::update`]s for as long as the guard is held
+    pub async fn lock(id: TapferId) -> OwnedMutexGuard<()> {
+        let lock = META_LOCKS.entry(id).or_default().clone();
+        lock.lock_owned().await
+    }
+
+    /// Drops the update lock of a deleted asset
+    pub fn forget_lock(id: TapferId) {
+        META_LOCKS.remove(&id);
+    }
+
+    pub fn record_download(&mut self) {
+        self.downloads = self.downloads.saturating_add(1);
     }
 
-    pub fn read_from_id_blocking(id: TapferId) -> TapferResult<Self> {
-        Ok(toml::from_str(&std::fs::read_to_string(format!(
-            "data/{id}/meta.toml"
-        ))?)?)
+    pub fn downloads(&self) -> u32 {
+        self.downloads
     }
 
-    pub fn from_upload_handle(handle: &UploadHandle) -> Self {
-        handle.file_meta().clone()
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn set_content_type(&mut self, mimetype: String) {
+        self.mimetype = mimetype;
+    }
+
+    pub fn set_removal_policy(&mut self, policy: RemovalPolicy) {
+        self.removal_policy = policy;
+    }
+
+    /// The metadata of an upload in progress, including the files received so far
+    pub async fn from_upload_handle(handle: &UploadHandle) -> Self {
+        let mut meta = handle.file_meta().clone();
+        let progress = handle.read_fsm().await.get_progress().unwrap_or_default();
+        let entries = handle.entries(progress);
+        if handle.is_multi_file() || entries.iter().any(|e| e.path.contains('/')) {
+            meta.set_entries(entries);
+        }
+        meta
+    }
+
+    /// Turns the asset into a multi-file asset, which is downloaded as a ZIP archive
+    pub fn set_entries(&mut self, entries: Vec<FileEntry>) {
+        let root = entries
+            .first()
+            .and_then(|e| e.path.split_once('/'))
+            .map(|(root, _)| root);
+        self.name = match root {
+            Some(root)
+                if entries.iter().all(|e| {
+                    e.path
+                        .strip_prefix(root)
+                        .is_some_and(|r| r.starts_with('/'))
+                }) =>
+            {
+                root.to_owned()
+            }
+            _ => format!("{} files", entries.len()),
+        };
+        self.mimetype = "application/zip".to_owned();
+        self.entries = entries;
+    }
+
+    pub fn entries(&self) -> &[FileEntry] {
+        &self.entries
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// All files of the asset. For a single-file asset that is the file `name`
+    pub fn files(&self) -> Vec<FileEntry> {
+        if self.is_multi_file() {
+            return self.entries.clone();
+        }
+        vec![FileEntry {
+            path: self.name.clone(),
+            size: self.size(),
+            mimetype: self.mimetype.clone(),
+            crc32: self.crc32.unwrap_or_default(),
+        }]
+    }
+
+    /// Looks up a file of the asset by its path, along with its offset in the concatenation of all files.
+    /// The file of a single-file asset is found by its name
+    pub fn entry(&self, path: &str) -> Option<(u64, FileEntry)> {
+        let mut offset = 0;
+        for entry in self.files() {
+            if entry.path == path {
+                return Some((offset, entry));
+            }
+            offset += entry.size;
+        }
+        None
+    }
+
+    /// Storage key of the file `path` of the asset
+    pub fn storage_key(&self, id: TapferId, path: &str) -> String {
+        match &self.blob {
+            Some(hash) => blob_store::blob_key(hash),
+            None => storage::asset_key(id, path),
+        }
+    }
+
+    /// Storage key of the file of a single-file asset
+    pub fn payload_key(&self, id: TapferId) -> String {
+        self.storage_key(id, &self.name)
+    }
+
+    pub fn blob(&self) -> Option<&str> {
+        self.blob.as_deref()
+    }
+
+    pub fn set_blob(&mut self, hash: String) {
+        self.blob = Some(hash);
+    }
+
+    pub fn pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+
+    pub fn crc32(&self) -> Option<u32> {
+        self.crc32
+    }
+
+    pub fn set_crc32(&mut self, crc32: u32) {
+        self.crc32 = Some(crc32);
     }
 
     pub fn add_size(&mut self, extra: u64) -> TapferResult<()> {
@@ -95,6 +396,12 @@ impl FileMeta {
     pub fn created(&self) -> UtcDateTime {
         self.created
     }
+    pub fn management_token_hash(&self) -> Option<&str> {
+        self.management_token_hash.as_deref()
+    }
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
     pub fn known_size(&self) -> Option<u64> {
         match self.size {
             FileSize::AlreadyKnown(s) => Some(s),
@@ -103,10 +410,9 @@ impl FileMeta {
     }
 
     pub fn expires_on_utc(&self) -> Option<UtcDateTime> {
-        match self.removal_policy {
-            RemovalPolicy::SingleDownload => None,
-            RemovalPolicy::Expiry { after } => Some(self.created + after),
-        }
+        self.removal_policy
+            .lifetime()
+            .map(|after| self.created + after)
     }
 }
 
@@ -114,6 +420,8 @@ impl FileMeta {
 pub struct FileMetaBuilder {
     pub expiration: Option<RemovalPolicy>,
     pub timezone: Option<String>,
+    pub management_token_hash: Option<String>,
+    pub password_hash: Option<String>,
 }
 
 impl FileMetaBuilder {
@@ -135,6 +443,55 @@ impl FileMetaBuilder {
             removal_policy: self.expiration.unwrap_or(RemovalPolicy::SingleDownload),
             name,
             mimetype,
+            downloads: 0,
+            management_token_hash: self.management_token_hash,
+            password_hash: self.password_hash,
+            entries: vec![],
+            crc32: None,
+            blob: None,
+            pinned: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_sets_reject_collisions() {
+        let mut paths = PathSet::default();
+        assert!(paths.insert("a/b/c.txt"));
+        assert!(paths.insert("a/b/d.txt"));
+        assert!(paths.insert("a/bc"));
+        assert!(paths.insert("b"));
+        assert!(!paths.insert("a/b/c.txt"));
+        // A file where a directory is, and the other way around
+        assert!(!paths.insert("a/b"));
+        assert!(!paths.insert("a"));
+        assert!(!paths.insert("b/c"));
+        assert!(!paths.insert("a/b/c.txt/d"));
+        assert!(paths.insert("a/b/e/f"));
+    }
+
+    #[test]
+    fn validates_file_names() {
+        assert!(validate_file_name("report \"final\".pdf").is_ok());
+        assert!(validate_file_name("grüße.txt").is_ok());
+        assert!(validate_file_name("meta.toml").is_err());
+        for algorithm in DigestAlgorithm::ALL {
+            assert!(validate_file_name(&algorithm.file_name()).is_err());
+            assert!(validate_file_name(&format!("{}.tmp", algorithm.file_name())).is_ok());
         }
+        assert!(validate_file_name("checksum.crc").is_ok());
+        assert!(validate_file_name("..").is_err());
+        assert!(validate_file_name("a/b").is_err());
+        assert!(validate_file_name("a\r\nSet-Cookie: x").is_err());
+        assert!(validate_file_name("tab\there").is_err());
+        assert!(validate_file_name("nul\0").is_err());
+        assert!(validate_entry_path("folder/file.txt").is_ok());
+        assert!(validate_entry_path("folder//file.txt").is_err());
+        assert!(validate_entry_path("meta.toml/file.txt").is_err());
+        assert!(validate_entry_path("checksum.md5/file.txt").is_err());
     }
 }
@@ -0,0 +1,184 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use time::Duration;
+
+/// A duration written the way humans write it, such as `90s`, `30m` or `1d12h`,
+/// or as an ISO 8601 duration such as `PT6H` or `P1DT12H`.
+/// Plain integers are interpreted as seconds.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    pub fn duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty duration".to_owned());
+        }
+        if let Ok(secs) = s.parse::<u32>() {
+            return Ok(Self(Duration::seconds(secs.into())));
+        }
+        if let Some(iso) = s.strip_prefix(['P', 'p']) {
+            return parse_iso8601(iso).ok_or_else(|| format!("invalid ISO 8601 duration {s:?}"));
+        }
+
+        let mut total = Duration::ZERO;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(|| format!("missing unit at the end of {s:?}"))?;
+            if split == 0 {
+                return Err(format!("expected a number in {s:?}"));
+            }
+            let (digits, tail) = rest.split_at(split);
+            let unit_len = tail
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+            let value: i64 = digits
+                .parse()
+                .map_err(|_| format!("number {digits:?} in {s:?} is too large"))?;
+            let seconds_per_unit = match unit.trim() {
+                "s" | "sec" | "secs" => 1,
+                "m" | "min" | "mins" => 60,
+                "h" | "hour" | "hours" => 60 * 60,
+                "d" | "day" | "days" => 24 * 60 * 60,
+                "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+                other => return Err(format!("unknown unit {other:?} in {s:?}")),
+            };
+            let part = value
+                .checked_mul(seconds_per_unit)
+                .ok_or_else(|| format!("duration {s:?} is too large"))?;
+            total = total
+                .checked_add(Duration::seconds(part))
+                .ok_or_else(|| format!("duration {s:?} is too large"))?;
+            rest = tail.trim_start();
+        }
+        Ok(Self(total))
+    }
+}
+
+/// Parses the part after the leading `P`. Years and months are approximated as 365 and 30 days
+fn parse_iso8601(s: &str) -> Option<HumanDuration> {
+    let (date, time) = match s.split_once(['T', 't']) {
+        Some((date, time)) if !time.is_empty() => (date, Some(time)),
+        Some(_) => return None,
+        None => (s, None),
+    };
+    if date.is_empty() && time.is_none() {
+        return None;
+    }
+
+    let mut total = Duration::ZERO;
+    let mut add = |part: &str, units: &[(char, i64)]| -> Option<()> {
+        let mut rest = part;
+        let mut allowed = units;
+        while !rest.is_empty() {
+            let split = rest.find(|c: char| !c.is_ascii_digit())?;
+            let (digits, tail) = rest.split_at(split);
+            let unit = tail.chars().next()?.to_ascii_uppercase();
+            // Designators must appear in order and at most once
+            let position = allowed.iter().position(|(u, _)| *u == unit)?;
+            let value: i64 = digits.parse().ok()?;
+            total =
+                total.checked_add(Duration::seconds(value.checked_mul(allowed[position].1)?))?;
+            allowed = &allowed[position + 1..];
+            rest = &tail[1..];
+        }
+        Some(())
+    };
+    const DAY: i64 = 24 * 60 * 60;
+    add(
+        date,
+        &[
+            ('Y', 365 * DAY),
+            ('M', 30 * DAY),
+            ('W', 7 * DAY),
+            ('D', DAY),
+        ],
+    )?;
+    if let Some(time) = time {
+        add(time, &[('H', 60 * 60), ('M', 60), ('S', 1)])?;
+    }
+    Some(HumanDuration(total))
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}s", self.0.whole_seconds())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for HumanDuration {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(u32),
+            Str(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Int(i) => Ok(Self(Duration::seconds(i.into()))),
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// For use with `#[serde(deserialize_with = "...")]` on plain [`Duration`] fields
+pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    <HumanDuration as serde::Deserialize>::deserialize(deserializer).map(HumanDuration::duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Duration, String> {
+        HumanDuration::from_str(s).map(HumanDuration::duration)
+    }
+
+    #[test]
+    fn parses_human_durations() {
+        assert_eq!(parse("90"), Ok(Duration::seconds(90)));
+        assert_eq!(parse("90s"), Ok(Duration::seconds(90)));
+        assert_eq!(parse("30m"), Ok(Duration::minutes(30)));
+        assert_eq!(parse("1d12h"), Ok(Duration::hours(36)));
+        assert_eq!(parse("2 weeks"), Ok(Duration::weeks(2)));
+        assert_eq!(parse("1h 30min"), Ok(Duration::minutes(90)));
+    }
+
+    #[test]
+    fn parses_iso8601_durations() {
+        assert_eq!(parse("PT6H"), Ok(Duration::hours(6)));
+        assert_eq!(parse("P1DT12H"), Ok(Duration::hours(36)));
+        assert_eq!(parse("P1W"), Ok(Duration::weeks(1)));
+        assert_eq!(parse("PT1M30S"), Ok(Duration::seconds(90)));
+        assert_eq!(parse("P1M"), Ok(Duration::days(30)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse("").is_err());
+        assert!(parse("10").is_ok());
+        assert!(parse("10x").is_err());
+        assert!(parse("3 fortnights").is_err());
+        assert!(parse("h").is_err());
+        assert!(parse("5h3").is_err());
+        assert!(parse("-5m").is_err());
+        assert!(parse("P").is_err());
+        assert!(parse("PT").is_err());
+        assert!(parse("PT5H3M2H").is_err());
+        assert!(parse("P1H").is_err());
+        assert!(parse("99999999999999999w").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::configuration::config;
+use crate::structs::error::{TapferError, TapferResult};
+use axum::http::{HeaderMap, header};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Response header handing the token to the uploader
+pub const MANAGEMENT_TOKEN_HEADER: &str = "tapfer-management-token";
+
+/// Secret proving ownership of an asset. Only its hash is ever stored
+pub struct ManagementToken(String);
+
+impl ManagementToken {
+    /// Two v4 UUIDs provide 244 random bits without pulling in another RNG library
+    pub fn new_random() -> Self {
+        Self(format!(
+            "{}{}",
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        ))
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+
+    pub fn hash(&self) -> String {
+        hash_secret(&self.0)
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    base16ct::lower::encode_string(&Sha256::digest(secret.as_bytes()))
+}
+
+/// Compares without short-circuiting, so response times do not leak how much of the hash matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization: Bearer <token>` header against the stored hash.
+/// Assets uploaded before tokens existed have no hash and cannot be managed
+pub fn authorize_owner(headers: &HeaderMap, stored_hash: Option<&str>) -> TapferResult<()> {
+    let presented = bearer_token(headers).ok_or(TapferError::MissingManagementToken)?;
+    let Some(stored) = stored_hash else {
+        return Err(TapferError::InvalidManagementToken);
+    };
+    if constant_time_eq(hash_secret(presented).as_bytes(), stored.as_bytes()) {
+        Ok(())
+    } else {
+        Err(TapferError::InvalidManagementToken)
+    }
+}
+
+/// Checks the `Authorization: Bearer <token>` header against `admin.token`.
+/// While no token is configured the admin API does not exist
+pub fn authorize_admin(headers: &HeaderMap) -> TapferResult<()> {
+    let token = &config().admin.token;
+    if token.is_empty() {
+        return Err(TapferError::AdminDisabled);
+    }
+    let presented = bearer_token(headers).ok_or(TapferError::MissingAdminToken)?;
+    // Hashing first keeps the comparison from leaking the length of the token
+    if constant_time_eq(
+        hash_secret(presented).as_bytes(),
+        hash_secret(token).as_bytes(),
+    ) {
+        Ok(())
+    } else {
+        Err(TapferError::InvalidAdminToken)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
@@ -1,3 +1,13 @@
+pub mod archive;
+pub mod archive_contents;
+pub mod asset_password;
+pub mod byte_range;
+pub mod byte_size;
+pub mod digest;
 pub mod error;
+pub mod expiration;
 pub mod file_meta;
+pub mod human_duration;
+pub mod management_token;
 pub mod tapfer_id;
+pub mod validators;
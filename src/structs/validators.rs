@@ -0,0 +1,204 @@
+use crate::structs::digest;
+use crate::structs::error::TapferResult;
+use axum::http::{HeaderMap, HeaderValue, header};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::UtcDateTime;
+
+/// `ETag`, `Last-Modified` and `Repr-Digest` of a completely uploaded asset, evaluated as described in RFC 9110 section 13
+#[derive(Debug, Clone)]
+pub struct Validators {
+    /// Including quotes and the `W/` prefix of weak tags
+    etag: String,
+    /// Truncated to seconds, like every HTTP date
+    last_modified: SystemTime,
+    /// RFC 9530 digest of the whole asset, for Range requests as well
+    repr_digest: Option<HeaderValue>,
+}
+
+impl Validators {
+    /// Strong when the SHA-512 is known. Until then the tag is weak and derived from the size and upload time
+    pub fn new(sha512: Option<&str>, size: u64, created: UtcDateTime) -> Self {
+        let etag = match sha512 {
+            Some(sha512) => format!("\"{sha512}\""),
+            None => format!("W/\"{size:x}-{:x}\"", created.unix_timestamp_nanos()),
+        };
+        Self {
+            etag,
+            last_modified: UNIX_EPOCH
+                + Duration::from_secs(u64::try_from(created.unix_timestamp()).unwrap_or(0)),
+            repr_digest: None,
+        }
+    }
+
+    pub fn with_repr_digest(mut self, repr_digest: Option<HeaderValue>) -> Self {
+        self.repr_digest = repr_digest;
+        self
+    }
+
+    pub fn insert_headers(&self, headers: &mut HeaderMap) -> TapferResult<()> {
+        headers.insert(header::ETAG, HeaderValue::from_str(&self.etag)?);
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(self.last_modified))?,
+        );
+        if let Some(repr_digest) = &self.repr_digest {
+            headers.insert(digest::REPR_DIGEST, repr_digest.clone());
+        }
+        Ok(())
+    }
+
+    /// Whether a GET or HEAD may be answered with `304 Not Modified`.
+    /// `If-None-Match` takes precedence, `If-Modified-Since` is only considered without it
+    pub fn not_modified(&self, request: &HeaderMap) -> bool {
+        if let Some(if_none_match) = header_str(request, header::IF_NONE_MATCH) {
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak_eq(tag, &self.etag));
+        }
+        header_str(request, header::IF_MODIFIED_SINCE)
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    /// Whether a `Range` header should be honoured, which `If-Range` restricts to an unchanged asset
+    pub fn range_applies(&self, request: &HeaderMap) -> bool {
+        let Some(if_range) = header_str(request, header::IF_RANGE) else {
+            return true;
+        };
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // Requires strong comparison, so weak tags never match
+            !self.etag.starts_with("W/") && if_range == self.etag
+        } else {
+            httpdate::parse_http_date(if_range).is_ok_and(|date| date == self.last_modified)
+        }
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+}
+
+/// Weak comparison ignores the `W/` prefix on either side
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA512: &str = "abc123";
+
+    fn created() -> UtcDateTime {
+        UtcDateTime::from_unix_timestamp(1_700_000_000).unwrap()
+    }
+
+    fn strong() -> Validators {
+        Validators::new(Some(SHA512), 10, created())
+    }
+
+    fn weak() -> Validators {
+        Validators::new(None, 10, created())
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn http_date(offset_secs: i64) -> String {
+        let date = UNIX_EPOCH
+            + Duration::from_secs(
+                (created().unix_timestamp() + offset_secs)
+                    .try_into()
+                    .unwrap(),
+            );
+        httpdate::fmt_http_date(date)
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let matches =
+            |v: &Validators, tags: &str| v.not_modified(&request(&[(header::IF_NONE_MATCH, tags)]));
+        assert!(matches(&strong(), "\"abc123\""));
+        assert!(matches(&strong(), "W/\"abc123\""));
+        assert!(matches(&strong(), "\"other\", \"abc123\""));
+        assert!(!matches(&strong(), "\"other\""));
+        assert!(!matches(&strong(), "abc123"));
+
+        let weak = weak();
+        let mut headers = HeaderMap::new();
+        weak.insert_headers(&mut headers).unwrap();
+        let tag = headers[header::ETAG].to_str().unwrap();
+        assert!(tag.starts_with("W/\""));
+        assert!(matches(&weak, tag));
+        assert!(matches(&weak, tag.trim_start_matches("W/")));
+    }
+
+    #[test]
+    fn if_none_match_accepts_any_tag() {
+        let headers = request(&[(header::IF_NONE_MATCH, "*")]);
+        assert!(strong().not_modified(&headers));
+        assert!(weak().not_modified(&headers));
+    }
+
+    #[test]
+    fn if_modified_since_compares_seconds() {
+        let since = |offset| {
+            strong().not_modified(&request(&[(header::IF_MODIFIED_SINCE, &http_date(offset))]))
+        };
+        assert!(since(0));
+        assert!(since(60));
+        assert!(!since(-1));
+        assert!(!strong().not_modified(&request(&[(header::IF_MODIFIED_SINCE, "yesterday")])));
+        assert!(!strong().not_modified(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let later = http_date(60);
+        let headers = request(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, &later),
+        ]);
+        assert!(!strong().not_modified(&headers));
+
+        let earlier = http_date(-60);
+        let headers = request(&[
+            (header::IF_NONE_MATCH, "\"abc123\""),
+            (header::IF_MODIFIED_SINCE, &earlier),
+        ]);
+        assert!(strong().not_modified(&headers));
+    }
+
+    #[test]
+    fn if_range_requires_strong_etags() {
+        let applies =
+            |v: &Validators, value: &str| v.range_applies(&request(&[(header::IF_RANGE, value)]));
+        assert!(strong().range_applies(&HeaderMap::new()));
+        assert!(applies(&strong(), "\"abc123\""));
+        assert!(!applies(&strong(), "\"other\""));
+        assert!(!applies(&strong(), "W/\"abc123\""));
+
+        let weak = weak();
+        let mut headers = HeaderMap::new();
+        weak.insert_headers(&mut headers).unwrap();
+        assert!(!applies(&weak, headers[header::ETAG].to_str().unwrap()));
+    }
+
+    #[test]
+    fn if_range_with_a_date_requires_the_exact_modification_time() {
+        let applies =
+            |offset| strong().range_applies(&request(&[(header::IF_RANGE, &http_date(offset))]));
+        assert!(applies(0));
+        assert!(!applies(60));
+        assert!(!applies(-60));
+        assert!(!strong().range_applies(&request(&[(header::IF_RANGE, "not a date")])));
+    }
+}
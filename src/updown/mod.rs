@@ -1,2 +1,3 @@
+pub mod resumable;
 pub mod upload_handle;
 pub mod upload_pool;
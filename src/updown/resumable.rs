@@ -0,0 +1,107 @@
+use crate::configuration::config;
+use crate::retention_control::delete_asset;
+use crate::structs::error::TapferResult;
+use crate::structs::tapfer_id::TapferId;
+use crate::updown::upload_handle::UploadHandle;
+use crate::updown::upload_pool::UploadFsm;
+use dashmap::DashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::info;
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_RESUMABLE: &str = "tus-resumable";
+pub const UPLOAD_OFFSET: &str = "upload-offset";
+pub const UPLOAD_LENGTH: &str = "upload-length";
+
+/// Response headers of the tus protocol that browsers on other origins need to read
+pub const EXPOSED_HEADERS: [&str; 7] = [
+    TUS_RESUMABLE,
+    "tus-version",
+    "tus-extension",
+    "tus-max-size",
+    "tus-checksum-algorithm",
+    UPLOAD_OFFSET,
+    UPLOAD_LENGTH,
+];
+
+/// Resumable uploads that have not received all their bytes yet.
+/// Only kept in memory, so a restart ends them: their URLs answer 404
+/// and `index::init` deletes what they had received
+pub static RESUMABLE_UPLOADS: LazyLock<DashMap<TapferId, Arc<ResumableUpload>>> =
+    LazyLock::new(DashMap::new);
+
+/// Keeps the upload handle alive between the requests appending to it
+#[derive(Debug)]
+pub struct ResumableUpload {
+    handle: UploadHandle,
+    length: u64,
+    /// Held by the request currently appending
+    writer: Arc<Mutex<()>>,
+    last_activity: std::sync::Mutex<Instant>,
+}
+
+impl ResumableUpload {
+    pub fn new(handle: UploadHandle, length: u64) -> Self {
+        Self {
+            handle,
+            length,
+            writer: Arc::new(Mutex::new(())),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn handle(&self) -> &UploadHandle {
+        &self.handle
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Appending is exclusive, `None` while another request appends
+    pub fn try_lock_writer(&self) -> Option<OwnedMutexGuard<()>> {
+        self.writer.clone().try_lock_owned().ok()
+    }
+
+    pub fn touch(&self) {
+        *self.last_activity.lock().expect("poisoned activity") = Instant::now();
+    }
+
+    fn idle_for(&self) -> Option<Duration> {
+        // Not idle while a request is appending, however slowly
+        let _writer = self.writer.try_lock().ok()?;
+        Some(
+            self.last_activity
+                .lock()
+                .expect("poisoned activity")
+                .elapsed(),
+        )
+    }
+}
+
+/// Fails the upload, wakes waiting downloaders so they abort, and deletes what was received
+pub async fn abort(id: TapferId) -> TapferResult<()> {
+    let Some((_, upload)) = RESUMABLE_UPLOADS.remove(&id) else {
+        return Ok(());
+    };
+    *upload.handle.write_fsm().await = UploadFsm::Failed;
+    upload.handle.notify_all_downloaders();
+    delete_asset(id).await
+}
+
+/// Deletes uploads that were not resumed within `tus.grace_period`
+pub async fn reap_stalled() -> TapferResult<()> {
+    let grace = config().tus.grace_period.unsigned_abs();
+    let stalled: Vec<TapferId> = RESUMABLE_UPLOADS
+        .iter()
+        .filter(|u| u.idle_for().is_some_and(|idle| idle > grace))
+        .map(|u| *u.key())
+        .collect();
+    for id in stalled {
+        info!("Deleting {id} as its upload was not resumed in time");
+        abort(id).await?;
+    }
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use crate::UPLOAD_POOL;
-use crate::structs::file_meta::FileMeta;
+use crate::structs::file_meta::{FileEntry, FileMeta};
 use crate::structs::tapfer_id::TapferId;
 use crate::updown::upload_pool::{UploadFsm, UploadPool};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::sync::{Notify, RwLock};
 use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
 use tokio::task::block_in_place;
@@ -15,15 +15,38 @@ pub struct UploadHandle {
     id: TapferId,
     file_meta: FileMeta,
     notify: Arc<Notify>,
+    /// Files of the asset in upload order, the last one is being written
+    entries: Arc<Mutex<Vec<PendingEntry>>>,
+    /// Files the upload declared to consist of, always one for raw and tus uploads
+    file_count: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct PendingEntry {
+    path: String,
+    mimetype: String,
+    /// Upload progress at which this file started
+    start: u64,
 }
 
 impl UploadPool {
-    pub fn handle(&self, id: TapferId, file_meta: FileMeta) -> UploadHandle {
+    pub fn handle(
+        &self,
+        id: TapferId,
+        file_meta: FileMeta,
+        file_count: Option<usize>,
+    ) -> UploadHandle {
         let handle = UploadHandle {
             handle: Arc::new(RwLock::new(UploadFsm::initial())),
             id,
-            file_meta,
             notify: Arc::new(Notify::new()),
+            entries: Arc::new(Mutex::new(vec![PendingEntry {
+                path: file_meta.name().to_owned(),
+                mimetype: file_meta.content_type().to_owned(),
+                start: 0,
+            }])),
+            file_meta,
+            file_count,
         };
         self.uploads.insert(id, handle.clone());
         handle
@@ -31,7 +54,6 @@ impl UploadPool {
 }
 
 impl UploadHandle {
-    #[allow(dead_code)]
     pub async fn read_fsm(&self) -> RwLockReadGuard<'_, UploadFsm> {
         self.handle.read().await
     }
@@ -65,6 +87,58 @@ impl UploadHandle {
     pub fn id(&self) -> TapferId {
         self.id
     }
+
+    /// Starts the next file of a multi-file upload, once all bytes of the previous file are written
+    pub fn begin_entry(&self, path: String, mimetype: String, start: u64) {
+        self.lock_entries().push(PendingEntry {
+            path,
+            mimetype,
+            start,
+        });
+    }
+
+    /// Whether the upload consists of several files, possibly before the second one arrived
+    pub fn is_multi_file(&self) -> bool {
+        self.file_count.is_some_and(|n| n > 1) || self.lock_entries().len() > 1
+    }
+
+    /// Whether the upload is known to consist of its first file only, so that its declared size is the file's.
+    /// Otherwise the first file may be followed by others and end before that size
+    pub fn is_single_file(&self) -> bool {
+        self.file_count == Some(1)
+    }
+
+    /// Bytes written of the file at `path` given the upload `progress`, `None` once the file is complete
+    pub fn entry_progress(&self, path: &str, progress: u64) -> Option<u64> {
+        let entries = self.lock_entries();
+        let last = entries.last()?;
+        (last.path == path).then(|| progress.saturating_sub(last.start))
+    }
+
+    /// The files received so far, the size of the last one is the part already written.
+    /// Checksums are only known once the upload is complete
+    pub fn entries(&self, progress: u64) -> Vec<FileEntry> {
+        let entries = self.lock_entries();
+        let ends = entries
+            .iter()
+            .skip(1)
+            .map(|e| e.start)
+            .chain(std::iter::once(progress));
+        entries
+            .iter()
+            .zip(ends)
+            .map(|(e, end)| FileEntry {
+                path: e.path.clone(),
+                size: end.saturating_sub(e.start),
+                mimetype: e.mimetype.clone(),
+                crc32: 0,
+            })
+            .collect()
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, Vec<PendingEntry>> {
+        self.entries.lock().expect("poisoned upload entries")
+    }
 }
 
 impl Drop for UploadHandle {
@@ -17,6 +17,10 @@ pub enum UploadFsm {
         /// Bytes already written to disk
         progress: u64,
     },
+    /// A resumable upload whose client disconnected, downloaders keep waiting for it to continue
+    Stalled {
+        progress: u64,
+    },
     Completed,
 }
 
@@ -34,6 +38,19 @@ impl UploadFsm {
             _ => Err(TapferError::UploadHandleSize(*self)),
         }
     }
+    /// Parks an upload that is not receiving data, without failing it
+    pub fn stall(&mut self) {
+        if let UploadFsm::InProgress { progress } = *self {
+            *self = UploadFsm::Stalled { progress };
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let UploadFsm::Stalled { progress } = *self {
+            *self = UploadFsm::InProgress { progress };
+        }
+    }
+
     pub fn mark_complete(&mut self) {
         *self = Self::Completed;
     }
@@ -44,7 +61,7 @@ impl UploadFsm {
 
     pub fn get_progress(&self) -> Option<u64> {
         match self {
-            UploadFsm::InProgress { progress, .. } => Some(*progress),
+            UploadFsm::InProgress { progress } | UploadFsm::Stalled { progress } => Some(*progress),
             _ => None,
         }
     }
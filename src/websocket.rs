@@ -1,4 +1,6 @@
-use crate::handlers::is_localhost;
+use crate::handlers::{checksum, get_any_meta};
+use crate::structs::asset_password::AssetCredentials;
+use crate::structs::digest::DigestAlgorithm;
 use crate::structs::error::TapferResult;
 use crate::structs::tapfer_id::TapferId;
 use axum::extract::ws::{Message, WebSocket};
@@ -10,7 +12,6 @@ use std::time::{Duration, Instant};
 use tokio::sync::broadcast::WeakSender;
 use tokio::sync::broadcast::channel;
 use tracing::warn;
-use uuid::Uuid;
 
 static WS_MAP: LazyLock<DashMap<WsDestination, WeakSender<WsEvent>>> = LazyLock::new(DashMap::new);
 
@@ -59,16 +60,18 @@ pub fn broadcast_event(dst: impl Into<WsDestination> + Copy, event: WsEvent) ->
     Ok(())
 }
 
-pub fn wss_method(host: &str) -> &str {
-    if is_localhost(host) { "ws" } else { "wss" }
-}
-
 // Impl
 
+/// Progress and checksums of a password protected asset are only streamed to those who unlocked it
 #[axum::debug_handler]
-pub async fn start_ws(Path(id): Path<Uuid>, ws: WebSocketUpgrade) -> Response {
-    let id = TapferId::from_id(id);
-    ws.on_upgrade(move |socket| handle_socket(socket, id))
+pub async fn start_ws(
+    Path(path): Path<String>,
+    credentials: AssetCredentials,
+    ws: WebSocketUpgrade,
+) -> TapferResult<Response> {
+    let ((id, meta), _) = get_any_meta(&path).await?;
+    credentials.authorize(id, &meta).await?;
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, id)))
 }
 
 pub(crate) async fn handle_socket(mut socket: WebSocket, dst: impl Into<WsDestination> + Copy) {
@@ -80,11 +83,25 @@ pub(crate) async fn handle_socket(mut socket: WebSocket, dst: impl Into<WsDestin
         WS_MAP.insert(dst.into(), tx.downgrade());
         (tx, rx)
     };
+    // A checksum finished before the page subscribed would never be announced to it
+    let mut replay = None;
+    if let WsDestination::Id(id) = dst.into()
+        && let Some(chksum) = checksum::read_checksum(id, DigestAlgorithm::Sha512).await
+    {
+        replay = Some(WsEvent::Sha512Ready { chksum });
+    }
     let cooldown = Duration::from_millis(1000 / 30); // 30Hz
     let mut last_progress = Instant::now().checked_sub(cooldown).unwrap();
-    while let Ok(msg) = rx.recv().await {
+    while let Some(msg) = match replay.take() {
+        Some(msg) => Some(msg),
+        None => rx.recv().await.ok(),
+    } {
         // Rate-limit progress
-        if matches!(msg, WsEvent::UploadProgress { .. }) && last_progress.elapsed() < cooldown {
+        if matches!(
+            msg,
+            WsEvent::UploadProgress { .. } | WsEvent::JobProgress { .. }
+        ) && last_progress.elapsed() < cooldown
+        {
             continue;
         }
         last_progress = Instant::now();
@@ -113,9 +130,25 @@ pub struct WsPacket {
 #[serde(tag = "key")]
 pub enum WsEvent {
     DeleteAsset,
-    UploadProgress { progress: u64, total: u64 },
+    UploadProgress {
+        progress: u64,
+        total: u64,
+    },
     UploadComplete,
-    DepositReady { id: TapferId },
-    Sha512Ready { chksum: String },
+    DepositReady {
+        id: TapferId,
+    },
+    Sha512Ready {
+        chksum: String,
+    },
+    JobProgress {
+        job: String,
+        processed: u64,
+        total: u64,
+    },
+    DownloadRecorded {
+        remaining: u32,
+    },
+    AssetUpdated,
     Shutdown,
 }